@@ -1,7 +1,10 @@
+use std::str::FromStr;
+
 use crate::{ProgressDisplay, http::HttpError};
 
 use super::http;
 use reqwest::StatusCode;
+use serde::Deserialize;
 use skelm_ollama as ollama;
 use thiserror::Error;
 
@@ -11,12 +14,20 @@ pub enum DownloadError {
     HttpError(#[from] HttpError),
     #[error("Fail to download manifest http-code={0}")]
     ManifestError(StatusCode),
+    #[error("Fail to list {0} http-code={1}")]
+    ListError(String, StatusCode),
+    #[error("Invalid JSON from registry {0}")]
+    InvalidJson(#[from] serde_json::Error),
     #[error("Fail to add manifest {0:?}")]
     ManifestAddingFailed(std::io::Error),
     #[error("Fail to commit blob {0} : {1}")]
     BlobCommitFailed(ollama::Blob, std::io::Error),
     #[error("Downloaded blob doesn't match expected {0} but got {1}")]
     InvalidBlobDownloaded(ollama::Blob, ollama::Blob),
+    #[error("Fail to lock blob {0} : {1}")]
+    BlobLockFailed(ollama::Blob, std::io::Error),
+    #[error("Downloaded model {0} is not a valid GGUF file : {1}")]
+    InvalidModelFile(ollama::Blob, skelm_gguf::GgufError),
 }
 
 pub async fn download_model<PB: ProgressDisplay>(
@@ -27,28 +38,9 @@ pub async fn download_model<PB: ProgressDisplay>(
     model: &ollama::Model,
     variant: &ollama::Variant,
 ) -> Result<Vec<(String, DownloadResult)>, DownloadError> {
-    let manifest_url = config.manifest_url(model, variant);
-
-    let request = client.get(manifest_url).header(
-        reqwest::header::CONTENT_TYPE,
-        "application/vnd.docker.distribution.manifest.v2+json",
-    );
-
-    let response = request.send().await.unwrap();
-
-    if response.status() == reqwest::StatusCode::OK {
-        let bytes = response.bytes().await.unwrap();
-        let manifest = ollama::Manifest::from_json_bytes(&bytes).unwrap();
-        //println!("{:#?}", manifest);
-
-        download_model_with_manifest::<PB>(
-            client, config, store, &manifest, registry, model, variant,
-        )
+    let manifest = fetch_manifest(client, config, model, variant).await?;
+    download_model_with_manifest::<PB>(client, config, store, &manifest, registry, model, variant)
         .await
-    } else {
-        Err(DownloadError::ManifestError(response.status()))
-        //println!("failed to download manifest : {}", response.status())
-    }
 }
 
 pub enum DownloadResult {
@@ -75,24 +67,52 @@ async fn download_model_with_manifest<PB: ProgressDisplay>(
         results.push((layer.media_type.clone(), r))
     }
 
+    // the digest only proves the registry sent what it meant to, check the model is usable
+    if let Some(model_layer) = manifest.find_media_type(ollama::MEDIA_TYPE_IMAGE_MODEL) {
+        skelm_gguf::GgufFile::open(store.blob_path(&model_layer.digest))
+            .and_then(|gguf| gguf.validate())
+            .map_err(|e| DownloadError::InvalidModelFile(model_layer.digest.clone(), e))?;
+    }
+
     store
         .add_manifest(registry, model, variant, manifest)
         .map_err(|e| DownloadError::ManifestAddingFailed(e))?;
     Ok(results)
 }
 
-async fn download_model_blob<PB: ProgressDisplay>(
+/// Download a single blob into the store, unless it is already present
+pub async fn download_model_blob<PB: ProgressDisplay>(
     client: &reqwest::Client,
     config: &ollama::OllamaConfig,
     store: &ollama::OllamaStore,
     blob: &ollama::Blob,
 ) -> Result<DownloadResult, DownloadError> {
+    // the lock is taken even for the blobs already present, so that a garbage collection
+    // doesn't remove them while they're reused
+    let _lock = lock_blob::<PB>(store, blob)
+        .await
+        .map_err(|e| DownloadError::BlobLockFailed(blob.clone(), e))?;
+
     if store.blob_exists(blob) {
+        // the blob may be an orphan planned for collection, make it recent so that it is
+        // kept until the manifest referencing it is written
+        let _ = std::fs::File::options()
+            .append(true)
+            .open(store.blob_path(blob))
+            .and_then(|file| file.set_modified(std::time::SystemTime::now()));
         return Ok(DownloadResult::Skipped(blob.clone()));
     }
 
+    // holding the lock, the partial files left by interrupted downloads are ours to resume
+    let partials = store
+        .blob_partial_files(blob)
+        .map_err(|e| DownloadError::BlobLockFailed(blob.clone(), e))?;
+    let blob_tmp_path = partials
+        .into_iter()
+        .max_by_key(|path| std::fs::metadata(path).map_or(0, |m| m.len()))
+        .unwrap_or_else(|| store.blob_path_tmp(blob));
+
     let blob_url = config.blob_url(blob);
-    let blob_tmp_path = store.blob_path_tmp(blob);
 
     let mut blob_context = ollama::BlobContext::new_from_blob_type(blob);
 
@@ -108,3 +128,117 @@ async fn download_model_blob<PB: ProgressDisplay>(
         .map_err(|e| DownloadError::BlobCommitFailed(blob.clone(), e))?;
     Ok(DownloadResult::Success(blob.clone()))
 }
+
+/// Wait for the lock of a blob, without blocking the runtime while another process holds it
+async fn lock_blob<PB: ProgressDisplay>(
+    store: &ollama::OllamaStore,
+    blob: &ollama::Blob,
+) -> std::io::Result<ollama::StoreLock> {
+    const LOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+    let mut waiting = false;
+    loop {
+        if let Some(lock) = store.try_lock_blob(blob)? {
+            return Ok(lock);
+        }
+        if !waiting {
+            PB::progress_waiting(&blob.to_string());
+            waiting = true;
+        }
+        tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct TagsList {
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Catalog {
+    #[serde(default)]
+    repositories: Vec<String>,
+}
+
+async fn get_bytes(
+    client: &reqwest::Client,
+    url: url::Url,
+    what: &str,
+) -> Result<Vec<u8>, DownloadError> {
+    let response = client.get(url).send().await.map_err(HttpError::from)?;
+    if response.status() != StatusCode::OK {
+        return Err(DownloadError::ListError(
+            what.to_string(),
+            response.status(),
+        ));
+    }
+    Ok(response.bytes().await.map_err(HttpError::from)?.to_vec())
+}
+
+/// List the tags of a model available in the registry
+pub async fn list_tags(
+    client: &reqwest::Client,
+    config: &ollama::OllamaConfig,
+    model: &ollama::Model,
+) -> Result<Vec<ollama::Variant>, DownloadError> {
+    let bytes = get_bytes(client, config.tags_url(model), "tags").await?;
+    let tags = serde_json::from_slice::<TagsList>(&bytes)?;
+    Ok(tags
+        .tags
+        .iter()
+        .filter_map(|tag| ollama::Variant::from_str(tag).ok())
+        .collect())
+}
+
+/// List the models of the registry whose name contains `query`
+pub async fn search_models(
+    client: &reqwest::Client,
+    config: &ollama::OllamaConfig,
+    query: &str,
+) -> Result<Vec<String>, DownloadError> {
+    let bytes = get_bytes(client, config.catalog_url(), "catalog").await?;
+    let catalog = serde_json::from_slice::<Catalog>(&bytes)?;
+    Ok(catalog
+        .repositories
+        .into_iter()
+        .map(|name| match name.strip_prefix("library/") {
+            Some(name) => name.to_string(),
+            None => name,
+        })
+        .filter(|name| name.contains(query))
+        .collect())
+}
+
+/// Fetch the manifest of a model without storing it
+pub async fn fetch_manifest(
+    client: &reqwest::Client,
+    config: &ollama::OllamaConfig,
+    model: &ollama::Model,
+    variant: &ollama::Variant,
+) -> Result<ollama::Manifest, DownloadError> {
+    let response = client
+        .get(config.manifest_url(model, variant))
+        .header(
+            reqwest::header::ACCEPT,
+            ollama::MEDIA_TYPE_DOCKER_DISTRIBUTION_MANIFEST,
+        )
+        .send()
+        .await
+        .map_err(HttpError::from)?;
+    if response.status() != StatusCode::OK {
+        return Err(DownloadError::ManifestError(response.status()));
+    }
+    let bytes = response.bytes().await.map_err(HttpError::from)?;
+    Ok(ollama::Manifest::from_json_bytes(&bytes)?)
+}
+
+/// Fetch and parse the config blob of a manifest, which is small, without storing it
+pub async fn fetch_image_config(
+    client: &reqwest::Client,
+    config: &ollama::OllamaConfig,
+    manifest: &ollama::Manifest,
+) -> Result<ollama::ImageConfig, DownloadError> {
+    let bytes = get_bytes(client, config.blob_url(&manifest.config.digest), "config").await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
@@ -5,6 +5,8 @@ pub trait ProgressDisplay {
     fn progress_start(size: Option<u64>) -> Self;
     fn progress_update(&self, position: u64);
     fn progress_finalize(self);
+    /// Called once when the download waits for another one of the same file to finish
+    fn progress_waiting(_what: &str) {}
 }
 
 #[derive(Clone)]
@@ -0,0 +1,127 @@
+//! Messages of a conversation with a model
+use std::{borrow::Cow, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use skelm_llama_cpp as llama;
+
+use crate::tools::ToolCall;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+    /// the result of a tool call
+    Tool,
+}
+
+impl ChatRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+            Self::Tool => "tool",
+        }
+    }
+}
+
+/// An image given to a vision model, PNG or JPEG
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Image {
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl Image {
+    pub fn bytes(&self) -> std::io::Result<Cow<'_, [u8]>> {
+        match self {
+            Self::Path(path) => std::fs::read(path).map(Cow::Owned),
+            Self::Bytes(bytes) => Ok(Cow::Borrowed(bytes)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+    /// calls made by an assistant message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// the tool whose result is given by a tool message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_name: Option<String>,
+    /// images of a user message, placed before its text
+    #[serde(skip)]
+    pub images: Vec<Image>,
+}
+
+impl ChatMessage {
+    fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_name: None,
+            images: Vec::new(),
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::new(ChatRole::Assistant, content)
+        }
+    }
+
+    pub fn tool(name: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_name: Some(name.into()),
+            ..Self::new(ChatRole::Tool, content)
+        }
+    }
+
+    pub fn with_images(self, images: Vec<Image>) -> Self {
+        Self { images, ..self }
+    }
+
+    /// The content preceded by a media marker for each image, the markers being
+    /// replaced by the image embeddings when the prompt is evaluated
+    pub fn content_with_markers(&self) -> Cow<'_, str> {
+        if self.images.is_empty() {
+            Cow::Borrowed(self.content.as_str())
+        } else {
+            let markers = format!("{}\n", llama::MEDIA_MARKER).repeat(self.images.len());
+            Cow::Owned(format!("{}{}", markers, self.content))
+        }
+    }
+
+    /// The message in the format expected by chat templates
+    pub fn template_value(&self) -> serde_json::Value {
+        let mut value = serde_json::json!({
+            "role": self.role.as_str(),
+            "content": self.content_with_markers(),
+        });
+        if !self.tool_calls.is_empty() {
+            value["tool_calls"] = self
+                .tool_calls
+                .iter()
+                .map(|call| call.template_value())
+                .collect();
+        }
+        if let Some(name) = &self.tool_name {
+            value["name"] = name.clone().into();
+        }
+        value
+    }
+}
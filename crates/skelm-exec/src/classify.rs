@@ -0,0 +1,171 @@
+//! Scoring of candidate completions of a prompt, for classification and multiple choice
+//!
+//! The prompt is decoded once, then copied to one sequence per candidate, sharing its
+//! cells in the KV cache, and the candidates are all decoded in the same batch.
+use skelm_llama_cpp as llama;
+use thiserror::Error;
+
+use crate::Context;
+use crate::score::log_softmax;
+
+#[derive(Clone, Debug)]
+pub struct CandidateScore {
+    pub text: String,
+    /// number of tokens of the candidate
+    pub tokens: usize,
+    /// log-likelihood of the candidate following the prompt
+    pub logprob: f64,
+    /// probability of the candidate among all the candidates
+    pub probability: f64,
+}
+
+#[derive(Debug, Error)]
+pub enum ClassifyError {
+    #[error("no candidates to score")]
+    NoCandidates,
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    #[error("candidate {0:?} is empty")]
+    EmptyCandidate(String),
+    #[error("{candidates} candidates need a context of {needed} sequences, it has {n_seq_max}")]
+    TooManyCandidates {
+        candidates: usize,
+        needed: usize,
+        n_seq_max: usize,
+    },
+    #[error("the prompt and the candidates need {needed} tokens, the context has {n_ctx}")]
+    ContextTooSmall { needed: usize, n_ctx: usize },
+    #[error(transparent)]
+    Decode(#[from] llama::DecodeError),
+}
+
+/// The probabilities of the candidates from their scores, with a softmax
+fn normalize(scores: &[f64]) -> Vec<f64> {
+    let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exps = scores.iter().map(|s| (s - max).exp()).collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f64>();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+impl Context {
+    /// Score each candidate as a completion of the prompt, in the order of `candidates`
+    ///
+    /// The probabilities are normalized over the candidates, from their total
+    /// log-likelihood, or from their mean log-likelihood per token when
+    /// `length_normalized` is set, not to favor the shorter candidates. The context is
+    /// cleared first, needs one sequence more than the number of candidates and a
+    /// unified KV cache for the candidates to share the cells of the prompt.
+    pub fn classify(
+        &mut self,
+        prompt: &str,
+        candidates: &[String],
+        length_normalized: bool,
+    ) -> Result<Vec<CandidateScore>, ClassifyError> {
+        if candidates.is_empty() {
+            return Err(ClassifyError::NoCandidates);
+        }
+        let n_seq_max = self.1.n_seq_max() as usize;
+        // the prompt stays alone in sequence 0
+        if candidates.len() + 1 > n_seq_max {
+            return Err(ClassifyError::TooManyCandidates {
+                candidates: candidates.len(),
+                needed: candidates.len() + 1,
+                n_seq_max,
+            });
+        }
+        let vocab = &self.0.vocab;
+        let prompt_tokens = vocab.tokenize(prompt.as_bytes(), true);
+        if prompt_tokens.is_empty() {
+            return Err(ClassifyError::EmptyPrompt);
+        }
+        let candidate_tokens = candidates
+            .iter()
+            .map(|c| {
+                let tokens = vocab.tokenize_with(c.as_bytes(), false, false);
+                match tokens.is_empty() {
+                    true => Err(ClassifyError::EmptyCandidate(c.clone())),
+                    false => Ok(tokens),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let needed = prompt_tokens.len() + candidate_tokens.iter().map(Vec::len).sum::<usize>();
+        let n_ctx = self.1.n_ctx() as usize;
+        if needed > n_ctx {
+            return Err(ClassifyError::ContextTooSmall { needed, n_ctx });
+        }
+
+        self.1.memory_clear(true);
+        self.1.append_tokens(&prompt_tokens)?;
+        let prompt_logits = self.1.get_logits(-1).to_vec();
+
+        // the first token of each candidate is predicted by the prompt, the next ones by
+        // the previous token of the candidate
+        let mut logprobs = candidate_tokens
+            .iter()
+            .map(|tokens| log_softmax(&prompt_logits, tokens[0].as_index()))
+            .collect::<Vec<_>>();
+        let mut batch = Vec::new();
+        // the candidate and the token predicted by each token of the batch
+        let mut targets = Vec::new();
+        for (i, tokens) in candidate_tokens.iter().enumerate() {
+            let seq = i as i32 + 1;
+            self.1.seq_copy(0, seq);
+            for (j, token) in tokens.iter().enumerate() {
+                let next = tokens.get(j + 1).copied();
+                batch.push(llama::SequenceToken {
+                    token: *token,
+                    position: prompt_tokens.len() + j,
+                    seq,
+                    logits: next.is_some(),
+                });
+                targets.push(next.map(|next| (i, next)));
+            }
+        }
+        let decoded = self.1.decode_sequences(&batch, |index, logits| {
+            if let Some((i, next)) = targets[index] {
+                logprobs[i] += log_softmax(logits, next.as_index());
+            }
+        });
+        for i in 0..candidates.len() {
+            self.1.seq_remove(i as i32 + 1);
+        }
+        decoded?;
+
+        let scores = logprobs
+            .iter()
+            .zip(&candidate_tokens)
+            .map(|(logprob, tokens)| match length_normalized {
+                true => logprob / tokens.len() as f64,
+                false => *logprob,
+            })
+            .collect::<Vec<_>>();
+        let probabilities = normalize(&scores);
+        Ok(candidates
+            .iter()
+            .zip(candidate_tokens)
+            .zip(logprobs)
+            .zip(probabilities)
+            .map(|(((text, tokens), logprob), probability)| CandidateScore {
+                text: text.clone(),
+                tokens: tokens.len(),
+                logprob,
+                probability,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_scores() {
+        let probabilities = normalize(&[2.0f64.ln(), 1.0f64.ln(), 1.0f64.ln()]);
+        assert!((probabilities[0] - 0.5).abs() < 1e-9);
+        assert!((probabilities[1] - 0.25).abs() < 1e-9);
+        // very unlikely candidates don't underflow the others
+        let probabilities = normalize(&[-1000.0, -1001.0]);
+        assert!(probabilities[0] > 0.7 && probabilities[0] < 0.75);
+    }
+}
@@ -0,0 +1,112 @@
+//! Loading of control vectors, as written by llama.cpp's `cvector-generator`
+//!
+//! A control vector file is a GGUF file with one F32 tensor `direction.<layer>` of
+//! `n_embd` values for each steered layer.
+use std::path::{Path, PathBuf};
+
+use skelm_gguf::{GgmlType, GgufError, GgufFile};
+use skelm_llama_cpp as llama;
+use thiserror::Error;
+
+const DIRECTION_PREFIX: &str = "direction.";
+
+#[derive(Debug, Error)]
+pub enum ControlVectorLoadError {
+    #[error("cannot read control vector {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("invalid control vector {0}: {1}")]
+    Gguf(PathBuf, GgufError),
+    #[error("control vector {0}: tensor {1} is not a F32 vector")]
+    InvalidTensor(PathBuf, String),
+    #[error("control vector {0} has no direction tensors")]
+    NoDirections(PathBuf),
+    #[error("control vector {0} has {1} values per layer, expected {2}")]
+    SizeMismatch(PathBuf, usize, usize),
+    #[error("control vector {0}: tensor {1} is beyond the {2} layers of the model")]
+    LayerOutOfRange(PathBuf, String, usize),
+}
+
+/// Read the directions of a control vector file for a model of `n_layer` layers
+pub fn load_control_vector(
+    path: &Path,
+    n_layer: usize,
+) -> Result<llama::ControlVector, ControlVectorLoadError> {
+    let data =
+        std::fs::read(path).map_err(|e| ControlVectorLoadError::Read(path.to_path_buf(), e))?;
+    let gguf =
+        GgufFile::parse(&data).map_err(|e| ControlVectorLoadError::Gguf(path.to_path_buf(), e))?;
+    gguf.validate()
+        .map_err(|e| ControlVectorLoadError::Gguf(path.to_path_buf(), e))?;
+
+    let invalid =
+        |name: &str| ControlVectorLoadError::InvalidTensor(path.to_path_buf(), name.to_string());
+    let mut cvec = llama::ControlVector::default();
+    for tensor in &gguf.tensors {
+        let Some(layer) = tensor.name.strip_prefix(DIRECTION_PREFIX) else {
+            continue;
+        };
+        // there is no direction for layer 0
+        let layer = layer
+            .parse::<usize>()
+            .ok()
+            .filter(|layer| *layer > 0)
+            .ok_or_else(|| invalid(&tensor.name))?;
+        if tensor.ty != GgmlType::F32 || tensor.dims.len() != 1 {
+            return Err(invalid(&tensor.name));
+        }
+        if layer >= n_layer {
+            return Err(ControlVectorLoadError::LayerOutOfRange(
+                path.to_path_buf(),
+                tensor.name.clone(),
+                n_layer,
+            ));
+        }
+        let n_embd = tensor.dims[0] as usize;
+        if cvec.n_embd == 0 {
+            cvec.n_embd = n_embd;
+        } else if cvec.n_embd != n_embd {
+            return Err(ControlVectorLoadError::SizeMismatch(
+                path.to_path_buf(),
+                n_embd,
+                cvec.n_embd,
+            ));
+        }
+        let start = (gguf.data_offset + tensor.offset) as usize;
+        let bytes = &data[start..start + n_embd * 4];
+        let end = layer
+            .checked_mul(n_embd)
+            .ok_or_else(|| invalid(&tensor.name))?;
+        if cvec.data.len() < end {
+            cvec.data.resize(end, 0.0);
+        }
+        for (value, bytes) in cvec.data[end - n_embd..end]
+            .iter_mut()
+            .zip(bytes.chunks_exact(4))
+        {
+            *value = f32::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+    if cvec.data.is_empty() {
+        return Err(ControlVectorLoadError::NoDirections(path.to_path_buf()));
+    }
+    Ok(cvec)
+}
+
+/// Load control vectors and sum their directions scaled by their strength
+pub fn load_control_vectors(
+    vectors: &[(PathBuf, f32)],
+    n_layer: usize,
+) -> Result<llama::ControlVector, ControlVectorLoadError> {
+    let mut cvec = llama::ControlVector::default();
+    for (path, strength) in vectors {
+        let other = load_control_vector(path, n_layer)?;
+        if !cvec.add_scaled(&other, *strength) {
+            return Err(ControlVectorLoadError::SizeMismatch(
+                path.clone(),
+                other.n_embd,
+                cvec.n_embd,
+            ));
+        }
+    }
+    Ok(cvec)
+}
@@ -0,0 +1,264 @@
+//! Incremental conversion of generated tokens to text
+//!
+//! A single character can be split across multiple tokens (CJK text, emoji, byte
+//! fallback tokens), so the bytes of each token can't be decoded on their own.
+use skelm_llama_cpp as llama;
+
+/// Bytes waiting for the rest of an incomplete UTF-8 sequence
+#[derive(Default)]
+struct Utf8Buffer {
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    /// Append bytes and return the text of all the complete characters, replacing
+    /// invalid sequences by U+FFFD
+    fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = &self.pending[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    // SAFETY: checked by from_utf8
+                    text.push_str(unsafe { std::str::from_utf8_unchecked(valid) });
+                    match e.error_len() {
+                        // incomplete sequence at the end, wait for the next bytes
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                        Some(n) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[n..];
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        text
+    }
+
+    /// Return the remaining bytes, an incomplete sequence being replaced by U+FFFD
+    fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+/// Filter of the control tokens, keeping the ones whose text is kept, including texts
+/// spanning several tokens like `<|channel|>analysis<|message|>`
+#[derive(Default)]
+struct ControlFilter {
+    keep: Vec<Vec<u8>>,
+    /// control tokens starting a header, dropped with its text up to `header_end`
+    header_starts: Vec<Vec<u8>>,
+    header_end: Vec<u8>,
+    /// tokens held while they could be the start of a kept text or are in a header,
+    /// with whether each is a control token
+    pending: Vec<(bool, Vec<u8>)>,
+}
+
+impl ControlFilter {
+    /// Add the bytes of a token, appending the bytes to output to `out`
+    fn push(&mut self, control: bool, bytes: &[u8], out: &mut Vec<u8>) {
+        if !control && self.pending.is_empty() {
+            out.extend_from_slice(bytes);
+            return;
+        }
+        self.pending.push((control, bytes.to_vec()));
+        let held = self
+            .pending
+            .iter()
+            .flat_map(|(_, bytes)| bytes.iter().copied())
+            .collect::<Vec<_>>();
+        if self.in_header() {
+            // only a kept text ending the header is output
+            if control && bytes == self.header_end {
+                self.pending.clear();
+                if let Some(text) = self.keep.iter().find(|text| held.ends_with(text)) {
+                    out.extend_from_slice(text);
+                }
+            }
+        } else if self.keep.contains(&held) {
+            self.pending.clear();
+            out.extend_from_slice(&held);
+        } else if !self.keep.iter().any(|text| text.starts_with(&held)) {
+            self.release(out);
+        }
+    }
+
+    /// Drop the first held token, a control token which doesn't start a kept text, and
+    /// replay the ones after it
+    fn release(&mut self, out: &mut Vec<u8>) {
+        let pending = std::mem::take(&mut self.pending);
+        for (control, bytes) in pending.into_iter().skip(1) {
+            self.push(control, &bytes, out);
+        }
+    }
+
+    fn in_header(&self) -> bool {
+        self.pending
+            .first()
+            .is_some_and(|(control, bytes)| *control && self.header_starts.contains(bytes))
+    }
+
+    /// Flush the tokens held at the end of the stream, an unfinished header is dropped
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        while !self.pending.is_empty() {
+            if self.in_header() {
+                self.pending.clear();
+            } else {
+                self.release(out);
+            }
+        }
+    }
+}
+
+/// Convert a stream of tokens to chunks of valid text
+pub struct TokenTextDecoder {
+    vocab: llama::Vocab,
+    buffer: Utf8Buffer,
+    strip_leading_space: bool,
+    skip_control: bool,
+    control: ControlFilter,
+    started: bool,
+}
+
+impl TokenTextDecoder {
+    pub fn new(vocab: llama::Vocab) -> Self {
+        Self {
+            vocab,
+            buffer: Utf8Buffer::default(),
+            strip_leading_space: false,
+            skip_control: true,
+            control: ControlFilter::default(),
+            started: false,
+        }
+    }
+
+    /// Remove the space at the start of the text, which SentencePiece tokenizers add as
+    /// a prefix of the first word (like llama.cpp does when detokenizing)
+    pub fn strip_leading_space(mut self, strip: bool) -> Self {
+        self.strip_leading_space = strip;
+        self
+    }
+
+    /// Don't output the text of control tokens (e.g. `<|im_end|>`), the default
+    pub fn skip_control(mut self, skip: bool) -> Self {
+        self.skip_control = skip;
+        self
+    }
+
+    /// Output the control tokens with one of these texts, e.g. reasoning markers, which
+    /// can be made of several tokens
+    pub fn keep_control(mut self, texts: &[&str]) -> Self {
+        self.control.keep = texts.iter().map(|s| s.as_bytes().to_vec()).collect();
+        self
+    }
+
+    /// Drop the headers starting with one of the `starts` control tokens and ending with
+    /// the `end` one, like the role and channel of gpt-oss messages
+    /// (`<|start|>assistant<|channel|>final<|message|>`), a header ending with a kept
+    /// control text being replaced by it
+    pub fn strip_headers(mut self, starts: &[&str], end: &str) -> Self {
+        self.control.header_starts = starts.iter().map(|s| s.as_bytes().to_vec()).collect();
+        self.control.header_end = end.as_bytes().to_vec();
+        self
+    }
+
+    /// Add a token, returning the text completed by it (possibly empty)
+    pub fn push(&mut self, token: llama::Token) -> String {
+        let control = self.skip_control && self.vocab.token_attr(token).is_control();
+        let mut bytes = Vec::new();
+        self.control
+            .push(control, &self.vocab.as_bytes(token), &mut bytes);
+        let text = self.buffer.push(&bytes);
+        self.strip(text)
+    }
+
+    /// Flush the held tokens and the bytes of an incomplete character at the end of the
+    /// stream
+    pub fn finish(&mut self) -> String {
+        let mut bytes = Vec::new();
+        self.control.finish(&mut bytes);
+        let mut text = self.buffer.push(&bytes);
+        text.push_str(&self.buffer.finish());
+        self.strip(text)
+    }
+
+    fn strip(&mut self, text: String) -> String {
+        if self.started || text.is_empty() {
+            return text;
+        }
+        self.started = true;
+        match text.strip_prefix(' ') {
+            Some(stripped) if self.strip_leading_space => stripped.to_string(),
+            _ => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_split_across_pushes() {
+        let mut buffer = Utf8Buffer::default();
+        let bytes = "a😀é".as_bytes();
+        assert_eq!(buffer.push(&bytes[..2]), "a");
+        assert_eq!(buffer.push(&bytes[2..4]), "");
+        assert_eq!(buffer.push(&bytes[4..6]), "😀");
+        assert_eq!(buffer.push(&bytes[6..]), "é");
+        assert_eq!(buffer.finish(), "");
+    }
+
+    #[test]
+    fn control_texts_across_tokens() {
+        let mut filter = ControlFilter {
+            keep: vec![
+                b"<|channel|>analysis<|message|>".to_vec(),
+                b"<|end|>".to_vec(),
+            ],
+            header_starts: vec![b"<|start|>".to_vec(), b"<|channel|>".to_vec()],
+            header_end: b"<|message|>".to_vec(),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        for (control, bytes) in [
+            (true, &b"<|start|>"[..]),
+            (true, b"<|channel|>"),
+            (false, b"analysis"),
+            (true, b"<|message|>"),
+            (false, b"hm"),
+            (true, b"<|end|>"),
+            (true, b"<|start|>"),
+            (false, b"assistant"),
+            (true, b"<|channel|>"),
+            (false, b"final"),
+            (true, b"<|message|>"),
+            (false, b"ok"),
+            (true, b"<|channel|>"),
+        ] {
+            filter.push(control, bytes, &mut out);
+        }
+        filter.finish(&mut out);
+        assert_eq!(out, b"<|channel|>analysis<|message|>hm<|end|>ok");
+    }
+
+    #[test]
+    fn utf8_invalid_bytes() {
+        let mut buffer = Utf8Buffer::default();
+        assert_eq!(buffer.push(b"a\xffb\xe2\x82"), "a\u{fffd}b");
+        assert_eq!(buffer.finish(), "\u{fffd}");
+    }
+}
@@ -0,0 +1,282 @@
+//! GBNF grammars constraining the generation of tool calls
+//!
+//! The JSON schemas of the tool parameters are converted to grammar rules, so that the
+//! model can only generate calls to the declared tools with well-formed arguments. Only
+//! the common subset of JSON schema is followed (types, properties, required, items,
+//! enum, const); anything else accepts any JSON value.
+use serde_json::Value;
+use skelm_ollama as ollama;
+
+use crate::tools::ToolCallFormat;
+
+const PRIMITIVE_RULES: &[(&str, &str)] = &[
+    ("space", r#"| " " | "\n" [ \t]{0,20}"#),
+    (
+        "string",
+        r#""\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\"" space"#,
+    ),
+    (
+        "number",
+        r#""-"? ( [0-9] | [1-9] [0-9]{0,15} ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? space"#,
+    ),
+    ("integer", r#""-"? ( [0-9] | [1-9] [0-9]{0,15} ) space"#),
+    ("boolean", r#"( "true" | "false" ) space"#),
+    ("null", r#""null" space"#),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+    ),
+    (
+        "object",
+        r#""{" space ( string ":" space value ( "," space string ":" space value )* )? "}" space"#,
+    ),
+    (
+        "array",
+        r#""[" space ( value ( "," space value )* )? "]" space"#,
+    ),
+];
+
+/// A GBNF string literal matching exactly `s`
+fn literal(s: &str) -> String {
+    // JSON escapes are valid in GBNF literals
+    serde_json::to_string(s).unwrap()
+}
+
+/// A GBNF literal matching the JSON encoding of `value`
+fn json_literal(value: &Value) -> String {
+    literal(&value.to_string())
+}
+
+struct GrammarBuilder {
+    rules: Vec<(String, String)>,
+}
+
+impl GrammarBuilder {
+    fn new() -> Self {
+        let rules = PRIMITIVE_RULES
+            .iter()
+            .map(|(name, body)| (name.to_string(), body.to_string()))
+            .collect();
+        Self { rules }
+    }
+
+    /// Add a rule, making its name unique, and return the name
+    fn add(&mut self, name: &str, body: String) -> String {
+        let base: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let mut name = base.clone();
+        let mut i = 1;
+        while self.rules.iter().any(|(n, _)| n == &name) {
+            i += 1;
+            name = format!("{}-{}", base, i);
+        }
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    /// The rule matching the JSON values valid for `schema`
+    fn schema(&mut self, name: &str, schema: &Value) -> String {
+        if let Some(value) = schema.get("const") {
+            return self.add(name, format!("{} space", json_literal(value)));
+        }
+        if let Some(values) = schema.get("enum").and_then(|v| v.as_array()) {
+            let alternatives = values.iter().map(json_literal).collect::<Vec<_>>();
+            return self.add(name, format!("( {} ) space", alternatives.join(" | ")));
+        }
+        match schema.get("type").and_then(|t| t.as_str()) {
+            Some("object") => self.object(name, schema),
+            Some("array") => {
+                let items = match schema.get("items") {
+                    Some(items) => self.schema(&format!("{}-item", name), items),
+                    None => "value".to_string(),
+                };
+                self.add(
+                    name,
+                    format!(
+                        r#""[" space ( {items} ( "," space {items} )* )? "]" space"#,
+                        items = items
+                    ),
+                )
+            }
+            Some(ty @ ("string" | "number" | "integer" | "boolean" | "null")) => ty.to_string(),
+            _ => "value".to_string(),
+        }
+    }
+
+    /// Objects with their required properties first, then the optional ones, in the
+    /// order of the schema
+    fn object(&mut self, name: &str, schema: &Value) -> String {
+        let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) else {
+            return "object".to_string();
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(|r| r.as_array())
+            .map(|r| r.iter().filter_map(|v| v.as_str()).collect())
+            .unwrap_or_default();
+
+        let mut members = Vec::new();
+        for (key, property) in properties {
+            let value = self.schema(&format!("{}-{}", name, key), property);
+            let member = format!(
+                r#"{} space ":" space {}"#,
+                json_literal(&key.clone().into()),
+                value
+            );
+            members.push((required.contains(&key.as_str()), key, member));
+        }
+
+        let mut body = String::from(r#""{" space"#);
+        let mut first = true;
+        for (_, _, member) in members.iter().filter(|(required, ..)| *required) {
+            if !first {
+                body.push_str(r#" "," space"#);
+            }
+            body.push(' ');
+            body.push_str(member);
+            first = false;
+        }
+        let optional = members
+            .iter()
+            .filter(|(required, ..)| !*required)
+            .collect::<Vec<_>>();
+        if first && !optional.is_empty() {
+            // as in llama.cpp's json-schema-to-grammar, one alternative per optional
+            // member written first, followed by a rule for the members after it which
+            // are each preceded by a comma
+            let mut alternatives = Vec::new();
+            let mut rest = String::new();
+            for (i, (_, _, member)) in optional.iter().enumerate().rev() {
+                alternatives.push(format!("{} {}", member, rest).trim_end().to_string());
+                if i > 0 {
+                    let tail = format!(r#"( "," space {} )? {}"#, member, rest);
+                    rest = self.add(
+                        &format!("{}-{}-rest", name, optional[i - 1].1),
+                        tail.trim_end().to_string(),
+                    );
+                }
+            }
+            alternatives.reverse();
+            body.push_str(&format!(" ( {} )?", alternatives.join(" | ")));
+        } else {
+            for (_, _, member) in optional {
+                body.push_str(&format!(r#" ( "," space {} )?"#, member));
+            }
+        }
+        body.push_str(r#" "}" space"#);
+        self.add(name, body)
+    }
+
+    fn build(self) -> String {
+        self.rules
+            .iter()
+            .map(|(name, body)| format!("{} ::= {}\n", name, body))
+            .collect()
+    }
+}
+
+/// Grammar of the tool calls of `format` to the given tools, whose root rule starts with
+/// the trigger of the format
+pub fn tool_call_grammar(format: ToolCallFormat, tools: &[ollama::Tool]) -> String {
+    let mut builder = GrammarBuilder::new();
+    let arguments_key = match format {
+        ToolCallFormat::Llama3 => "parameters",
+        ToolCallFormat::Hermes | ToolCallFormat::Mistral => "arguments",
+    };
+
+    let calls = tools
+        .iter()
+        .map(|tool| {
+            let arguments = builder.schema(&format!("{}-args", tool.name), &tool.parameters);
+            builder.add(
+                &format!("{}-call", tool.name),
+                format!(
+                    r#""{{" space "\"name\"" space ":" space {} space "," space {} space ":" space {} "}}" space"#,
+                    json_literal(&tool.name.clone().into()),
+                    literal(&literal(arguments_key)),
+                    arguments
+                ),
+            )
+        })
+        .collect::<Vec<_>>();
+    let call = builder.add("call", calls.join(" | "));
+
+    let root = match format {
+        ToolCallFormat::Hermes => format!(
+            r#"( {start} space {call} {end} space )+"#,
+            start = literal("<tool_call>"),
+            end = literal("</tool_call>"),
+            call = call
+        ),
+        ToolCallFormat::Llama3 => format!(r#"{call} ( ";" space {call} )*"#, call = call),
+        ToolCallFormat::Mistral => format!(
+            r#"{start} "[" space {call} ( "," space {call} )* "]" space"#,
+            start = literal("[TOOL_CALLS]"),
+            call = call
+        ),
+    };
+    builder.add("root", root);
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_grammar_rules() {
+        let tool = ollama::Tool {
+            name: "get_weather".to_string(),
+            description: String::new(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "city": {"type": "string"},
+                    "unit": {"enum": ["celsius", "fahrenheit"]}
+                },
+                "required": ["city"]
+            }),
+        };
+        let grammar = tool_call_grammar(ToolCallFormat::Hermes, &[tool]);
+        assert!(
+            grammar.contains(
+                r#"get-weather-args-unit ::= ( "\"celsius\"" | "\"fahrenheit\"" ) space"#
+            )
+        );
+        assert!(grammar.contains(
+            r#"get-weather-args ::= "{" space "\"city\"" space ":" space string ( "," space "\"unit\"" space ":" space get-weather-args-unit )? "}" space"#
+        ));
+        assert!(grammar.contains(r#"root ::= ( "<tool_call>" space call "</tool_call>" space )+"#));
+    }
+
+    #[test]
+    fn optional_properties_grammar() {
+        let tool = ollama::Tool {
+            name: "search".to_string(),
+            description: String::new(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "a": {"type": "string"},
+                    "b": {"type": "integer"},
+                    "c": {"type": "boolean"}
+                }
+            }),
+        };
+        let grammar = tool_call_grammar(ToolCallFormat::Hermes, &[tool]);
+        // no comma can be written before the first member
+        assert!(grammar.contains(
+            r#"search-args ::= "{" space ( "\"a\"" space ":" space string search-args-a-rest | "\"b\"" space ":" space integer search-args-b-rest | "\"c\"" space ":" space boolean )? "}" space"#
+        ));
+        assert!(grammar.contains(
+            r#"search-args-a-rest ::= ( "," space "\"b\"" space ":" space integer )? search-args-b-rest"#
+        ));
+        assert!(
+            grammar.contains(
+                r#"search-args-b-rest ::= ( "," space "\"c\"" space ":" space boolean )?"#
+            )
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Loading of importance matrices, as written by llama.cpp's `llama-imatrix`
+//!
+//! Both the GGUF format, with `<tensor>.in_sum2` and `<tensor>.counts` tensors, and the
+//! legacy `.dat` format are supported.
+use std::path::{Path, PathBuf};
+
+use skelm_gguf::{GgmlType, GgufError, GgufFile};
+use skelm_llama_cpp as llama;
+use thiserror::Error;
+
+const SUM2_SUFFIX: &str = ".in_sum2";
+const COUNTS_SUFFIX: &str = ".counts";
+
+#[derive(Debug, Error)]
+pub enum ImatrixLoadError {
+    #[error("cannot read importance matrix {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("invalid importance matrix {0}: {1}")]
+    Gguf(PathBuf, GgufError),
+    #[error("importance matrix {0}: invalid tensor {1}")]
+    InvalidTensor(PathBuf, String),
+    #[error("importance matrix {0} is truncated")]
+    Truncated(PathBuf),
+    #[error("importance matrix {0} has no entries")]
+    Empty(PathBuf),
+}
+
+/// Read an importance matrix file, giving the mean squared activations of each tensor
+pub fn load_imatrix(path: &Path) -> Result<llama::ImportanceMatrix, ImatrixLoadError> {
+    let data = std::fs::read(path).map_err(|e| ImatrixLoadError::Read(path.to_path_buf(), e))?;
+    let imatrix = if data.starts_with(b"GGUF") {
+        load_gguf(path, &data)?
+    } else {
+        load_legacy(&data).ok_or_else(|| ImatrixLoadError::Truncated(path.to_path_buf()))?
+    };
+    if imatrix.is_empty() {
+        return Err(ImatrixLoadError::Empty(path.to_path_buf()));
+    }
+    Ok(imatrix)
+}
+
+fn load_gguf(path: &Path, data: &[u8]) -> Result<llama::ImportanceMatrix, ImatrixLoadError> {
+    let gguf = GgufFile::parse(data).map_err(|e| ImatrixLoadError::Gguf(path.to_path_buf(), e))?;
+    gguf.validate()
+        .map_err(|e| ImatrixLoadError::Gguf(path.to_path_buf(), e))?;
+
+    let invalid = |name: &str| ImatrixLoadError::InvalidTensor(path.to_path_buf(), name.into());
+    let tensor_data = |name: &str| {
+        let tensor = gguf
+            .tensors
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| invalid(name))?;
+        if tensor.ty != GgmlType::F32 || tensor.dims.is_empty() {
+            return Err(invalid(name));
+        }
+        let start = (gguf.data_offset + tensor.offset) as usize;
+        let end = start + tensor.n_elements() as usize * 4;
+        let bytes = data.get(start..end).ok_or_else(|| invalid(name))?;
+        Ok((tensor.dims[0] as usize, f32_values(bytes)))
+    };
+
+    let mut imatrix = llama::ImportanceMatrix::new();
+    for tensor in &gguf.tensors {
+        let Some(name) = tensor.name.strip_suffix(SUM2_SUFFIX) else {
+            continue;
+        };
+        let (n_per_row, sums) = tensor_data(&tensor.name)?;
+        let (_, counts) = tensor_data(&format!("{}{}", name, COUNTS_SUFFIX))?;
+        if n_per_row == 0 || sums.len() != n_per_row * counts.len() {
+            return Err(invalid(&tensor.name));
+        }
+        // each matrix of a stacked (expert) tensor has its own count
+        let mut values = Vec::with_capacity(sums.len());
+        for (sums, count) in sums.chunks_exact(n_per_row).zip(&counts) {
+            if *count > 0.0 {
+                values.extend(sums.iter().map(|s| s / count));
+            } else {
+                // never activated during calibration, treat all weights as equal
+                values.extend(std::iter::repeat_n(1.0, n_per_row));
+            }
+        }
+        imatrix.insert(name.to_string(), values);
+    }
+    Ok(imatrix)
+}
+
+/// The legacy format: a count of entries, then for each the tensor name, the number of
+/// calls and the sum of the squared activations
+fn load_legacy(data: &[u8]) -> Option<llama::ImportanceMatrix> {
+    let mut reader = Reader(data);
+    let n_entries = reader.i32()?;
+    let mut imatrix = llama::ImportanceMatrix::new();
+    for _ in 0..n_entries {
+        let len = reader.i32()?;
+        let name = String::from_utf8_lossy(reader.bytes(usize::try_from(len).ok()?)?).into_owned();
+        let ncall = reader.i32()?;
+        let nval = usize::try_from(reader.i32()?).ok()?;
+        let mut values = f32_values(reader.bytes(nval.checked_mul(4)?)?);
+        if ncall > 0 {
+            for value in &mut values {
+                *value /= ncall as f32;
+            }
+        }
+        imatrix.insert(name, values);
+    }
+    Some(imatrix)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+fn f32_values(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_imatrix() {
+        let mut data = Vec::new();
+        data.extend(1i32.to_le_bytes());
+        data.extend(6i32.to_le_bytes());
+        data.extend(b"output");
+        data.extend(2i32.to_le_bytes());
+        data.extend(2i32.to_le_bytes());
+        data.extend(4.0f32.to_le_bytes());
+        data.extend(1.0f32.to_le_bytes());
+        let imatrix = load_legacy(&data).unwrap();
+        assert_eq!(imatrix["output"], [2.0, 0.5]);
+        assert!(load_legacy(&data[..data.len() - 1]).is_none());
+    }
+}
@@ -1,6 +1,18 @@
+mod chat;
+mod classify;
+mod control;
+mod decoder;
+mod grammar;
+mod imatrix;
+mod logprobs;
+mod memory;
+mod score;
 mod template;
+mod thinking;
+mod tools;
 
 use std::hash::Hash;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::sync::Mutex;
 use std::{collections::HashMap, sync::Arc};
@@ -10,7 +22,20 @@ use thiserror::Error;
 use skelm_llama_cpp as llama;
 use skelm_ollama as ollama;
 
-pub use template::chat_template;
+pub use chat::{ChatMessage, ChatRole, Image};
+pub use classify::{CandidateScore, ClassifyError};
+pub use control::{ControlVectorLoadError, load_control_vector, load_control_vectors};
+pub use decoder::TokenTextDecoder;
+pub use grammar::tool_call_grammar;
+pub use imatrix::{ImatrixLoadError, load_imatrix};
+pub use logprobs::{TokenLogprobs, TopLogprob, token_logprobs};
+pub use memory::{
+    MemoryEstimate, MemoryShortfall, SystemMemory, check_memory_fit, estimate_memory,
+};
+pub use score::{Score, ScoreError, ScoreParams, TokenLogprob};
+pub use template::{chat_template, chat_template_messages};
+pub use thinking::{TextChunk, ThinkingMarkers, ThinkingParser};
+pub use tools::{ToolCall, ToolCallFormat};
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum ModelDescr {
@@ -18,11 +43,28 @@ pub enum ModelDescr {
     Path(PathBuf),
 }
 
+impl std::fmt::Display for ModelDescr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ollama(descr) => write!(f, "{}", descr),
+            Self::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Model {
     pub model: llama::Model,
     pub vocab: llama::Vocab,
     pub config: Arc<ModelConfig>,
+    /// parameters of the contexts created from this model
+    pub context_params: llama::ContextParams,
+    /// the multimodal projector, when loaded
+    pub projector: Option<llama::Projector>,
+    /// the LoRA adapters set on the new contexts, with their scale
+    pub adapters: Vec<(llama::LoraAdapter, f32)>,
+    /// the control vector applied by the new contexts, and its layers
+    pub control_vector: Option<(llama::ControlVector, RangeInclusive<i32>)>,
 }
 
 #[derive(Clone)]
@@ -33,71 +75,274 @@ pub enum ModelConfig {
 
 #[derive(Debug, Error)]
 pub enum ModelLoadError {
-    #[error("Llama model failed to load model")]
-    LlamaModelFailedLoading(#[from] llama::ModelLoadError),
+    #[error("Failed to load model {0}: {1}")]
+    LlamaModelFailedLoading(ModelDescr, Box<llama::ModelLoadError>),
     #[error("Ollama config get error {0}")]
     OllamaConfigGetError(#[from] ollama::ModelConfigGetError),
+    #[error("Invalid model file {0}: {1}")]
+    InvalidModelFile(PathBuf, skelm_gguf::GgufError),
+    #[error("Not enough memory to load {0}: {1}")]
+    InsufficientMemory(PathBuf, MemoryShortfall),
+    #[error("Failed to load the projector of {0}: {1}")]
+    ProjectorFailedLoading(ModelDescr, Box<llama::ProjectorLoadError>),
+    #[error("Model {0} has no multimodal projector")]
+    NoProjector(ModelDescr),
+    #[error("Failed to load an adapter of {0}: {1}")]
+    AdapterFailedLoading(ModelDescr, Box<llama::AdapterLoadError>),
+    #[error("{0}")]
+    ControlVector(#[from] ControlVectorLoadError),
+}
+
+#[derive(Debug, Error)]
+pub enum ContextError {
+    #[error("{0}")]
+    Create(#[from] llama::ContextCreateError),
+    #[error("{0}")]
+    Adapter(#[from] llama::AdapterSetError),
+    #[error("{0}")]
+    ControlVector(#[from] llama::ControlVectorError),
+}
+
+#[derive(Debug, Error)]
+pub enum PromptError {
+    #[error("Model has no multimodal projector to evaluate images")]
+    NoProjector,
+    #[error("Cannot read image: {0}")]
+    ImageRead(#[from] std::io::Error),
+    #[error("{0}")]
+    Media(#[from] llama::MediaError),
+    #[error("Decode error: {0}")]
+    Decode(#[from] llama::DecodeError),
+}
+
+/// Parameters of model loading
+#[derive(Clone, Debug)]
+pub struct LoadParams {
+    pub context: llama::ContextParams,
+    /// refuse to load models whose estimated memory use exceeds the available memory
+    pub memory_check: bool,
+    /// load the multimodal projector of the model, needed for images
+    pub vision: bool,
+    /// projector to use instead of the one of the model
+    pub projector_path: Option<PathBuf>,
+    /// LoRA adapters applied in addition to the ones of the model, with their scale
+    pub adapters: Vec<(PathBuf, f32)>,
+    /// control vectors, with their strength, summed and applied to the model
+    pub control_vectors: Vec<(PathBuf, f32)>,
+    /// the layers steered by the control vectors, all of them by default
+    pub control_vector_layers: Option<RangeInclusive<i32>>,
+}
+
+impl Default for LoadParams {
+    fn default() -> Self {
+        Self {
+            context: llama::ContextParams::default(),
+            memory_check: true,
+            vision: false,
+            projector_path: None,
+            adapters: Vec::new(),
+            control_vectors: Vec::new(),
+            control_vector_layers: None,
+        }
+    }
 }
 
 impl Model {
-    pub fn load(descr: &ModelDescr) -> Result<Self, ModelLoadError> {
+    /// Load a model, looking up models given by name in `store`
+    pub fn load(
+        store: &dyn ollama::ModelStore,
+        descr: &ModelDescr,
+    ) -> Result<Self, ModelLoadError> {
+        Self::load_with(store, descr, &LoadParams::default())
+    }
+
+    pub fn load_with(
+        store: &dyn ollama::ModelStore,
+        descr: &ModelDescr,
+        load_params: &LoadParams,
+    ) -> Result<Self, ModelLoadError> {
         let (config, model_path) = match descr {
             ModelDescr::Ollama(model_descr) => {
-                let config = ollama::model_config_get(model_descr)?;
+                let config = store.model_config(model_descr)?;
                 let model_path = config.model_path.clone();
                 (ModelConfig::Ollama(config), model_path)
             }
             ModelDescr::Path(path_buf) => (ModelConfig::Implicit, path_buf.clone()),
         };
+        // catch corrupt and truncated files before llama.cpp, which only logs the reason
+        let gguf = skelm_gguf::GgufFile::open(&model_path)
+            .and_then(|gguf| gguf.validate().map(|()| gguf))
+            .map_err(|e| ModelLoadError::InvalidModelFile(model_path.clone(), e))?;
+        if load_params.memory_check {
+            check_memory_fit(&gguf, &load_params.context, SystemMemory::get())
+                .map_err(|e| ModelLoadError::InsufficientMemory(model_path.clone(), e))?;
+        }
         let params = llama::ModelParams::default();
-        llama::Model::load(model_path, &params)
-            .map_err(ModelLoadError::LlamaModelFailedLoading)
-            .map(|m| Model {
-                vocab: m.vocab(),
-                model: m,
-                config: Arc::new(config),
+        let model = llama::Model::load(model_path, &params)
+            .map_err(|e| ModelLoadError::LlamaModelFailedLoading(descr.clone(), Box::new(e)))?;
+        let projector_path = match (&load_params.projector_path, &config) {
+            (Some(path), _) => Some(path.clone()),
+            (None, ModelConfig::Ollama(config)) if load_params.vision => {
+                config.projector_path.clone()
+            }
+            _ => None,
+        };
+        let projector = match projector_path {
+            Some(path) => {
+                Some(llama::Projector::load(path, &model).map_err(|e| {
+                    ModelLoadError::ProjectorFailedLoading(descr.clone(), Box::new(e))
+                })?)
+            }
+            None if load_params.vision => return Err(ModelLoadError::NoProjector(descr.clone())),
+            None => None,
+        };
+        let model_adapters: &[PathBuf] = match &config {
+            ModelConfig::Ollama(config) => &config.adapter_paths,
+            ModelConfig::Implicit => &[],
+        };
+        let adapters = model_adapters
+            .iter()
+            .map(|path| (path, 1.0))
+            .chain(
+                load_params
+                    .adapters
+                    .iter()
+                    .map(|(path, scale)| (path, *scale)),
+            )
+            .map(|(path, scale)| {
+                model
+                    .load_adapter(path)
+                    .map(|adapter| (adapter, scale))
+                    .map_err(|e| ModelLoadError::AdapterFailedLoading(descr.clone(), Box::new(e)))
             })
+            .collect::<Result<Vec<_>, _>>()?;
+        let control_vector = if load_params.control_vectors.is_empty() {
+            None
+        } else {
+            let cvec = load_control_vectors(&load_params.control_vectors, model.n_layer())?;
+            let layers = load_params
+                .control_vector_layers
+                .clone()
+                .unwrap_or(1..=cvec.n_layers() as i32);
+            Some((cvec, layers))
+        };
+        Ok(Model {
+            vocab: model.vocab(),
+            model,
+            config: Arc::new(config),
+            context_params: load_params.context.clone(),
+            projector,
+            adapters,
+            control_vector,
+        })
     }
 
-    pub fn new_context(&self) -> Context {
-        let params = llama::ContextParams::default();
-        Context(self.clone(), self.model.new_context(&params).unwrap())
+    pub fn new_context(&self) -> Result<Context, ContextError> {
+        let params = self.context_params.clone();
+        self.with_adapters(self.model.new_context(&params)?)
     }
 
-    pub fn new_context_embeddings(&self) -> Context {
+    pub fn new_context_embeddings(&self) -> Result<Context, ContextError> {
         if self.model.has_encoder() && self.model.has_decoder() {
             panic!("cannot generate embeddings in models with encoder-decoder")
         }
         let params = llama::ContextParams {
             embeddings: true,
-            ..llama::ContextParams::default()
+            ..self.context_params.clone()
         };
-        Context(self.clone(), self.model.new_context(&params).unwrap())
+        self.with_adapters(self.model.new_context(&params)?)
+    }
+
+    fn with_adapters(&self, mut context: llama::Context) -> Result<Context, ContextError> {
+        if !self.adapters.is_empty() {
+            context.set_adapters(&self.adapters)?;
+        }
+        if let Some((cvec, layers)) = &self.control_vector {
+            context.set_control_vector(cvec, *layers.start(), *layers.end())?;
+        }
+        Ok(Context(self.clone(), context))
     }
 
     pub fn model_template_render(&self, parameters: &ModelParameters) -> String {
         match self.config.as_ref() {
-            ModelConfig::Ollama(_model_config) => {
-                implicit_model_template(self, &parameters.system, &parameters.prompt)
-            }
-            ModelConfig::Implicit => {
-                implicit_model_template(self, &parameters.system, &parameters.prompt)
-            }
+            ModelConfig::Ollama(_model_config) => implicit_model_template(self, parameters),
+            ModelConfig::Implicit => implicit_model_template(self, parameters),
+        }
+    }
+
+    /// The markers of the reasoning blocks of this model
+    pub fn thinking_markers(&self) -> ThinkingMarkers {
+        ThinkingMarkers::detect(&self.model.chat_template().unwrap_or_default())
+    }
+
+    /// The way this model writes tool calls, if its chat template supports tools
+    pub fn tool_call_format(&self) -> Option<ToolCallFormat> {
+        ToolCallFormat::detect(&self.model.chat_template()?)
+    }
+
+    /// Render the chat template of the model with a conversation
+    pub fn chat_render(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[ollama::Tool],
+        enable_thinking: Option<bool>,
+    ) -> Result<String, String> {
+        let template = self
+            .model
+            .chat_template()
+            .ok_or_else(|| "model has no chat template".to_string())?;
+        chat_template_messages(&template, messages, tools, enable_thinking)
+    }
+
+    /// A sampler constraining the tool calls to the given tools, applied once the model
+    /// starts a tool call so that it can still answer with text
+    pub fn tool_call_sampler(
+        &self,
+        tools: &[ollama::Tool],
+    ) -> Result<Option<llama::SamplerGrammar>, llama::GrammarError> {
+        let Some(format) = self.tool_call_format() else {
+            return Ok(None);
+        };
+        if tools.is_empty() {
+            return Ok(None);
+        }
+        let grammar = tool_call_grammar(format, tools);
+        let trigger = regex_escape(format.trigger());
+        llama::SamplerGrammar::new_lazy(&self.vocab, &grammar, "root", &[&trigger]).map(Some)
+    }
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
         }
+        escaped.push(c);
     }
+    escaped
 }
 
-fn implicit_model_template(model: &Model, system: &str, prompt: &str) -> String {
+fn implicit_model_template(model: &Model, parameters: &ModelParameters) -> String {
+    let user = ChatMessage::user(parameters.prompt.as_str()).with_images(parameters.images.clone());
+    // without a template, the images still need their markers in the prompt
+    let prompt = user.content_with_markers().into_owned();
     if let Some(template) = model.model.chat_template() {
         //println!("template:\n{}", template);
-        match chat_template(&template, &system, &prompt) {
+        let messages = [ChatMessage::system(parameters.system.as_str()), user];
+        match chat_template_messages(
+            &template,
+            &messages,
+            &parameters.tools,
+            parameters.enable_thinking,
+        ) {
             Err(e) => {
                 eprintln!("rendering chat template failed: {}", e);
                 eprintln!("chat template:");
                 for (i, l) in template.lines().enumerate() {
                     eprintln!("{:03} {}", i + 1, l)
                 }
-                prompt.to_string()
+                prompt
             }
             Ok(render) => {
                 //println!("rendered:\n{}", render);
@@ -105,13 +350,19 @@ fn implicit_model_template(model: &Model, system: &str, prompt: &str) -> String
             }
         }
     } else {
-        prompt.to_string()
+        prompt
     }
 }
 
 pub struct ModelParameters {
     pub system: String,
     pub prompt: String,
+    /// switch the reasoning on or off, for the models supporting it
+    pub enable_thinking: Option<bool>,
+    /// the tools the model can call
+    pub tools: Vec<ollama::Tool>,
+    /// images given with the prompt, to vision models
+    pub images: Vec<Image>,
 }
 
 pub struct Context(pub Model, pub llama::Context);
@@ -125,6 +376,23 @@ impl Context {
         let mut tokens = self.0.vocab.tokenize(bytes, true);
         self.1.append_tokens(&mut tokens).unwrap();
     }
+
+    /// Append a rendered prompt, whose media markers are replaced by the embeddings of
+    /// the images
+    pub fn append_prompt(&mut self, prompt: &str, images: &[Image]) -> Result<(), PromptError> {
+        if images.is_empty() {
+            let tokens = self.0.vocab.tokenize(prompt.as_bytes(), true);
+            self.1.append_tokens(&tokens)?;
+            return Ok(());
+        }
+        let projector = self.0.projector.as_ref().ok_or(PromptError::NoProjector)?;
+        let bitmaps = images
+            .iter()
+            .map(|image| Ok(projector.bitmap_from_bytes(&image.bytes()?)?))
+            .collect::<Result<Vec<_>, PromptError>>()?;
+        projector.eval(&mut self.1, prompt, &bitmaps)?;
+        Ok(())
+    }
 }
 
 pub struct Models<K> {
@@ -0,0 +1,67 @@
+//! Log-probabilities of the generated tokens, in the shape of the OpenAI `logprobs`
+//! and `top_logprobs` fields
+use serde::Serialize;
+use skelm_llama_cpp as llama;
+
+/// Log-probability of the tokens whose probability rounds to 0, as reported by OpenAI
+const MIN_LOGPROB: f32 = -9999.0;
+
+/// The log-probability of a generated token and of the most likely alternatives
+#[derive(Clone, Debug, Serialize)]
+pub struct TokenLogprobs {
+    pub token: String,
+    pub logprob: f32,
+    /// the token may be part of a UTF-8 character, its bytes are exact
+    pub bytes: Vec<u8>,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
+/// The log-probabilities of `token` and of the `top_n` most likely tokens, from the
+/// logits it was sampled from
+///
+/// These are the probabilities of the model, before any sampler changes them.
+pub fn token_logprobs(
+    vocab: &llama::Vocab,
+    logits: &[f32],
+    token: llama::Token,
+    top_n: usize,
+) -> TokenLogprobs {
+    let mut array = llama::TokenDataArray::from_logits(logits);
+    array.softmax();
+    let logprob = array
+        .data
+        .iter()
+        .find(|data| data.id() == token)
+        .map_or(MIN_LOGPROB, |data| to_logprob(data.proba()));
+    let top_logprobs = array
+        .data
+        .iter()
+        .take(top_n)
+        .map(|data| TopLogprob {
+            token: vocab.as_string_lossy(data.id()),
+            logprob: to_logprob(data.proba()),
+            bytes: vocab.as_bytes(data.id()),
+        })
+        .collect();
+    TokenLogprobs {
+        token: vocab.as_string_lossy(token),
+        logprob,
+        bytes: vocab.as_bytes(token),
+        top_logprobs,
+    }
+}
+
+fn to_logprob(proba: f32) -> f32 {
+    if proba > 0.0 {
+        proba.ln().max(MIN_LOGPROB)
+    } else {
+        MIN_LOGPROB
+    }
+}
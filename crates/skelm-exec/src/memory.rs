@@ -0,0 +1,290 @@
+//! Estimation of the memory needed to run a model, checked before loading it
+//!
+//! The estimate is computed from the GGUF metadata only: the size of the weights, the
+//! KV cache for the context size and cache types, and the compute buffers. It is an
+//! approximation of what llama.cpp allocates, meant to refuse models that would get the
+//! process killed rather than to account for every byte.
+use skelm_gguf::{GgmlType, GgufFile};
+use skelm_llama_cpp as llama;
+use thiserror::Error;
+
+/// Number of tokens processed by a single compute graph evaluation
+const N_UBATCH: u64 = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    pub weights: u64,
+    pub kv_cache: u64,
+    pub compute: u64,
+    pub n_ctx: u32,
+}
+
+impl MemoryEstimate {
+    pub fn total(&self) -> u64 {
+        self.weights + self.kv_cache + self.compute
+    }
+}
+
+/// Available and total memory of the system, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct SystemMemory {
+    pub available: u64,
+    pub total: u64,
+}
+
+impl SystemMemory {
+    pub fn get() -> Self {
+        let mut system = sysinfo::System::new();
+        system.refresh_memory();
+        Self {
+            available: system.available_memory(),
+            total: system.total_memory(),
+        }
+    }
+}
+
+/// The model doesn't fit in the available memory
+#[derive(Clone, Debug, Error)]
+#[error(
+    "model needs about {} ({} weights, {} KV cache for a context of {} tokens, {} compute) but only {} of memory is available: {}",
+    size_units(.estimate.total()),
+    size_units(.estimate.weights),
+    size_units(.estimate.kv_cache),
+    .estimate.n_ctx,
+    size_units(.estimate.compute),
+    size_units(.memory.available),
+    self.suggestion()
+)]
+pub struct MemoryShortfall {
+    pub estimate: MemoryEstimate,
+    pub memory: SystemMemory,
+    /// the largest context that would fit, if any
+    pub max_n_ctx: Option<u32>,
+}
+
+impl MemoryShortfall {
+    fn suggestion(&self) -> String {
+        match self.max_n_ctx {
+            Some(n_ctx) => format!(
+                "use a context of at most {} tokens or a smaller quantization",
+                n_ctx
+            ),
+            None => "use a smaller model or quantization".to_string(),
+        }
+    }
+}
+
+fn size_units(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+}
+
+fn kv_ggml_type(ty: llama::KvCacheType) -> GgmlType {
+    match ty {
+        llama::KvCacheType::F32 => GgmlType::F32,
+        llama::KvCacheType::F16 => GgmlType::F16,
+        llama::KvCacheType::BF16 => GgmlType::BF16,
+        llama::KvCacheType::Q8_0 => GgmlType::Q8_0,
+        llama::KvCacheType::Q4_0 => GgmlType::Q4_0,
+        llama::KvCacheType::Q4_1 => GgmlType::Q4_1,
+        llama::KvCacheType::IQ4_NL => GgmlType::IQ4_NL,
+        llama::KvCacheType::Q5_0 => GgmlType::Q5_0,
+        llama::KvCacheType::Q5_1 => GgmlType::Q5_1,
+    }
+}
+
+/// Size of `n` elements, rounding up to whole blocks
+fn type_size(ty: GgmlType, n: u64) -> u64 {
+    let (block_elements, block_size) = ty.block_layout().unwrap_or((1, 4));
+    n.div_ceil(block_elements) * block_size
+}
+
+/// Values that can be given either once for all layers or as a per-layer array
+fn per_layer(gguf: &GgufFile, key: &str, n_layer: u64) -> Vec<u64> {
+    match gguf.get_arch(key) {
+        Some(v) => match v.as_array() {
+            Some(a) => a.iter().filter_map(|v| v.as_u64()).collect(),
+            None => vec![v.as_u64().unwrap_or(0); n_layer as usize],
+        },
+        None => Vec::new(),
+    }
+}
+
+/// Model dimensions driving the context dependent part of the estimate
+struct Dims {
+    /// bytes of KV cache per token of context
+    kv_per_token: u64,
+    n_head: u64,
+    n_embd: u64,
+    n_vocab: u64,
+}
+
+fn dims(gguf: &GgufFile, params: &llama::ContextParams) -> Dims {
+    let n_layer = gguf.get_arch_u64("block_count").unwrap_or(0);
+    let n_embd = gguf.get_arch_u64("embedding_length").unwrap_or(0);
+    let n_head = per_layer(gguf, "attention.head_count", n_layer);
+    let mut n_head_kv = per_layer(gguf, "attention.head_count_kv", n_layer);
+    if n_head_kv.is_empty() {
+        n_head_kv = n_head.clone();
+    }
+    let max_head = n_head.iter().copied().max().unwrap_or(0);
+    let default_head_dim = n_embd.checked_div(max_head).unwrap_or(0);
+    let key_length = gguf
+        .get_arch_u64("attention.key_length")
+        .unwrap_or(default_head_dim);
+    let value_length = gguf
+        .get_arch_u64("attention.value_length")
+        .unwrap_or(default_head_dim);
+
+    let type_k = kv_ggml_type(params.type_k);
+    let type_v = kv_ggml_type(params.type_v);
+    // recurrent models have no head count and no KV cache growing with the context
+    let kv_per_token = n_head_kv
+        .iter()
+        .map(|n| type_size(type_k, n * key_length) + type_size(type_v, n * value_length))
+        .sum();
+
+    let n_vocab = gguf
+        .get("tokenizer.ggml.tokens")
+        .and_then(|v| v.as_array())
+        .map(|a| a.len() as u64)
+        .or_else(|| gguf.get_arch_u64("vocab_size"))
+        .unwrap_or(0);
+
+    Dims {
+        kv_per_token,
+        n_head: max_head,
+        n_embd,
+        n_vocab,
+    }
+}
+
+impl Dims {
+    /// compute buffers: the logits and activations of a batch, and the attention scores
+    /// of one layer over the whole context
+    fn compute(&self, n_ctx: u64) -> u64 {
+        let n_ubatch = N_UBATCH.min(n_ctx);
+        n_ubatch * (self.n_vocab + 4 * self.n_embd) * 4 + self.n_head * n_ctx * n_ubatch * 4
+    }
+
+    fn per_token(&self) -> u64 {
+        self.kv_per_token + self.n_head * N_UBATCH * 4
+    }
+}
+
+/// The context size of `params`, 0 meaning the training context of the model as in
+/// llama.cpp
+fn context_size(gguf: &GgufFile, params: &llama::ContextParams) -> u64 {
+    match params.n_ctx {
+        0 => gguf.get_arch_u64("context_length").unwrap_or(0),
+        n_ctx => n_ctx as u64,
+    }
+}
+
+/// Estimate the memory needed to run a model with a context of `params`
+pub fn estimate_memory(gguf: &GgufFile, params: &llama::ContextParams) -> MemoryEstimate {
+    let dims = dims(gguf, params);
+    let n_ctx = context_size(gguf, params);
+    MemoryEstimate {
+        weights: gguf.tensors_size().unwrap_or(gguf.file_size),
+        kv_cache: dims.kv_per_token * n_ctx,
+        compute: dims.compute(n_ctx),
+        n_ctx: n_ctx.min(u32::MAX as u64) as u32,
+    }
+}
+
+/// Check that the estimate fits in the available memory
+pub fn check_memory_fit(
+    gguf: &GgufFile,
+    params: &llama::ContextParams,
+    memory: SystemMemory,
+) -> Result<MemoryEstimate, MemoryShortfall> {
+    /// contexts are suggested as multiple of this number of tokens
+    const N_CTX_STEP: u64 = 256;
+
+    let estimate = estimate_memory(gguf, params);
+    if estimate.total() <= memory.available {
+        return Ok(estimate);
+    }
+
+    let dims = dims(gguf, params);
+    let fixed = estimate.weights + dims.compute(0);
+    let max_n_ctx = memory
+        .available
+        .checked_sub(fixed)
+        .and_then(|budget| budget.checked_div(dims.per_token()))
+        .map(|n_ctx| n_ctx / N_CTX_STEP * N_CTX_STEP)
+        .filter(|n_ctx| *n_ctx >= N_UBATCH)
+        .map(|n_ctx| n_ctx.min(u32::MAX as u64) as u32);
+
+    Err(MemoryShortfall {
+        estimate,
+        memory,
+        max_n_ctx,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    /// The header of a 2 layers model with 4 heads of 16 dimensions and no tensors
+    fn sample_model() -> GgufFile {
+        let mut out = b"GGUF".to_vec();
+        out.extend_from_slice(&3u32.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&5u64.to_le_bytes());
+        push_string(&mut out, "general.architecture");
+        out.extend_from_slice(&8u32.to_le_bytes());
+        push_string(&mut out, "llama");
+        for (key, value) in [
+            ("llama.block_count", 2u32),
+            ("llama.embedding_length", 64),
+            ("llama.attention.head_count", 4),
+            ("llama.context_length", 4096),
+        ] {
+            push_string(&mut out, key);
+            out.extend_from_slice(&4u32.to_le_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        GgufFile::parse(&out).unwrap()
+    }
+
+    fn context_params(n_ctx: u32) -> llama::ContextParams {
+        llama::ContextParams {
+            n_ctx,
+            embeddings: false,
+            type_k: llama::KvCacheType::F16,
+            type_v: llama::KvCacheType::F16,
+            n_seq_max: 1,
+            kv_unified: false,
+        }
+    }
+
+    #[test]
+    fn estimate_and_fit() {
+        let gguf = sample_model();
+        // 2 layers of F16 keys and values of 4 heads of 16 dimensions
+        let kv_per_token = 2 * 2 * 4 * 16 * 2;
+        let estimate = estimate_memory(&gguf, &context_params(1024));
+        assert_eq!(estimate.kv_cache, kv_per_token * 1024);
+        assert_eq!(estimate.n_ctx, 1024);
+
+        // a context of 0 is the training context
+        let training = estimate_memory(&gguf, &context_params(0));
+        assert_eq!(training.kv_cache, kv_per_token * 4096);
+        assert_eq!(training.n_ctx, 4096);
+
+        let memory = SystemMemory {
+            available: estimate.total(),
+            total: estimate.total(),
+        };
+        assert!(check_memory_fit(&gguf, &context_params(1024), memory).is_ok());
+        let shortfall = check_memory_fit(&gguf, &context_params(0), memory).unwrap_err();
+        assert_eq!(shortfall.max_n_ctx, Some(1024));
+    }
+}
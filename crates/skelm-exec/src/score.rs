@@ -0,0 +1,196 @@
+//! Scoring of a text by a model: log-probability of each token, negative log-likelihood
+//! and perplexity
+//!
+//! Texts longer than the context are evaluated in sliding windows: each window starts
+//! from an empty context and only scores the tokens not scored by the previous ones, so
+//! that after the first window the tokens are predicted from at least `window - stride`
+//! previous tokens. When the windows don't overlap, their first token has nothing to be
+//! predicted from and is not scored.
+use skelm_llama_cpp as llama;
+use thiserror::Error;
+
+use crate::Context;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScoreParams {
+    /// tokens evaluated at once, at most the size of the context
+    pub window: usize,
+    /// tokens between the starts of two windows, at most `window`
+    pub stride: usize,
+}
+
+impl ScoreParams {
+    /// Windows of the whole context, overlapping by half
+    pub fn for_context(n_ctx: usize) -> Self {
+        Self {
+            window: n_ctx,
+            stride: std::cmp::max(n_ctx / 2, 1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TokenLogprob {
+    pub token: llama::Token,
+    /// natural log of the probability of the token given the previous ones
+    pub logprob: f32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Score {
+    /// the scored tokens, in the order of the text
+    pub tokens: Vec<TokenLogprob>,
+    /// total negative log-likelihood of the scored tokens
+    pub nll: f64,
+}
+
+impl Score {
+    pub fn mean_nll(&self) -> f64 {
+        if self.tokens.is_empty() {
+            return 0.0;
+        }
+        self.nll / self.tokens.len() as f64
+    }
+
+    pub fn perplexity(&self) -> f64 {
+        self.mean_nll().exp()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ScoreError {
+    #[error("at least 2 tokens are needed to score a text, got {0}")]
+    TooShort(usize),
+    #[error("invalid window of {window} tokens with a stride of {stride} for a context of {n_ctx}")]
+    InvalidWindow {
+        window: usize,
+        stride: usize,
+        n_ctx: usize,
+    },
+    #[error(transparent)]
+    Decode(#[from] llama::DecodeError),
+}
+
+/// A window of tokens evaluated from an empty context
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Window {
+    start: usize,
+    end: usize,
+    /// first token scored, the previous ones are only context
+    first_scored: usize,
+}
+
+fn windows(n_tokens: usize, window: usize, stride: usize) -> Vec<Window> {
+    let mut windows = Vec::new();
+    // the first token has no context to be predicted from
+    let mut scored = 1;
+    let mut start = 0;
+    while scored < n_tokens {
+        let end = std::cmp::min(start + window, n_tokens);
+        let first_scored = std::cmp::max(scored, start + 1);
+        if first_scored < end {
+            windows.push(Window {
+                start,
+                end,
+                first_scored,
+            });
+        }
+        scored = end;
+        start += stride;
+    }
+    windows
+}
+
+/// Log of the softmax of `logits` at `index`
+pub(crate) fn log_softmax(logits: &[f32], index: usize) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let sum = logits.iter().map(|l| (*l as f64 - max).exp()).sum::<f64>();
+    logits[index] as f64 - max - sum.ln()
+}
+
+impl Context {
+    /// Score a text, tokenized with the special tokens of the model (e.g. BOS)
+    pub fn score_text(
+        &mut self,
+        text: &str,
+        params: &ScoreParams,
+        progress: impl FnMut(&Score),
+    ) -> Result<Score, ScoreError> {
+        let tokens = self.0.vocab.tokenize(text.as_bytes(), true);
+        self.score_tokens(&tokens, params, progress)
+    }
+
+    /// Score tokens, calling `progress` with the partial score after each window
+    ///
+    /// The context is cleared before each window.
+    pub fn score_tokens(
+        &mut self,
+        tokens: &[llama::Token],
+        params: &ScoreParams,
+        mut progress: impl FnMut(&Score),
+    ) -> Result<Score, ScoreError> {
+        let n_ctx = self.1.n_ctx() as usize;
+        if params.window < 2
+            || params.window > n_ctx
+            || !(1..=params.window).contains(&params.stride)
+        {
+            return Err(ScoreError::InvalidWindow {
+                window: params.window,
+                stride: params.stride,
+                n_ctx,
+            });
+        }
+        if tokens.len() < 2 {
+            return Err(ScoreError::TooShort(tokens.len()));
+        }
+
+        let mut score = Score::default();
+        for window in windows(tokens.len(), params.window, params.stride) {
+            self.1.memory_clear(true);
+            let window_tokens = &tokens[window.start..window.end];
+            self.1.append_tokens_logits(window_tokens, |i, logits| {
+                // the logits of a token predict the next one
+                let next = window.start + i + 1;
+                if next < window.first_scored || next >= window.end {
+                    return;
+                }
+                let token = tokens[next];
+                let logprob = log_softmax(logits, token.as_index());
+                score.nll -= logprob;
+                score.tokens.push(TokenLogprob {
+                    token,
+                    logprob: logprob as f32,
+                });
+            })?;
+            progress(&score);
+        }
+        Ok(score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_windows() {
+        let w = |start, end, first_scored| Window {
+            start,
+            end,
+            first_scored,
+        };
+        assert_eq!(
+            windows(10, 4, 2),
+            [w(0, 4, 1), w(2, 6, 4), w(4, 8, 6), w(6, 10, 8)]
+        );
+        assert_eq!(windows(9, 4, 4), [w(0, 4, 1), w(4, 8, 5)]);
+        assert_eq!(windows(3, 8, 4), [w(0, 3, 1)]);
+        assert!(windows(1, 8, 4).is_empty());
+    }
+
+    #[test]
+    fn log_softmax_uniform() {
+        let logprob = log_softmax(&[1.0, 1.0, 1.0, 1.0], 2);
+        assert!((logprob - 0.25f64.ln()).abs() < 1e-9);
+    }
+}
@@ -1,4 +1,7 @@
 use minijinja::context;
+use skelm_ollama as ollama;
+
+use crate::chat::ChatMessage;
 
 fn raise_exception(err_text: String) -> Result<String, minijinja::Error> {
     Err(minijinja::Error::new(
@@ -19,7 +22,27 @@ fn strftime_now(s: String) -> Result<String, minijinja::Error> {
     }
 }
 
-pub fn chat_template(template: &str, system: &str, prompt: &str) -> Result<String, String> {
+/// Render a chat template with a system prompt and a user prompt
+///
+/// `enable_thinking` switches the reasoning of the models supporting it on or off, the
+/// template default is used when it's `None`.
+pub fn chat_template(
+    template: &str,
+    system: &str,
+    prompt: &str,
+    enable_thinking: Option<bool>,
+) -> Result<String, String> {
+    let messages = [ChatMessage::system(system), ChatMessage::user(prompt)];
+    chat_template_messages(template, &messages, &[], enable_thinking)
+}
+
+/// Render a chat template with a conversation and the tools the model can call
+pub fn chat_template_messages(
+    template: &str,
+    messages: &[ChatMessage],
+    tools: &[ollama::Tool],
+    enable_thinking: Option<bool>,
+) -> Result<String, String> {
     let mut env = minijinja::Environment::new();
     minijinja_contrib::add_to_environment(&mut env);
 
@@ -33,12 +56,21 @@ pub fn chat_template(template: &str, system: &str, prompt: &str) -> Result<Strin
     env.add_template(MAIN, template).unwrap();
 
     let tmpl = env.get_template(MAIN).unwrap();
-    let messages = vec![
-        context!(role => "system", content => system),
-        context!(role => "user", content => prompt),
-    ];
-    let tools: Vec<String> = Vec::new();
+    let messages = messages
+        .iter()
+        .map(|m| m.template_value())
+        .collect::<Vec<_>>();
+    let tools = tools.iter().map(|t| t.template_value()).collect::<Vec<_>>();
 
-    tmpl.render(context!(tools => tools, messages => messages))
+    let ctx = match enable_thinking {
+        Some(enable_thinking) => context!(
+            tools => tools,
+            messages => messages,
+            add_generation_prompt => true,
+            enable_thinking => enable_thinking
+        ),
+        None => context!(tools => tools, messages => messages, add_generation_prompt => true),
+    };
+    tmpl.render(ctx)
         .map_err(|e| format!("chat template error {}", e))
 }
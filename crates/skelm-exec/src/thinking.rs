@@ -0,0 +1,216 @@
+//! Separation of the reasoning ("thinking") of a model from its answer
+//!
+//! Reasoning models wrap their thinking between markers, `<think>` and `</think>` for
+//! most of them. The markers can be split across tokens so the generated text is
+//! buffered until a marker can be ruled out.
+
+/// The markers around a reasoning block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThinkingMarkers {
+    pub start: &'static str,
+    pub end: &'static str,
+}
+
+impl ThinkingMarkers {
+    pub const THINK: Self = Self {
+        start: "<think>",
+        end: "</think>",
+    };
+
+    /// Markers of the model families not using `<think>`
+    const MODEL_SPECIFIC: &[Self] = &[
+        // magistral
+        Self {
+            start: "[THINK]",
+            end: "[/THINK]",
+        },
+        // command-r7b
+        Self {
+            start: "<|START_THINKING|>",
+            end: "<|END_THINKING|>",
+        },
+        // kimi
+        Self {
+            start: "◁think▷",
+            end: "◁/think▷",
+        },
+        Self::GPT_OSS,
+    ];
+
+    /// gpt-oss, the start marker is made of several tokens
+    const GPT_OSS: Self = Self {
+        start: "<|channel|>analysis<|message|>",
+        end: "<|end|>",
+    };
+
+    /// The markers mentioned by a chat template, `<think>` by default
+    pub fn detect(chat_template: &str) -> Self {
+        Self::MODEL_SPECIFIC
+            .iter()
+            .find(|markers| chat_template.contains(markers.start))
+            .copied()
+            .unwrap_or(Self::THINK)
+    }
+
+    /// The control tokens starting the message headers to drop from the text and the one
+    /// ending them, for gpt-oss' `<|start|>assistant<|channel|>final<|message|>`
+    pub fn headers(&self) -> Option<(&'static [&'static str], &'static str)> {
+        (*self == Self::GPT_OSS).then_some((&["<|start|>", "<|channel|>"], "<|message|>"))
+    }
+}
+
+/// A piece of generated text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TextChunk {
+    Reasoning(String),
+    Content(String),
+}
+
+/// Split a stream of generated text into reasoning and content
+pub struct ThinkingParser {
+    markers: ThinkingMarkers,
+    buffer: String,
+    in_reasoning: bool,
+    /// skip the whitespace separating the end of the reasoning from the answer
+    trim_content: bool,
+}
+
+impl ThinkingParser {
+    pub fn new(markers: ThinkingMarkers) -> Self {
+        Self {
+            markers,
+            buffer: String::new(),
+            in_reasoning: false,
+            trim_content: false,
+        }
+    }
+
+    /// For a prompt already opening the reasoning block, as some templates do to force
+    /// the model to think
+    pub fn for_prompt(markers: ThinkingMarkers, prompt: &str) -> Self {
+        let mut parser = Self::new(markers);
+        parser.in_reasoning = prompt.trim_end().ends_with(markers.start);
+        parser
+    }
+
+    pub fn in_reasoning(&self) -> bool {
+        self.in_reasoning
+    }
+
+    /// Add generated text, returning the chunks that are complete
+    pub fn push(&mut self, text: &str) -> Vec<TextChunk> {
+        self.buffer.push_str(text);
+        let mut chunks = Vec::new();
+        loop {
+            let marker = self.current_marker();
+            match self.buffer.find(marker) {
+                Some(pos) => {
+                    let before = self.buffer[..pos].to_string();
+                    self.emit(&mut chunks, before);
+                    self.buffer.drain(..pos + marker.len());
+                    self.in_reasoning = !self.in_reasoning;
+                    self.trim_content = !self.in_reasoning;
+                }
+                None => {
+                    // keep the end of the buffer that could be the start of the marker
+                    let keep = (1..marker.len().min(self.buffer.len() + 1))
+                        .rev()
+                        .find(|n| {
+                            self.buffer.is_char_boundary(self.buffer.len() - n)
+                                && marker.starts_with(&self.buffer[self.buffer.len() - n..])
+                        })
+                        .unwrap_or(0);
+                    let ready = self.buffer[..self.buffer.len() - keep].to_string();
+                    self.buffer.drain(..self.buffer.len() - keep);
+                    self.emit(&mut chunks, ready);
+                    break;
+                }
+            }
+        }
+        chunks
+    }
+
+    /// Flush the text kept at the end of the generation
+    pub fn finish(&mut self) -> Vec<TextChunk> {
+        let mut chunks = Vec::new();
+        let rest = std::mem::take(&mut self.buffer);
+        self.emit(&mut chunks, rest);
+        chunks
+    }
+
+    fn current_marker(&self) -> &'static str {
+        if self.in_reasoning {
+            self.markers.end
+        } else {
+            self.markers.start
+        }
+    }
+
+    fn emit(&mut self, chunks: &mut Vec<TextChunk>, text: String) {
+        if self.in_reasoning {
+            if !text.is_empty() {
+                chunks.push(TextChunk::Reasoning(text))
+            }
+            return;
+        }
+        let text = if self.trim_content {
+            let trimmed = text.trim_start();
+            if !trimmed.is_empty() {
+                self.trim_content = false;
+            }
+            trimmed.to_string()
+        } else {
+            text
+        };
+        if !text.is_empty() {
+            chunks.push(TextChunk::Content(text))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(chunks: Vec<TextChunk>) -> (String, String) {
+        let mut reasoning = String::new();
+        let mut content = String::new();
+        for chunk in chunks {
+            match chunk {
+                TextChunk::Reasoning(s) => reasoning.push_str(&s),
+                TextChunk::Content(s) => content.push_str(&s),
+            }
+        }
+        (reasoning, content)
+    }
+
+    #[test]
+    fn split_markers_across_pushes() {
+        let mut parser = ThinkingParser::new(ThinkingMarkers::THINK);
+        let mut chunks = Vec::new();
+        for piece in [
+            "<th",
+            "ink>let me",
+            " see</",
+            "think>",
+            "\n\nThe answer",
+            " is <4",
+        ] {
+            chunks.extend(parser.push(piece));
+        }
+        chunks.extend(parser.finish());
+        assert_eq!(
+            collect(chunks),
+            ("let me see".to_string(), "The answer is <4".to_string())
+        );
+    }
+
+    #[test]
+    fn prompt_opening_reasoning() {
+        let mut parser =
+            ThinkingParser::for_prompt(ThinkingMarkers::THINK, "<|im_start|>assistant\n<think>\n");
+        let mut chunks = parser.push("hmm</think>ok");
+        chunks.extend(parser.finish());
+        assert_eq!(collect(chunks), ("hmm".to_string(), "ok".to_string()));
+    }
+}
@@ -0,0 +1,228 @@
+//! Parsing of the tool calls generated by models
+//!
+//! Each model family has its own way of writing tool calls in the generated text; the
+//! format is detected from the markers mentioned by the chat template.
+use serde::{Deserialize, Serialize};
+
+/// A call to a tool requested by the model
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// The call in the format expected by chat templates
+    pub fn template_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "arguments": self.arguments,
+            }
+        })
+    }
+
+    /// A call from a `{"name", "arguments"}` object, `parameters` being used by Llama 3
+    /// and the arguments being possibly encoded as a JSON string
+    fn from_value(value: &serde_json::Value) -> Option<Self> {
+        let name = value.get("name")?.as_str()?.to_string();
+        let arguments = value
+            .get("arguments")
+            .or_else(|| value.get("parameters"))
+            .cloned()
+            .unwrap_or_else(|| serde_json::json!({}));
+        let arguments = match arguments {
+            serde_json::Value::String(s) => serde_json::from_str(&s).ok()?,
+            arguments => arguments,
+        };
+        Some(Self { name, arguments })
+    }
+}
+
+/// The way a model family writes tool calls
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToolCallFormat {
+    /// `<tool_call>{"name": ..., "arguments": ...}</tool_call>`, used by Qwen and Hermes
+    Hermes,
+    /// `{"name": ..., "parameters": ...}`, optionally after `<|python_tag|>`
+    Llama3,
+    /// `[TOOL_CALLS][{"name": ..., "arguments": ...}]` or `[TOOL_CALLS]name[ARGS]{...}`
+    Mistral,
+}
+
+const HERMES_START: &str = "<tool_call>";
+const HERMES_END: &str = "</tool_call>";
+const LLAMA3_PYTHON_TAG: &str = "<|python_tag|>";
+const MISTRAL_TOOL_CALLS: &str = "[TOOL_CALLS]";
+const MISTRAL_ARGS: &str = "[ARGS]";
+
+impl ToolCallFormat {
+    /// The format of the tool calls of a model, from its chat template
+    pub fn detect(chat_template: &str) -> Option<Self> {
+        if chat_template.contains(HERMES_START) {
+            Some(Self::Hermes)
+        } else if chat_template.contains(MISTRAL_TOOL_CALLS) {
+            Some(Self::Mistral)
+        } else if chat_template.contains(LLAMA3_PYTHON_TAG) || chat_template.contains("<|eom_id|>")
+        {
+            Some(Self::Llama3)
+        } else {
+            None
+        }
+    }
+
+    /// The text starting a tool call
+    pub fn trigger(self) -> &'static str {
+        match self {
+            Self::Hermes => HERMES_START,
+            Self::Llama3 => "{\"name\"",
+            Self::Mistral => MISTRAL_TOOL_CALLS,
+        }
+    }
+
+    /// The control tokens written in tool calls, which the decoder must keep for the
+    /// calls to be parsed
+    pub fn control_texts(self) -> &'static [&'static str] {
+        match self {
+            Self::Hermes => &[HERMES_START, HERMES_END],
+            Self::Llama3 => &[LLAMA3_PYTHON_TAG],
+            Self::Mistral => &[MISTRAL_TOOL_CALLS, MISTRAL_ARGS],
+        }
+    }
+
+    /// Split generated text into its content and its tool calls
+    ///
+    /// Text looking like a tool call but failing to parse is kept in the content.
+    pub fn parse(self, text: &str) -> (String, Vec<ToolCall>) {
+        match self {
+            Self::Hermes => parse_hermes(text),
+            Self::Llama3 => parse_llama3(text),
+            Self::Mistral => parse_mistral(text),
+        }
+    }
+}
+
+/// Parse the JSON value at the start of `s`, returning it and the length of its text
+fn parse_json_prefix(s: &str) -> Option<(serde_json::Value, usize)> {
+    let mut stream = serde_json::Deserializer::from_str(s).into_iter::<serde_json::Value>();
+    let value = stream.next()?.ok()?;
+    Some((value, stream.byte_offset()))
+}
+
+fn parse_hermes(text: &str) -> (String, Vec<ToolCall>) {
+    let mut content = String::new();
+    let mut calls = Vec::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find(HERMES_START) {
+        content.push_str(&rest[..pos]);
+        let after = &rest[pos + HERMES_START.len()..];
+        let trimmed = after.trim_start();
+        let call = parse_json_prefix(trimmed)
+            .and_then(|(value, len)| ToolCall::from_value(&value).map(|call| (call, len)));
+        let Some((call, len)) = call else {
+            content.push_str(&rest[pos..]);
+            rest = "";
+            break;
+        };
+        calls.push(call);
+        let after_call = trimmed[len..].trim_start();
+        rest = after_call.strip_prefix(HERMES_END).unwrap_or(after_call);
+    }
+    content.push_str(rest);
+    (content.trim().to_string(), calls)
+}
+
+fn parse_llama3(text: &str) -> (String, Vec<ToolCall>) {
+    let body = text.trim();
+    let body = body.strip_prefix(LLAMA3_PYTHON_TAG).unwrap_or(body).trim();
+    if !body.starts_with('{') {
+        return (text.trim().to_string(), Vec::new());
+    }
+    // multiple calls are separated by `;`
+    let mut calls = Vec::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        let Some(call) = parse_json_prefix(rest)
+            .and_then(|(value, len)| ToolCall::from_value(&value).map(|call| (call, len)))
+        else {
+            return (text.trim().to_string(), Vec::new());
+        };
+        calls.push(call.0);
+        rest = rest[call.1..].trim_start();
+        rest = rest.strip_prefix(';').unwrap_or(rest).trim_start();
+    }
+    (String::new(), calls)
+}
+
+fn parse_mistral(text: &str) -> (String, Vec<ToolCall>) {
+    let Some(pos) = text.find(MISTRAL_TOOL_CALLS) else {
+        return (text.trim().to_string(), Vec::new());
+    };
+    let content = text[..pos].trim().to_string();
+    let mut calls = Vec::new();
+    let mut rest = &text[pos..];
+    while let Some(after) = rest.strip_prefix(MISTRAL_TOOL_CALLS) {
+        let after = after.trim_start();
+        let parsed = if after.starts_with('[') {
+            // [{"name": ..., "arguments": ...}, ...]
+            parse_json_prefix(after).and_then(|(value, len)| {
+                let list = value
+                    .as_array()?
+                    .iter()
+                    .map(ToolCall::from_value)
+                    .collect::<Option<Vec<_>>>()?;
+                Some((list, len))
+            })
+        } else {
+            // name[ARGS]{...}
+            after.split_once(MISTRAL_ARGS).and_then(|(name, args)| {
+                let (arguments, len) = parse_json_prefix(args)?;
+                let call = ToolCall {
+                    name: name.trim().to_string(),
+                    arguments,
+                };
+                Some((vec![call], after.len() - args.len() + len))
+            })
+        };
+        let Some((list, len)) = parsed else {
+            return (text.trim().to_string(), Vec::new());
+        };
+        calls.extend(list);
+        rest = after[len..].trim_start();
+    }
+    (content, calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_formats() {
+        let (content, calls) = ToolCallFormat::Hermes.parse(
+            "Let me check.\n<tool_call>\n{\"name\": \"weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+        );
+        assert_eq!(content, "Let me check.");
+        assert_eq!(calls[0].name, "weather");
+        assert_eq!(calls[0].arguments["city"], "Paris");
+
+        let (content, calls) = ToolCallFormat::Llama3.parse(
+            "<|python_tag|>{\"name\": \"a\", \"parameters\": {\"x\": 1}}; {\"name\": \"b\", \"parameters\": \"{\\\"y\\\": 2}\"}",
+        );
+        assert!(content.is_empty());
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].arguments["y"], 2);
+
+        let (_, calls) =
+            ToolCallFormat::Mistral.parse("[TOOL_CALLS][{\"name\": \"a\", \"arguments\": {}}]");
+        assert_eq!(calls[0].name, "a");
+        let (_, calls) = ToolCallFormat::Mistral.parse("[TOOL_CALLS]b[ARGS]{\"z\": true}");
+        assert_eq!(calls[0].name, "b");
+        assert_eq!(calls[0].arguments["z"], true);
+
+        let (content, calls) = ToolCallFormat::Hermes.parse("<tool_call>not json");
+        assert_eq!(content, "<tool_call>not json");
+        assert!(calls.is_empty());
+    }
+}
@@ -0,0 +1,9 @@
+//! Reader of GGUF model files
+//!
+//! Parse the header, metadata and tensor infos of a GGUF file without loading it with
+//! llama.cpp, to inspect, validate and size models.
+mod reader;
+mod types;
+
+pub use reader::*;
+pub use types::*;
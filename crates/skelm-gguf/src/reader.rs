@@ -0,0 +1,437 @@
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::types::*;
+
+pub const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+pub const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+pub const KEY_ALIGNMENT: &str = "general.alignment";
+pub const KEY_ARCHITECTURE: &str = "general.architecture";
+
+#[derive(Debug, Error)]
+pub enum GgufError {
+    #[error("I/O error {0}")]
+    IO(#[from] std::io::Error),
+    #[error("not a GGUF file, magic is {0:02x?}")]
+    InvalidMagic([u8; 4]),
+    #[error("unsupported GGUF version {0}")]
+    UnsupportedVersion(u32),
+    #[error("file truncated at offset {0}")]
+    UnexpectedEof(u64),
+    #[error("invalid UTF-8 string at offset {0}")]
+    InvalidString(u64),
+    #[error("invalid metadata value type {1} at offset {0}")]
+    InvalidValueType(u64, u32),
+    #[error("invalid alignment {0}")]
+    InvalidAlignment(u64),
+    #[error("tensor {0} has an invalid shape {1:?}")]
+    InvalidShape(String, Vec<u64>),
+    #[error("tensor {0} has unsupported type {1}")]
+    UnsupportedTensorType(String, GgmlType),
+    #[error("tensor {0} is not aligned, offset {1}")]
+    UnalignedTensor(String, u64),
+    #[error("tensor {0} data at {1}..{2} is beyond the end of the file ({3} bytes)")]
+    TensorOutOfBounds(String, u64, u64, u64),
+    #[error("tensors {0} and {1} overlap")]
+    OverlappingTensors(String, String),
+}
+
+/// The header, metadata and tensor infos of a GGUF file
+///
+/// The tensor data is not read, only located.
+#[derive(Clone, Debug)]
+pub struct GgufFile {
+    pub version: u32,
+    /// metadata key-value pairs, in file order
+    pub metadata: Vec<(String, MetadataValue)>,
+    pub tensors: Vec<TensorInfo>,
+    pub alignment: u64,
+    /// absolute offset of the data section in the file
+    pub data_offset: u64,
+    pub file_size: u64,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn offset(&self) -> u64 {
+        self.pos as u64
+    }
+
+    fn take(&mut self, n: u64) -> Result<&'a [u8], GgufError> {
+        let end = usize::try_from(n)
+            .ok()
+            .and_then(|n| self.pos.checked_add(n))
+            .filter(|end| *end <= self.data.len())
+            .ok_or(GgufError::UnexpectedEof(self.offset()))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], GgufError> {
+        Ok(self.take(N as u64)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, GgufError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, GgufError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, GgufError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, GgufError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String, GgufError> {
+        let start = self.offset();
+        let len = self.u64()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| GgufError::InvalidString(start))
+    }
+
+    fn value(&mut self, ty: u32) -> Result<MetadataValue, GgufError> {
+        let value = match ty {
+            0 => MetadataValue::U8(self.u8()?),
+            1 => MetadataValue::I8(self.u8()? as i8),
+            2 => MetadataValue::U16(self.u16()?),
+            3 => MetadataValue::I16(self.u16()? as i16),
+            4 => MetadataValue::U32(self.u32()?),
+            5 => MetadataValue::I32(self.u32()? as i32),
+            6 => MetadataValue::F32(f32::from_bits(self.u32()?)),
+            7 => MetadataValue::Bool(self.u8()? != 0),
+            8 => MetadataValue::String(self.string()?),
+            9 => {
+                let start = self.offset();
+                let elem_ty = self.u32()?;
+                if elem_ty == 9 {
+                    // nested arrays are not produced by any known writer
+                    return Err(GgufError::InvalidValueType(start, elem_ty));
+                }
+                let len = self.u64()?;
+                // reject lengths the remaining data can't hold before allocating for them
+                let remaining = (self.data.len() - self.pos) as u64;
+                if len > remaining / min_value_size(elem_ty) {
+                    return Err(GgufError::UnexpectedEof(self.offset()));
+                }
+                let mut values = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    values.push(self.value(elem_ty)?);
+                }
+                MetadataValue::Array(values)
+            }
+            10 => MetadataValue::U64(self.u64()?),
+            11 => MetadataValue::I64(self.u64()? as i64),
+            12 => MetadataValue::F64(f64::from_bits(self.u64()?)),
+            ty => return Err(GgufError::InvalidValueType(self.offset(), ty)),
+        };
+        Ok(value)
+    }
+}
+
+/// The smallest encoded size of a metadata value of type `ty`
+fn min_value_size(ty: u32) -> u64 {
+    match ty {
+        0 | 1 | 7 => 1,
+        2 | 3 => 2,
+        4..=6 => 4,
+        // strings start with their 64 bits length
+        8 | 10..=12 => 8,
+        _ => 1,
+    }
+}
+
+impl GgufFile {
+    /// Parse the header of a GGUF file, mapping it in memory
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, GgufError> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the mapping is only read during parsing, and nothing borrowed from it
+        // outlives this function. A concurrent modification of the file could still make
+        // the parse fail or return garbage, but not produce dangling data.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::parse(&map)
+    }
+
+    /// Parse the header of a GGUF file from its content
+    ///
+    /// `data` only needs to contain the header, but the tensor bounds checks of
+    /// [`GgufFile::validate`] use its length as the file size.
+    pub fn parse(data: &[u8]) -> Result<Self, GgufError> {
+        let mut cursor = Cursor { data, pos: 0 };
+
+        let magic = cursor.array::<4>()?;
+        if magic != GGUF_MAGIC {
+            return Err(GgufError::InvalidMagic(magic));
+        }
+        let version = cursor.u32()?;
+        // version 1 used 32 bits lengths, and byte swapped versions are big endian files
+        if !(2..=3).contains(&version) {
+            return Err(GgufError::UnsupportedVersion(version));
+        }
+
+        let n_tensors = cursor.u64()?;
+        let n_kv = cursor.u64()?;
+
+        let mut metadata = Vec::new();
+        for _ in 0..n_kv {
+            let key = cursor.string()?;
+            let ty = cursor.u32()?;
+            let value = cursor.value(ty)?;
+            metadata.push((key, value));
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..n_tensors {
+            let name = cursor.string()?;
+            let n_dims = cursor.u32()?;
+            let mut dims = Vec::new();
+            for _ in 0..n_dims {
+                dims.push(cursor.u64()?);
+            }
+            let ty = GgmlType::from_u32(cursor.u32()?);
+            let offset = cursor.u64()?;
+            if dims.is_empty()
+                || dims
+                    .iter()
+                    .try_fold(1u64, |acc, d| acc.checked_mul(*d))
+                    .is_none()
+            {
+                return Err(GgufError::InvalidShape(name, dims));
+            }
+            tensors.push(TensorInfo {
+                name,
+                dims,
+                ty,
+                offset,
+            });
+        }
+
+        let alignment = metadata
+            .iter()
+            .find(|(k, _)| k == KEY_ALIGNMENT)
+            .and_then(|(_, v)| v.as_u64())
+            .unwrap_or(GGUF_DEFAULT_ALIGNMENT);
+        if alignment == 0 || !alignment.is_power_of_two() {
+            return Err(GgufError::InvalidAlignment(alignment));
+        }
+        let data_offset = cursor.offset().next_multiple_of(alignment);
+
+        Ok(Self {
+            version,
+            metadata,
+            tensors,
+            alignment,
+            data_offset,
+            file_size: data.len() as u64,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(|v| v.as_str())
+    }
+
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(|v| v.as_u64())
+    }
+
+    /// The model architecture, e.g. `llama`
+    pub fn architecture(&self) -> Option<&str> {
+        self.get_str(KEY_ARCHITECTURE)
+    }
+
+    /// A metadata value of the architecture namespace, e.g. `block_count` for
+    /// `llama.block_count`
+    pub fn get_arch(&self, key: &str) -> Option<&MetadataValue> {
+        let arch = self.architecture()?;
+        self.get(&format!("{}.{}", arch, key))
+    }
+
+    pub fn get_arch_u64(&self, key: &str) -> Option<u64> {
+        self.get_arch(key).and_then(|v| v.as_u64())
+    }
+
+    /// Total number of elements of all the tensors
+    pub fn parameter_count(&self) -> u64 {
+        self.tensors.iter().map(|t| t.n_elements()).sum()
+    }
+
+    /// Total size of the tensor data, `None` if a tensor has an unknown type
+    pub fn tensors_size(&self) -> Option<u64> {
+        self.tensors.iter().map(|t| t.size()).sum()
+    }
+
+    /// Check that every tensor has a known type, is aligned, is entirely contained in
+    /// the file and doesn't overlap another tensor
+    pub fn validate(&self) -> Result<(), GgufError> {
+        let mut ranges = Vec::new();
+        for tensor in self.tensors.iter() {
+            let Some(size) = tensor.size() else {
+                return Err(GgufError::UnsupportedTensorType(
+                    tensor.name.clone(),
+                    tensor.ty,
+                ));
+            };
+            if tensor.offset % self.alignment != 0 {
+                return Err(GgufError::UnalignedTensor(
+                    tensor.name.clone(),
+                    tensor.offset,
+                ));
+            }
+            let start = self.data_offset.checked_add(tensor.offset);
+            let end = start.and_then(|start| start.checked_add(size));
+            match (start, end) {
+                (Some(start), Some(end)) if end <= self.file_size => {
+                    ranges.push((start, end, &tensor.name))
+                }
+                _ => {
+                    return Err(GgufError::TensorOutOfBounds(
+                        tensor.name.clone(),
+                        start.unwrap_or(u64::MAX),
+                        end.unwrap_or(u64::MAX),
+                        self.file_size,
+                    ));
+                }
+            }
+        }
+
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            let (_, end, name1) = pair[0];
+            let (start, _, name2) = pair[1];
+            if start < end {
+                return Err(GgufError::OverlappingTensors(
+                    name1.to_string(),
+                    name2.to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    /// A GGUF file with an architecture, a token array and two F32 tensors of 4 elements
+    fn sample_file(second_offset: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&GGUF_MAGIC);
+        out.extend_from_slice(&3u32.to_le_bytes());
+        out.extend_from_slice(&2u64.to_le_bytes());
+        out.extend_from_slice(&3u64.to_le_bytes());
+
+        push_string(&mut out, KEY_ARCHITECTURE);
+        out.extend_from_slice(&8u32.to_le_bytes());
+        push_string(&mut out, "llama");
+
+        push_string(&mut out, "llama.block_count");
+        out.extend_from_slice(&4u32.to_le_bytes());
+        out.extend_from_slice(&2u32.to_le_bytes());
+
+        push_string(&mut out, "tokenizer.ggml.tokens");
+        out.extend_from_slice(&9u32.to_le_bytes());
+        out.extend_from_slice(&8u32.to_le_bytes());
+        out.extend_from_slice(&2u64.to_le_bytes());
+        push_string(&mut out, "<s>");
+        push_string(&mut out, "</s>");
+
+        for (name, offset) in [("a.weight", 0u64), ("b.weight", second_offset)] {
+            push_string(&mut out, name);
+            out.extend_from_slice(&2u32.to_le_bytes());
+            out.extend_from_slice(&2u64.to_le_bytes());
+            out.extend_from_slice(&2u64.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+
+        out.resize(out.len().next_multiple_of(32) + 64, 0);
+        out
+    }
+
+    #[test]
+    fn parse_header() {
+        let gguf = GgufFile::parse(&sample_file(32)).unwrap();
+        assert_eq!(gguf.version, 3);
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert_eq!(gguf.get_arch_u64("block_count"), Some(2));
+        assert_eq!(
+            gguf.get("tokenizer.ggml.tokens")
+                .and_then(|v| v.as_array())
+                .map(|a| a.len()),
+            Some(2)
+        );
+        assert_eq!(gguf.tensors.len(), 2);
+        assert_eq!(gguf.tensors[1].dims, vec![2, 2]);
+        assert_eq!(gguf.tensors[1].ty, GgmlType::F32);
+        assert_eq!(gguf.parameter_count(), 8);
+        assert_eq!(gguf.tensors_size(), Some(32));
+        assert_eq!(gguf.data_offset % 32, 0);
+        gguf.validate().unwrap();
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let mut data = sample_file(32);
+        data[0] = b'X';
+        assert!(matches!(
+            GgufFile::parse(&data),
+            Err(GgufError::InvalidMagic(_))
+        ));
+
+        let data = sample_file(32);
+        assert!(matches!(
+            GgufFile::parse(&data[..40]),
+            Err(GgufError::UnexpectedEof(_))
+        ));
+
+        // an array of more u32 elements than the 8 bytes left in the file
+        let mut data = GGUF_MAGIC.to_vec();
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&1u64.to_le_bytes());
+        push_string(&mut data, "oversized");
+        data.extend_from_slice(&9u32.to_le_bytes());
+        data.extend_from_slice(&4u32.to_le_bytes());
+        for len in [3u64, u64::MAX] {
+            let mut data = data.clone();
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(&[0; 8]);
+            assert!(matches!(
+                GgufFile::parse(&data),
+                Err(GgufError::UnexpectedEof(_))
+            ));
+        }
+
+        let gguf = GgufFile::parse(&sample_file(64)).unwrap();
+        assert!(matches!(
+            gguf.validate(),
+            Err(GgufError::TensorOutOfBounds(..))
+        ));
+
+        let gguf = GgufFile::parse(&sample_file(0)).unwrap();
+        assert!(matches!(
+            gguf.validate(),
+            Err(GgufError::OverlappingTensors(..))
+        ));
+    }
+}
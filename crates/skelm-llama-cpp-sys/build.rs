@@ -58,13 +58,17 @@ fn main() {
 
     bindings(&lib_path, &out_path);
     let ggml_objects = lib_ggml(&lib_path, &out_path);
+    // mtmd uses llama and ggml, so it has to come first on the link line
+    lib_mtmd(&lib_path);
     lib_llama(&lib_path, ggml_objects);
+    lib_shim();
 }
 
 fn bindings(lib_path: &Path, out_path: &Path) {
     let ggml_path = lib_path.join("ggml");
     let ggml_include_path = ggml_path.join("include");
     let llama_include_path = lib_path.join("include");
+    let mtmd_path = lib_path.join("tools").join("mtmd");
 
     let ggml_bindings = Builder::default()
         .header(ggml_include_path.join("ggml.h").to_string_lossy())
@@ -91,12 +95,33 @@ fn bindings(lib_path: &Path, out_path: &Path) {
         .generate()
         .expect("Unable to generate bindings");
 
+    // the llama and ggml types used by mtmd come from the llama bindings
+    let mtmd_bindings = Builder::default()
+        .header(mtmd_path.join("mtmd.h").to_string_lossy())
+        .header(mtmd_path.join("mtmd-helper.h").to_string_lossy())
+        .allowlist_function("mtmd_.*")
+        .allowlist_type("mtmd_.*")
+        .blocklist_type("llama_.*")
+        .blocklist_type("ggml_.*")
+        .clang_arg(format!("-I{}", ggml_include_path.display()))
+        .clang_arg(format!("-I{}", llama_include_path.display()))
+        .derive_debug(true)
+        .derive_copy(false)
+        .default_enum_style(bindgen::EnumVariation::Rust {
+            non_exhaustive: true,
+        })
+        .generate()
+        .expect("Unable to generate bindings");
+
     ggml_bindings
         .write_to_file(out_path.join("bindings_ggml.rs"))
         .expect("cannot write ggml bindings");
     llama_bindings
         .write_to_file(out_path.join("bindings_llama.rs"))
-        .expect("cannot write llama bindings")
+        .expect("cannot write llama bindings");
+    mtmd_bindings
+        .write_to_file(out_path.join("bindings_mtmd.rs"))
+        .expect("cannot write mtmd bindings")
 }
 
 fn lib_ggml(lib_path: &Path, out_path: &Path) -> Vec<PathBuf> {
@@ -282,6 +307,46 @@ _ggml_metallib_end:
     all
 }
 
+/// The multimodal library (clip and the image/audio projectors), built from
+/// `tools/mtmd` of llama.cpp
+fn lib_mtmd(lib_path: &Path) {
+    let ggml_include_path = lib_path.join("ggml").join("include");
+    let include_path = lib_path.join("include");
+    let mtmd_path = lib_path.join("tools").join("mtmd");
+    // stb_image and miniaudio
+    let vendor_path = lib_path.join("vendor");
+
+    let cpp_files = ["mtmd.cpp", "mtmd-audio.cpp", "mtmd-helper.cpp", "clip.cpp"];
+
+    let mut cpp = Build::new();
+    cpp.include(include_path);
+    cpp.include(ggml_include_path);
+    cpp.include(&mtmd_path);
+    cpp.include(vendor_path);
+    cpp.opt_level(3);
+    cpp.define("GGML_SHARED", None);
+    cpp.define("LLAMA_SHARED", None);
+    cpp.define("MTMD_SHARED", None);
+    cpp.define("MTMD_BUILD", None);
+    // the vendored headers are not warning free
+    cpp.warnings(false);
+
+    cpp.cpp(true).std("c++17");
+    cpp.files(cpp_files.into_iter().map(|f| mtmd_path.join(f)));
+
+    cpp.compile("mtmd");
+}
+
+/// The C wrappers of the C++ parts of the llama.cpp API, in `src/`
+fn lib_shim() {
+    let mut cpp = Build::new();
+    cpp.opt_level(3);
+    cpp.cpp(true).std("c++17");
+    cpp.file("src/imatrix.cpp");
+
+    cpp.compile("skelm_shim");
+}
+
 fn lib_llama(lib_path: &Path, ggml_objects: Vec<PathBuf>) {
     let ggml_path = lib_path.join("ggml");
     let ggml_include_path = ggml_path.join("include");
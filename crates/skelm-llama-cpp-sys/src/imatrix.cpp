@@ -0,0 +1,24 @@
+// The importance matrix given to llama_model_quantize is a C++ map from tensor names
+// to their weights, which can't be built from Rust directly.
+#include <cstddef>
+#include <string>
+#include <unordered_map>
+#include <vector>
+
+using imatrix = std::unordered_map<std::string, std::vector<float>>;
+
+extern "C" {
+
+void * skelm_imatrix_new(void) {
+    return new imatrix();
+}
+
+void skelm_imatrix_add(void * map, const char * name, const float * values, size_t n_values) {
+    (*static_cast<imatrix *>(map))[name] = std::vector<float>(values, values + n_values);
+}
+
+void skelm_imatrix_free(void * map) {
+    delete static_cast<imatrix *>(map);
+}
+
+}
@@ -11,3 +11,24 @@ pub mod ggml {
 pub mod llama {
     include!(concat!(env!("OUT_DIR"), "/bindings_llama.rs"));
 }
+pub mod mtmd {
+    use super::llama::*;
+    include!(concat!(env!("OUT_DIR"), "/bindings_mtmd.rs"));
+}
+
+/// C wrappers of `src/imatrix.cpp`, building the `std::unordered_map` expected by
+/// `llama_model_quantize_params::imatrix`
+pub mod shim {
+    use std::os::raw::{c_char, c_void};
+
+    unsafe extern "C" {
+        pub fn skelm_imatrix_new() -> *mut c_void;
+        pub fn skelm_imatrix_add(
+            map: *mut c_void,
+            name: *const c_char,
+            values: *const f32,
+            n_values: usize,
+        );
+        pub fn skelm_imatrix_free(map: *mut c_void);
+    }
+}
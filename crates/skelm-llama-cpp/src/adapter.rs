@@ -0,0 +1,214 @@
+//! LoRA adapters and control vectors applied on top of the weights of a model
+use skelm_llama_cpp_sys::llama;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::log::{FailureKind, capture_errors, fmt_log};
+use crate::model::path_to_cpath;
+use crate::{Context, Model};
+
+/// A LoRA adapter loaded for a model, which can be set on any context of that model
+#[derive(Clone)]
+pub struct LoraAdapter {
+    pub(crate) ptr: Arc<LoraAdapterPtr>,
+    path: PathBuf,
+}
+
+pub(crate) struct LoraAdapterPtr {
+    ptr: *mut llama::llama_adapter_lora,
+    // the adapter refers to the model, which has to outlive it
+    _model: Model,
+}
+
+unsafe impl Send for LoraAdapterPtr {}
+unsafe impl Sync for LoraAdapterPtr {}
+
+impl Drop for LoraAdapterPtr {
+    fn drop(&mut self) {
+        unsafe { llama::llama_adapter_lora_free(self.ptr) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdapterLoadError {
+    pub path: PathBuf,
+    pub kind: FailureKind,
+    /// error lines logged by llama.cpp while loading
+    pub log: Vec<String>,
+}
+
+impl std::fmt::Display for AdapterLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot load adapter {}: {}",
+            self.path.display(),
+            self.kind
+        )?;
+        fmt_log(f, &self.log)
+    }
+}
+
+impl std::error::Error for AdapterLoadError {}
+
+#[derive(Debug, Clone, Error)]
+#[error("cannot set adapter {0} on the context")]
+pub struct AdapterSetError(pub PathBuf);
+
+/// Directions added to the hidden state of each layer, to steer the generation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControlVector {
+    pub n_embd: usize,
+    /// `n_embd` values per layer, starting at layer 1 as layer 0 has no direction
+    pub data: Vec<f32>,
+}
+
+impl ControlVector {
+    /// Number of layers with a direction, from layer 1
+    pub fn n_layers(&self) -> usize {
+        self.data.len().checked_div(self.n_embd).unwrap_or(0)
+    }
+
+    /// Add the directions of `other`, scaled by `strength`, extending this vector to its
+    /// layers
+    ///
+    /// Returns false if the embedding sizes don't match.
+    pub fn add_scaled(&mut self, other: &ControlVector, strength: f32) -> bool {
+        if self.data.is_empty() {
+            self.n_embd = other.n_embd;
+        } else if self.n_embd != other.n_embd {
+            return false;
+        }
+        if self.data.len() < other.data.len() {
+            self.data.resize(other.data.len(), 0.0);
+        }
+        for (value, other) in self.data.iter_mut().zip(&other.data) {
+            *value += other * strength;
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+#[error(
+    "cannot apply a control vector of {n_embd} values per layer on layers {layer_start}-{layer_end}"
+)]
+pub struct ControlVectorError {
+    pub n_embd: usize,
+    pub layer_start: i32,
+    pub layer_end: i32,
+}
+
+impl LoraAdapter {
+    pub(crate) fn load(model: &Model, path: &Path) -> Result<Self, AdapterLoadError> {
+        let error = |kind, log| AdapterLoadError {
+            path: path.to_path_buf(),
+            kind,
+            log,
+        };
+        let cpath = path_to_cpath(path).ok_or_else(|| error(FailureKind::InvalidPath, vec![]))?;
+        if !path.exists() {
+            return Err(error(FailureKind::FileNotFound, vec![]));
+        }
+        let (ptr, log) = capture_errors(|| unsafe {
+            llama::llama_adapter_lora_init(model.ptr.0, cpath.as_ptr())
+        });
+        if ptr.is_null() {
+            return Err(error(FailureKind::classify(&log), log));
+        }
+        Ok(Self {
+            ptr: Arc::new(LoraAdapterPtr {
+                ptr,
+                _model: model.clone(),
+            }),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Context {
+    /// Replace the adapters applied by this context, each with its scale
+    ///
+    /// The weights of the model are shared, so switching adapters is cheap; the KV cache
+    /// is not cleared.
+    pub fn set_adapters(&mut self, adapters: &[(LoraAdapter, f32)]) -> Result<(), AdapterSetError> {
+        unsafe { llama::llama_clear_adapter_lora(self.ptr) };
+        self.adapters.clear();
+        for (adapter, scale) in adapters {
+            let ret = unsafe { llama::llama_set_adapter_lora(self.ptr, adapter.ptr.ptr, *scale) };
+            if ret != 0 {
+                return Err(AdapterSetError(adapter.path.clone()));
+            }
+            self.adapters.push(adapter.clone());
+        }
+        Ok(())
+    }
+}
+
+impl Context {
+    /// Apply a control vector to the layers `layer_start..=layer_end`, replacing the
+    /// previous one
+    pub fn set_control_vector(
+        &mut self,
+        cvec: &ControlVector,
+        layer_start: i32,
+        layer_end: i32,
+    ) -> Result<(), ControlVectorError> {
+        let ret = unsafe {
+            llama::llama_apply_adapter_cvec(
+                self.ptr,
+                cvec.data.as_ptr(),
+                cvec.data.len(),
+                cvec.n_embd as i32,
+                layer_start,
+                layer_end,
+            )
+        };
+        if ret != 0 {
+            return Err(ControlVectorError {
+                n_embd: cvec.n_embd,
+                layer_start,
+                layer_end,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn clear_control_vector(&mut self) {
+        unsafe { llama::llama_apply_adapter_cvec(self.ptr, std::ptr::null(), 0, 0, 0, 0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_control_vectors() {
+        let mut cvec = ControlVector::default();
+        let a = ControlVector {
+            n_embd: 2,
+            data: vec![1.0, 2.0],
+        };
+        let b = ControlVector {
+            n_embd: 2,
+            data: vec![1.0, 1.0, 3.0, 3.0],
+        };
+        assert!(cvec.add_scaled(&a, 1.0));
+        assert!(cvec.add_scaled(&b, -0.5));
+        assert_eq!(cvec.data, [0.5, 1.5, -1.5, -1.5]);
+        assert_eq!(cvec.n_layers(), 2);
+        assert!(!cvec.add_scaled(
+            &ControlVector {
+                n_embd: 3,
+                data: vec![0.0; 3]
+            },
+            1.0
+        ));
+    }
+}
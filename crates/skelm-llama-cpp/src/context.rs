@@ -1,7 +1,12 @@
 use skelm_llama_cpp_sys::llama;
 use thiserror::Error;
 
-use crate::{batch::Batch, token::Token, Model, Sampler, Vocab};
+use crate::{
+    LoraAdapter, Model, Sampler, Vocab,
+    batch::Batch,
+    log::{FailureKind, capture_errors, fmt_log},
+    token::Token,
+};
 
 #[allow(dead_code)]
 pub struct Context {
@@ -9,13 +14,25 @@ pub struct Context {
     pub(crate) tokens: usize,
     pub(crate) context_params: llama::llama_context_params,
     pub(crate) ptr: *mut llama::llama_context,
+    /// the adapters set on the context, kept alive while in use
+    pub(crate) adapters: Vec<LoraAdapter>,
 }
 
 unsafe impl Send for Context {}
 
+#[derive(Clone, Debug)]
 pub struct ContextParams {
     pub n_ctx: u32,
     pub embeddings: bool,
+    /// type of the keys in the KV cache
+    pub type_k: KvCacheType,
+    /// type of the values in the KV cache
+    pub type_v: KvCacheType,
+    /// number of sequences decoded together, see `Context::decode_sequences`
+    pub n_seq_max: u32,
+    /// one KV cache shared by all the sequences, so that copying a sequence shares its
+    /// cells instead of splitting the context between the sequences
+    pub kv_unified: bool,
 }
 
 impl Default for ContextParams {
@@ -25,6 +42,10 @@ impl Default for ContextParams {
         Self {
             n_ctx: context.n_ctx,
             embeddings: context.embeddings,
+            type_k: KvCacheType::F16,
+            type_v: KvCacheType::F16,
+            n_seq_max: context.n_seq_max,
+            kv_unified: context.kv_unified,
         }
     }
 }
@@ -34,10 +55,99 @@ impl ContextParams {
         let mut context = unsafe { llama::llama_context_default_params() };
         context.n_ctx = self.n_ctx;
         context.embeddings = self.embeddings;
+        context.type_k = self.type_k.as_c();
+        context.type_v = self.type_v.as_c();
+        context.n_seq_max = self.n_seq_max;
+        context.kv_unified = self.kv_unified;
         context
     }
 }
 
+/// The types supported for the KV cache
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvCacheType {
+    F32,
+    F16,
+    BF16,
+    Q8_0,
+    Q4_0,
+    Q4_1,
+    IQ4_NL,
+    Q5_0,
+    Q5_1,
+}
+
+impl KvCacheType {
+    pub const ALL: [Self; 9] = [
+        Self::F32,
+        Self::F16,
+        Self::BF16,
+        Self::Q8_0,
+        Self::Q4_0,
+        Self::Q4_1,
+        Self::IQ4_NL,
+        Self::Q5_0,
+        Self::Q5_1,
+    ];
+
+    fn as_c(self) -> llama::ggml_type {
+        match self {
+            Self::F32 => llama::ggml_type::GGML_TYPE_F32,
+            Self::F16 => llama::ggml_type::GGML_TYPE_F16,
+            Self::BF16 => llama::ggml_type::GGML_TYPE_BF16,
+            Self::Q8_0 => llama::ggml_type::GGML_TYPE_Q8_0,
+            Self::Q4_0 => llama::ggml_type::GGML_TYPE_Q4_0,
+            Self::Q4_1 => llama::ggml_type::GGML_TYPE_Q4_1,
+            Self::IQ4_NL => llama::ggml_type::GGML_TYPE_IQ4_NL,
+            Self::Q5_0 => llama::ggml_type::GGML_TYPE_Q5_0,
+            Self::Q5_1 => llama::ggml_type::GGML_TYPE_Q5_1,
+        }
+    }
+
+    /// The name used by llama.cpp tools, e.g. `q8_0`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::F16 => "f16",
+            Self::BF16 => "bf16",
+            Self::Q8_0 => "q8_0",
+            Self::Q4_0 => "q4_0",
+            Self::Q4_1 => "q4_1",
+            Self::IQ4_NL => "iq4_nl",
+            Self::Q5_0 => "q5_0",
+            Self::Q5_1 => "q5_1",
+        }
+    }
+}
+
+impl std::str::FromStr for KvCacheType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown KV cache type {}", s))
+    }
+}
+
+impl std::fmt::Display for KvCacheType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A token of a batch spanning several sequences
+#[derive(Clone, Copy, Debug)]
+pub struct SequenceToken {
+    pub token: Token,
+    pub position: usize,
+    pub seq: i32,
+    /// compute the logits predicted after this token
+    pub logits: bool,
+}
+
 #[derive(Clone, Copy, Debug, Error)]
 pub enum DecodeError {
     #[error("cannot find KV Slot")]
@@ -52,12 +162,22 @@ pub enum DecodeError {
     FatalError(#[allow(dead_code)] i32),
 }
 
-#[derive(Debug)]
-pub struct ContextCreateError;
+#[derive(Debug, Clone)]
+pub struct ContextCreateError {
+    pub n_ctx: u32,
+    pub kind: FailureKind,
+    /// error lines logged by llama.cpp while creating the context
+    pub log: Vec<String>,
+}
 
 impl std::fmt::Display for ContextCreateError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Context Create Error (consult logging for reasons)")
+        write!(
+            f,
+            "cannot create a context of {} tokens: {}",
+            self.n_ctx, self.kind
+        )?;
+        fmt_log(f, &self.log)
     }
 }
 
@@ -112,9 +232,15 @@ impl Context {
     pub fn new(model: Model, params: &ContextParams) -> Result<Self, ContextCreateError> {
         let c_params = params.as_c();
         let c_params_clone = params.as_c();
-        let ctx = unsafe { llama::llama_new_context_with_model(model.ptr.0, c_params) };
+        let (ctx, log) = capture_errors(|| unsafe {
+            llama::llama_new_context_with_model(model.ptr.0, c_params)
+        });
         if ctx.is_null() {
-            return Err(ContextCreateError);
+            return Err(ContextCreateError {
+                n_ctx: params.n_ctx,
+                kind: FailureKind::classify(&log),
+                log,
+            });
         }
 
         Ok(Self {
@@ -122,6 +248,7 @@ impl Context {
             tokens: 0,
             context_params: c_params_clone,
             ptr: ctx,
+            adapters: Vec::new(),
         })
     }
 
@@ -160,6 +287,10 @@ impl Context {
         unsafe { llama::llama_n_ubatch(self.ptr) }
     }
 
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { llama::llama_n_seq_max(self.ptr) }
+    }
+
     pub fn state_get(&self) -> Vec<u8> {
         let state_size = unsafe { llama::llama_state_get_size(self.ptr) };
 
@@ -175,11 +306,59 @@ impl Context {
         assert_eq!(read, data.len());
     }
 
-    pub fn memory_clear(&self, clear_data: bool) {
+    /// Forget all the tokens of the context, the next ones start at position 0
+    pub fn memory_clear(&mut self, clear_data: bool) {
         unsafe {
             let memory = llama::llama_get_memory(self.ptr);
             llama::llama_memory_clear(memory, clear_data)
         }
+        self.tokens = 0;
+    }
+
+    /// Make the sequence `dst` a copy of the sequence `src`, sharing its cells
+    pub fn seq_copy(&mut self, src: i32, dst: i32) {
+        unsafe {
+            let memory = llama::llama_get_memory(self.ptr);
+            llama::llama_memory_seq_cp(memory, src, dst, -1, -1)
+        }
+    }
+
+    /// Remove all the tokens of a sequence
+    pub fn seq_remove(&mut self, seq: i32) {
+        unsafe {
+            let memory = llama::llama_get_memory(self.ptr);
+            llama::llama_memory_seq_rm(memory, seq, -1, -1);
+        }
+    }
+
+    /// Decode tokens of several sequences, given with their position and sequence, and
+    /// call `f` with the index and the logits of each token requesting them
+    ///
+    /// The tokens are decoded in batches of `n_batch` tokens. The tokens of the context
+    /// are not counted, the positions being explicit.
+    pub fn decode_sequences(
+        &mut self,
+        tokens: &[SequenceToken],
+        mut f: impl FnMut(usize, &[f32]),
+    ) -> Result<(), DecodeError> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        let n_batch = std::cmp::max(self.n_batch() as usize, 1);
+        let mut batch = Batch::new(std::cmp::min(tokens.len(), n_batch), 0, 1);
+        for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+            for token in chunk {
+                batch.append(token.token, token.position, &[token.seq], token.logits);
+            }
+            self.decode(&batch)?;
+            batch.clear();
+            for (i, token) in chunk.iter().enumerate() {
+                if token.logits {
+                    f(chunk_index * n_batch + i, self.get_logits(i as i32));
+                }
+            }
+        }
+        Ok(())
     }
 
     fn decode(&self, batch: &Batch) -> Result<(), DecodeError> {
@@ -215,6 +394,34 @@ impl Context {
         Ok(())
     }
 
+    /// Append tokens, calling `f` with the index of each token and the logits predicted
+    /// after it
+    ///
+    /// The tokens are decoded in batches of `n_batch` tokens, all requesting their logits.
+    pub fn append_tokens_logits(
+        &mut self,
+        tokens: &[Token],
+        mut f: impl FnMut(usize, &[f32]),
+    ) -> Result<(), DecodeError> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        let n_batch = std::cmp::max(self.n_batch() as usize, 1);
+        let mut batch = Batch::new(std::cmp::min(tokens.len(), n_batch), 0, 1);
+        for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+            for (i, token) in chunk.iter().enumerate() {
+                batch.append(*token, self.tokens + i, &[0], true);
+            }
+            self.decode(&batch)?;
+            batch.clear();
+            self.tokens += chunk.len();
+            for i in 0..chunk.len() {
+                f(chunk_index * n_batch + i, self.get_logits(i as i32));
+            }
+        }
+        Ok(())
+    }
+
     pub fn next_token<S: Sampler>(&mut self, sampler: &mut S, vocab: &Vocab) -> Option<Token> {
         let new_token = sampler.sample(self, -1);
         (!vocab.is_eog(new_token)).then_some(new_token)
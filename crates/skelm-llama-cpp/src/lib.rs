@@ -2,21 +2,32 @@
 
 // NOT MEMORY SAFE YET / it will explode if you look at it side ways.
 
+mod adapter;
 mod batch;
 mod context;
 mod log;
 mod model;
+mod mtmd;
+mod quantize;
 mod sampler;
 mod token;
 mod tokendata;
 mod vocab;
 
-pub use context::{Context, ContextParams};
-pub use log::{LogKey, LogLevel, llama_logging};
+pub use adapter::{
+    AdapterLoadError, AdapterSetError, ControlVector, ControlVectorError, LoraAdapter,
+};
+pub use context::{
+    Context, ContextCreateError, ContextParams, DecodeError, KvCacheType, SequenceToken,
+};
+pub use log::{FailureKind, LogKey, LogLevel, llama_logging};
 pub use model::{Model, ModelLoadError, ModelParams};
+pub use mtmd::{Bitmap, MEDIA_MARKER, MediaError, Projector, ProjectorLoadError};
+pub use quantize::{ImportanceMatrix, QuantType, QuantizeError, QuantizeParams, quantize};
 pub use sampler::{
-    Sampler, SamplerChain, SamplerDistance, SamplerGreedy, SamplerMinP, SamplerMirostatV1,
-    SamplerMirostatV2, SamplerRandom, SamplerTemperature,
+    GrammarError, Sampler, SamplerChain, SamplerDistance, SamplerGrammar, SamplerGreedy,
+    SamplerMinP, SamplerMirostatV1, SamplerMirostatV2, SamplerRandom, SamplerTemperature,
+    SamplerTopK, SamplerTopP,
 };
 pub use token::Token;
 pub use tokendata::{TokenData, TokenDataArray};
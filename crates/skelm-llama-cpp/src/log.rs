@@ -1,5 +1,9 @@
-use skelm_llama_cpp_sys::llama;
-use std::{ffi::CStr, sync::Once};
+use skelm_llama_cpp_sys::{llama, mtmd};
+use std::{
+    cell::RefCell,
+    ffi::CStr,
+    sync::{Mutex, Once},
+};
 
 static LOG_CALLBACK: Once = Once::new();
 
@@ -103,39 +107,179 @@ impl<'a> From<&'a str> for LogKey {
     }
 }
 
+type LogFn = Box<dyn FnMut(LogLevel, LogKey, &str) + Send + 'static>;
+
+/// The callback set with `llama_logging`, llama.cpp's default of printing everything to
+/// stderr is kept when there's none
+static LOGGER: Mutex<Option<LogFn>> = Mutex::new(None);
+
+thread_local! {
+    /// Error lines logged by the calls wrapped in `capture_errors` on this thread
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
 unsafe extern "C" fn _llama_bindings_log_callback_internal(
     level: llama::ggml_log_level,
     text: *const ::std::os::raw::c_char,
-    user_data: *mut ::std::os::raw::c_void,
+    _user_data: *mut ::std::os::raw::c_void,
 ) {
+    let t = unsafe { CStr::from_ptr(text) };
+    let s = t.to_string_lossy();
+    let x = s.strip_suffix("\n").unwrap_or(&s);
+    // errors are captured whether or not a logger is installed
+    if level == llama::ggml_log_level::GGML_LOG_LEVEL_ERROR {
+        CAPTURED.with_borrow_mut(|captured| {
+            if let Some(lines) = captured {
+                lines.push(x.trim().to_string())
+            }
+        });
+    }
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(f) = logger.as_mut() else {
+        eprint!("{}", s);
+        return;
+    };
     if level == llama::ggml_log_level::GGML_LOG_LEVEL_CONT {
         return;
     }
     let level = LogLevel::from(level);
-    let t = unsafe { CStr::from_ptr(text) };
-    let s = t.to_string_lossy();
-    let x = s.strip_suffix("\n").unwrap_or(&s);
-    let f = unsafe { &mut *(user_data as *mut LogCallback) };
     if let Some((cat, content)) = x.split_once(':') {
         let k = LogKey::from(cat);
-        f.0(level, k, content)
+        f(level, k, content)
     } else {
-        f.0(level, LogKey::Unknown, x)
+        f(level, LogKey::Unknown, x)
     }
 }
 
-struct LogCallback<'a>(Box<dyn FnMut(LogLevel, LogKey, &'a str) + Send + 'static>);
-
-pub fn llama_logging<'a, F: 'a>(f: Box<F>)
-where
-    F: FnMut(LogLevel, LogKey, &'a str) + Send + 'static,
-{
-    let cb = LogCallback(Box::new(f));
-    let boxed = Box::into_raw(Box::new(cb));
+fn install_callback() {
     LOG_CALLBACK.call_once(|| unsafe {
         llama::llama_log_set(
             Some(_llama_bindings_log_callback_internal),
-            boxed as *mut ::std::os::raw::c_void,
-        )
+            std::ptr::null_mut(),
+        );
+        // the projectors log through their own callback
+        mtmd::mtmd_helper_log_set(
+            Some(_llama_bindings_log_callback_internal),
+            std::ptr::null_mut(),
+        );
     })
 }
+
+pub fn llama_logging<F>(f: Box<F>)
+where
+    F: FnMut(LogLevel, LogKey, &str) + Send + 'static,
+{
+    *LOGGER.lock().unwrap_or_else(|e| e.into_inner()) = Some(f);
+    install_callback()
+}
+
+/// Run `f`, collecting the error lines llama.cpp logs meanwhile on this thread
+pub(crate) fn capture_errors<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    install_callback();
+    let previous = CAPTURED.replace(Some(Vec::new()));
+    let ret = f();
+    let lines = CAPTURED.replace(previous).unwrap_or_default();
+    (ret, lines)
+}
+
+/// Append the logged error lines to an error message
+pub(crate) fn fmt_log(f: &mut std::fmt::Formatter<'_>, log: &[String]) -> std::fmt::Result {
+    if !log.is_empty() {
+        write!(f, " ({})", log.join("; "))?;
+    }
+    Ok(())
+}
+
+/// Probable cause of a llama.cpp failure, guessed from the logged errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    FileNotFound,
+    InvalidPath,
+    BadMagic,
+    UnsupportedArchitecture,
+    OutOfMemory,
+    TensorMismatch,
+    Unknown,
+}
+
+impl FailureKind {
+    pub(crate) fn classify(lines: &[String]) -> Self {
+        const PATTERNS: &[(&str, FailureKind)] = &[
+            ("failed to open", FailureKind::FileNotFound),
+            ("no such file", FailureKind::FileNotFound),
+            ("invalid magic", FailureKind::BadMagic),
+            ("failed to read magic", FailureKind::BadMagic),
+            (
+                "unknown model architecture",
+                FailureKind::UnsupportedArchitecture,
+            ),
+            (
+                "unsupported model architecture",
+                FailureKind::UnsupportedArchitecture,
+            ),
+            ("failed to allocate", FailureKind::OutOfMemory),
+            ("unable to allocate", FailureKind::OutOfMemory),
+            ("out of memory", FailureKind::OutOfMemory),
+            ("wrong number of tensors", FailureKind::TensorMismatch),
+            ("wrong shape", FailureKind::TensorMismatch),
+            ("missing tensor", FailureKind::TensorMismatch),
+            ("not within the file bounds", FailureKind::TensorMismatch),
+        ];
+        lines
+            .iter()
+            .map(|line| line.to_lowercase())
+            .find_map(|line| {
+                PATTERNS
+                    .iter()
+                    .find(|(pattern, _)| line.contains(pattern))
+                    .map(|(_, kind)| *kind)
+            })
+            .unwrap_or(FailureKind::Unknown)
+    }
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::FileNotFound => "file not found",
+            Self::InvalidPath => "path cannot be passed to llama.cpp",
+            Self::BadMagic => "not a GGUF file",
+            Self::UnsupportedArchitecture => "model architecture not supported by llama.cpp",
+            Self::OutOfMemory => "out of memory",
+            Self::TensorMismatch => {
+                "tensors don't match the model architecture (truncated file or newer format)"
+            }
+            Self::Unknown => "unknown failure",
+        };
+        f.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_failures() {
+        let lines = |l: &[&str]| l.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            FailureKind::classify(&lines(&[
+                "llama_model_load: error loading model: error loading model architecture: unknown model architecture: 'foo'"
+            ])),
+            FailureKind::UnsupportedArchitecture
+        );
+        assert_eq!(
+            FailureKind::classify(&lines(&[
+                "gguf_init_from_file_impl: invalid magic characters: 'XGUF', expected 'GGUF'"
+            ])),
+            FailureKind::BadMagic
+        );
+        assert_eq!(
+            FailureKind::classify(&lines(&[
+                "llama_model_load: error loading model: done_getting_tensors: wrong number of tensors; expected 292, got 291"
+            ])),
+            FailureKind::TensorMismatch
+        );
+        assert_eq!(FailureKind::classify(&[]), FailureKind::Unknown);
+    }
+}
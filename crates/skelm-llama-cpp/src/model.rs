@@ -1,9 +1,14 @@
 use skelm_llama_cpp_sys::llama;
 use std::ptr::null_mut;
 use std::sync::Arc;
-use std::{ffi::CStr, path::Path};
+use std::{
+    ffi::{CStr, CString},
+    path::{Path, PathBuf},
+};
 
+use crate::adapter::{AdapterLoadError, LoraAdapter};
 use crate::context::ContextParams;
+use crate::log::{FailureKind, capture_errors, fmt_log};
 use crate::vocab::{Vocab, VocabPtr};
 
 use super::context::{Context, ContextCreateError};
@@ -40,12 +45,23 @@ impl ModelParams {
     }
 }
 
-#[derive(Debug)]
-pub struct ModelLoadError;
+#[derive(Debug, Clone)]
+pub struct ModelLoadError {
+    pub path: PathBuf,
+    pub kind: FailureKind,
+    /// error lines logged by llama.cpp while loading
+    pub log: Vec<String>,
+}
 
 impl std::fmt::Display for ModelLoadError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Model Load Error (consult logging for reasons)")
+        write!(
+            f,
+            "cannot load model {}: {}",
+            self.path.display(),
+            self.kind
+        )?;
+        fmt_log(f, &self.log)
     }
 }
 
@@ -54,10 +70,21 @@ impl std::error::Error for ModelLoadError {}
 impl Model {
     pub fn load(path: impl AsRef<Path>, params: &ModelParams) -> Result<Self, ModelLoadError> {
         let path = path.as_ref();
+        let error = |kind, log| ModelLoadError {
+            path: path.to_path_buf(),
+            kind,
+            log,
+        };
+        let cpath = path_to_cpath(path).ok_or_else(|| error(FailureKind::InvalidPath, vec![]))?;
+        if !path.exists() {
+            return Err(error(FailureKind::FileNotFound, vec![]));
+        }
         let c_params = params.as_c();
-        let ret = unsafe { llama::llama_load_model_from_file(path_to_cpath(path), c_params) };
+        let (ret, log) = capture_errors(|| unsafe {
+            llama::llama_load_model_from_file(cpath.as_ptr(), c_params)
+        });
         if ret.is_null() {
-            return Err(ModelLoadError);
+            return Err(error(FailureKind::classify(&log), log));
         }
 
         Ok(Model {
@@ -104,6 +131,15 @@ impl Model {
         unsafe { llama::llama_model_n_embd(self.ptr.0) as usize }
     }
 
+    pub fn n_layer(&self) -> usize {
+        unsafe { llama::llama_model_n_layer(self.ptr.0) as usize }
+    }
+
+    /// Load a LoRA adapter of this model
+    pub fn load_adapter(&self, path: impl AsRef<Path>) -> Result<LoraAdapter, AdapterLoadError> {
+        LoraAdapter::load(self, path.as_ref())
+    }
+
     /// Create a new context for this model
     pub fn new_context(&self, params: &ContextParams) -> Result<Context, ContextCreateError> {
         Context::new(self.clone(), params)
@@ -121,10 +157,16 @@ impl Model {
     }
 }
 
+/// The path as a NUL terminated string, or `None` if it contains a NUL byte or isn't
+/// representable as bytes on this platform
 #[cfg(unix)]
-use std::os::unix::ffi::OsStrExt;
+pub(crate) fn path_to_cpath(path: &Path) -> Option<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    CString::new(path.as_os_str().as_bytes()).ok()
+}
 
-#[cfg(unix)]
-fn path_to_cpath(path: &Path) -> *const ::std::os::raw::c_char {
-    path.as_os_str().as_bytes().as_ptr() as *const ::std::os::raw::c_char
+/// llama.cpp expects UTF-8 paths on platforms without byte paths
+#[cfg(not(unix))]
+pub(crate) fn path_to_cpath(path: &Path) -> Option<CString> {
+    CString::new(path.to_str()?).ok()
 }
@@ -0,0 +1,188 @@
+//! Multimodal input through the projectors (mmproj) of vision models
+use skelm_llama_cpp_sys::mtmd;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::log::{FailureKind, capture_errors, fmt_log};
+use crate::model::path_to_cpath;
+use crate::{Context, Model};
+
+/// Marker placed in the prompt where each image is inserted
+pub const MEDIA_MARKER: &str = "<__media__>";
+
+/// A projector turning images into embeddings of a model
+#[derive(Clone)]
+pub struct Projector {
+    ptr: Arc<ProjectorPtr>,
+    // the projector refers to the model, which has to outlive it
+    #[allow(dead_code)]
+    model: Model,
+}
+
+struct ProjectorPtr(*mut mtmd::mtmd_context);
+
+unsafe impl Send for ProjectorPtr {}
+unsafe impl Sync for ProjectorPtr {}
+
+impl Drop for ProjectorPtr {
+    fn drop(&mut self) {
+        unsafe { mtmd::mtmd_free(self.0) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProjectorLoadError {
+    pub path: PathBuf,
+    pub kind: FailureKind,
+    /// error lines logged by llama.cpp while loading
+    pub log: Vec<String>,
+}
+
+impl std::fmt::Display for ProjectorLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot load projector {}: {}",
+            self.path.display(),
+            self.kind
+        )?;
+        fmt_log(f, &self.log)
+    }
+}
+
+impl std::error::Error for ProjectorLoadError {}
+
+#[derive(Debug, Error)]
+pub enum MediaError {
+    #[error("cannot decode the image (PNG and JPEG are supported)")]
+    InvalidImage,
+    #[error("the prompt has {markers} media markers for {images} images")]
+    MarkerMismatch { markers: usize, images: usize },
+    #[error("image preprocessing failed")]
+    Preprocessing,
+    #[error("multimodal tokenization failed ({0})")]
+    Tokenize(i32),
+    #[error("evaluating the multimodal prompt failed ({0})")]
+    Eval(i32),
+}
+
+/// A decoded image, ready to be given to the projector
+pub struct Bitmap(*mut mtmd::mtmd_bitmap);
+
+impl Drop for Bitmap {
+    fn drop(&mut self) {
+        unsafe { mtmd::mtmd_bitmap_free(self.0) }
+    }
+}
+
+struct InputChunks(*mut mtmd::mtmd_input_chunks);
+
+impl Drop for InputChunks {
+    fn drop(&mut self) {
+        unsafe { mtmd::mtmd_input_chunks_free(self.0) }
+    }
+}
+
+impl Projector {
+    pub fn load(path: impl AsRef<Path>, model: &Model) -> Result<Self, ProjectorLoadError> {
+        let path = path.as_ref();
+        let error = |kind, log| ProjectorLoadError {
+            path: path.to_path_buf(),
+            kind,
+            log,
+        };
+        let cpath = path_to_cpath(path).ok_or_else(|| error(FailureKind::InvalidPath, vec![]))?;
+        if !path.exists() {
+            return Err(error(FailureKind::FileNotFound, vec![]));
+        }
+        let marker = CString::new(MEDIA_MARKER).unwrap();
+        let mut params = unsafe { mtmd::mtmd_context_params_default() };
+        params.media_marker = marker.as_ptr();
+        params.print_timings = false;
+        let (ptr, log) = capture_errors(|| unsafe {
+            mtmd::mtmd_init_from_file(cpath.as_ptr(), model.ptr.0, params)
+        });
+        if ptr.is_null() {
+            return Err(error(FailureKind::classify(&log), log));
+        }
+        Ok(Self {
+            ptr: Arc::new(ProjectorPtr(ptr)),
+            model: model.clone(),
+        })
+    }
+
+    pub fn supports_vision(&self) -> bool {
+        unsafe { mtmd::mtmd_support_vision(self.ptr.0) }
+    }
+
+    /// Decode a PNG or JPEG image
+    pub fn bitmap_from_bytes(&self, bytes: &[u8]) -> Result<Bitmap, MediaError> {
+        let ptr = unsafe {
+            mtmd::mtmd_helper_bitmap_init_from_buf(self.ptr.0, bytes.as_ptr(), bytes.len())
+        };
+        if ptr.is_null() {
+            return Err(MediaError::InvalidImage);
+        }
+        Ok(Bitmap(ptr))
+    }
+
+    /// Append a prompt to the context, each [`MEDIA_MARKER`] of the prompt being
+    /// replaced by the embeddings of the next image
+    pub fn eval(
+        &self,
+        context: &mut Context,
+        prompt: &str,
+        images: &[Bitmap],
+    ) -> Result<(), MediaError> {
+        let text = CString::new(prompt).map_err(|_| MediaError::Tokenize(-1))?;
+        let input = mtmd::mtmd_input_text {
+            text: text.as_ptr(),
+            add_special: context.tokens == 0,
+            parse_special: true,
+        };
+        let mut bitmaps: Vec<*const mtmd::mtmd_bitmap> =
+            images.iter().map(|b| b.0 as *const _).collect();
+        let chunks = InputChunks(unsafe { mtmd::mtmd_input_chunks_init() });
+        let ret = unsafe {
+            mtmd::mtmd_tokenize(
+                self.ptr.0,
+                chunks.0,
+                &input,
+                bitmaps.as_mut_ptr(),
+                bitmaps.len(),
+            )
+        };
+        match ret {
+            0 => (),
+            1 => {
+                return Err(MediaError::MarkerMismatch {
+                    markers: prompt.matches(MEDIA_MARKER).count(),
+                    images: images.len(),
+                });
+            }
+            2 => return Err(MediaError::Preprocessing),
+            _ => return Err(MediaError::Tokenize(ret)),
+        }
+
+        let mut n_past = context.tokens as i32;
+        let ret = unsafe {
+            mtmd::mtmd_helper_eval_chunks(
+                self.ptr.0,
+                context.ptr,
+                chunks.0,
+                n_past,
+                0,
+                context.n_batch() as i32,
+                true,
+                &mut n_past,
+            )
+        };
+        if ret != 0 {
+            return Err(MediaError::Eval(ret));
+        }
+        context.tokens = n_past as usize;
+        Ok(())
+    }
+}
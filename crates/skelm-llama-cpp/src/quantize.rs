@@ -0,0 +1,316 @@
+//! Conversion of a model file to a smaller type of weights
+use skelm_llama_cpp_sys::{llama, shim};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use crate::log::{FailureKind, capture_errors, fmt_log};
+use crate::model::path_to_cpath;
+
+/// The types a model can be quantized to, named as in llama.cpp's `llama-quantize`
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuantType {
+    F32,
+    F16,
+    BF16,
+    Q8_0,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q2_K,
+    Q2_K_S,
+    Q3_K_S,
+    Q3_K_M,
+    Q3_K_L,
+    Q4_K_S,
+    Q4_K_M,
+    Q5_K_S,
+    Q5_K_M,
+    Q6_K,
+    IQ1_S,
+    IQ1_M,
+    IQ2_XXS,
+    IQ2_XS,
+    IQ2_S,
+    IQ2_M,
+    IQ3_XXS,
+    IQ3_XS,
+    IQ3_S,
+    IQ3_M,
+    IQ4_NL,
+    IQ4_XS,
+    TQ1_0,
+    TQ2_0,
+}
+
+impl QuantType {
+    pub const ALL: [Self; 32] = [
+        Self::F32,
+        Self::F16,
+        Self::BF16,
+        Self::Q8_0,
+        Self::Q4_0,
+        Self::Q4_1,
+        Self::Q5_0,
+        Self::Q5_1,
+        Self::Q2_K,
+        Self::Q2_K_S,
+        Self::Q3_K_S,
+        Self::Q3_K_M,
+        Self::Q3_K_L,
+        Self::Q4_K_S,
+        Self::Q4_K_M,
+        Self::Q5_K_S,
+        Self::Q5_K_M,
+        Self::Q6_K,
+        Self::IQ1_S,
+        Self::IQ1_M,
+        Self::IQ2_XXS,
+        Self::IQ2_XS,
+        Self::IQ2_S,
+        Self::IQ2_M,
+        Self::IQ3_XXS,
+        Self::IQ3_XS,
+        Self::IQ3_S,
+        Self::IQ3_M,
+        Self::IQ4_NL,
+        Self::IQ4_XS,
+        Self::TQ1_0,
+        Self::TQ2_0,
+    ];
+
+    fn as_c(self) -> llama::llama_ftype {
+        use llama::llama_ftype as F;
+        match self {
+            Self::F32 => F::LLAMA_FTYPE_ALL_F32,
+            Self::F16 => F::LLAMA_FTYPE_MOSTLY_F16,
+            Self::BF16 => F::LLAMA_FTYPE_MOSTLY_BF16,
+            Self::Q8_0 => F::LLAMA_FTYPE_MOSTLY_Q8_0,
+            Self::Q4_0 => F::LLAMA_FTYPE_MOSTLY_Q4_0,
+            Self::Q4_1 => F::LLAMA_FTYPE_MOSTLY_Q4_1,
+            Self::Q5_0 => F::LLAMA_FTYPE_MOSTLY_Q5_0,
+            Self::Q5_1 => F::LLAMA_FTYPE_MOSTLY_Q5_1,
+            Self::Q2_K => F::LLAMA_FTYPE_MOSTLY_Q2_K,
+            Self::Q2_K_S => F::LLAMA_FTYPE_MOSTLY_Q2_K_S,
+            Self::Q3_K_S => F::LLAMA_FTYPE_MOSTLY_Q3_K_S,
+            Self::Q3_K_M => F::LLAMA_FTYPE_MOSTLY_Q3_K_M,
+            Self::Q3_K_L => F::LLAMA_FTYPE_MOSTLY_Q3_K_L,
+            Self::Q4_K_S => F::LLAMA_FTYPE_MOSTLY_Q4_K_S,
+            Self::Q4_K_M => F::LLAMA_FTYPE_MOSTLY_Q4_K_M,
+            Self::Q5_K_S => F::LLAMA_FTYPE_MOSTLY_Q5_K_S,
+            Self::Q5_K_M => F::LLAMA_FTYPE_MOSTLY_Q5_K_M,
+            Self::Q6_K => F::LLAMA_FTYPE_MOSTLY_Q6_K,
+            Self::IQ1_S => F::LLAMA_FTYPE_MOSTLY_IQ1_S,
+            Self::IQ1_M => F::LLAMA_FTYPE_MOSTLY_IQ1_M,
+            Self::IQ2_XXS => F::LLAMA_FTYPE_MOSTLY_IQ2_XXS,
+            Self::IQ2_XS => F::LLAMA_FTYPE_MOSTLY_IQ2_XS,
+            Self::IQ2_S => F::LLAMA_FTYPE_MOSTLY_IQ2_S,
+            Self::IQ2_M => F::LLAMA_FTYPE_MOSTLY_IQ2_M,
+            Self::IQ3_XXS => F::LLAMA_FTYPE_MOSTLY_IQ3_XXS,
+            Self::IQ3_XS => F::LLAMA_FTYPE_MOSTLY_IQ3_XS,
+            Self::IQ3_S => F::LLAMA_FTYPE_MOSTLY_IQ3_S,
+            Self::IQ3_M => F::LLAMA_FTYPE_MOSTLY_IQ3_M,
+            Self::IQ4_NL => F::LLAMA_FTYPE_MOSTLY_IQ4_NL,
+            Self::IQ4_XS => F::LLAMA_FTYPE_MOSTLY_IQ4_XS,
+            Self::TQ1_0 => F::LLAMA_FTYPE_MOSTLY_TQ1_0,
+            Self::TQ2_0 => F::LLAMA_FTYPE_MOSTLY_TQ2_0,
+        }
+    }
+
+    /// The numeric value of the type, as stored in the `general.file_type` of GGUF files
+    pub fn file_type(self) -> u32 {
+        self.as_c() as u32
+    }
+
+    /// The name used by llama.cpp tools, e.g. `Q4_K_M`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::F32 => "F32",
+            Self::F16 => "F16",
+            Self::BF16 => "BF16",
+            Self::Q8_0 => "Q8_0",
+            Self::Q4_0 => "Q4_0",
+            Self::Q4_1 => "Q4_1",
+            Self::Q5_0 => "Q5_0",
+            Self::Q5_1 => "Q5_1",
+            Self::Q2_K => "Q2_K",
+            Self::Q2_K_S => "Q2_K_S",
+            Self::Q3_K_S => "Q3_K_S",
+            Self::Q3_K_M => "Q3_K_M",
+            Self::Q3_K_L => "Q3_K_L",
+            Self::Q4_K_S => "Q4_K_S",
+            Self::Q4_K_M => "Q4_K_M",
+            Self::Q5_K_S => "Q5_K_S",
+            Self::Q5_K_M => "Q5_K_M",
+            Self::Q6_K => "Q6_K",
+            Self::IQ1_S => "IQ1_S",
+            Self::IQ1_M => "IQ1_M",
+            Self::IQ2_XXS => "IQ2_XXS",
+            Self::IQ2_XS => "IQ2_XS",
+            Self::IQ2_S => "IQ2_S",
+            Self::IQ2_M => "IQ2_M",
+            Self::IQ3_XXS => "IQ3_XXS",
+            Self::IQ3_XS => "IQ3_XS",
+            Self::IQ3_S => "IQ3_S",
+            Self::IQ3_M => "IQ3_M",
+            Self::IQ4_NL => "IQ4_NL",
+            Self::IQ4_XS => "IQ4_XS",
+            Self::TQ1_0 => "TQ1_0",
+            Self::TQ2_0 => "TQ2_0",
+        }
+    }
+
+    /// The lowest bit types give garbage without an importance matrix
+    pub fn needs_imatrix(self) -> bool {
+        matches!(
+            self,
+            Self::IQ1_S | Self::IQ1_M | Self::IQ2_XXS | Self::IQ2_XS | Self::IQ2_S | Self::Q2_K_S
+        )
+    }
+}
+
+impl std::str::FromStr for QuantType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown quantization type {}", s))
+    }
+}
+
+impl std::fmt::Display for QuantType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Importance of the weights of each tensor, as computed by llama.cpp's `llama-imatrix`
+pub type ImportanceMatrix = HashMap<String, Vec<f32>>;
+
+#[derive(Clone, Debug)]
+pub struct QuantizeParams {
+    pub ftype: QuantType,
+    /// threads used, 0 for the number of cores
+    pub threads: u32,
+    pub imatrix: Option<ImportanceMatrix>,
+    /// keep the output tensor in its original type
+    pub leave_output_tensor: bool,
+    /// quantize all tensors to `ftype`, instead of using higher precision types for the
+    /// sensitive ones
+    pub pure: bool,
+    /// allow quantizing tensors that are already quantized, which loses quality
+    pub allow_requantize: bool,
+}
+
+impl QuantizeParams {
+    pub fn new(ftype: QuantType) -> Self {
+        Self {
+            ftype,
+            threads: 0,
+            imatrix: None,
+            leave_output_tensor: false,
+            pure: false,
+            allow_requantize: false,
+        }
+    }
+}
+
+/// The C++ map given to llama.cpp, alive for the duration of the quantization
+struct ImatrixPtr(*mut std::ffi::c_void);
+
+impl ImatrixPtr {
+    fn new(imatrix: &ImportanceMatrix) -> Self {
+        let ptr = Self(unsafe { shim::skelm_imatrix_new() });
+        for (name, values) in imatrix {
+            // tensor names never contain NUL
+            let Ok(name) = CString::new(name.as_str()) else {
+                continue;
+            };
+            unsafe { shim::skelm_imatrix_add(ptr.0, name.as_ptr(), values.as_ptr(), values.len()) };
+        }
+        ptr
+    }
+}
+
+impl Drop for ImatrixPtr {
+    fn drop(&mut self) {
+        unsafe { shim::skelm_imatrix_free(self.0) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuantizeError {
+    pub path: PathBuf,
+    pub kind: FailureKind,
+    /// error lines logged by llama.cpp while quantizing
+    pub log: Vec<String>,
+}
+
+impl std::fmt::Display for QuantizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cannot quantize {}: {}", self.path.display(), self.kind)?;
+        fmt_log(f, &self.log)
+    }
+}
+
+impl std::error::Error for QuantizeError {}
+
+/// Quantize the model file `input` into `output`
+///
+/// This reads and writes the whole model and takes a while for large models.
+pub fn quantize(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    params: &QuantizeParams,
+) -> Result<(), QuantizeError> {
+    let input = input.as_ref();
+    let error = |kind, log| QuantizeError {
+        path: input.to_path_buf(),
+        kind,
+        log,
+    };
+    let cinput = path_to_cpath(input).ok_or_else(|| error(FailureKind::InvalidPath, vec![]))?;
+    let coutput =
+        path_to_cpath(output.as_ref()).ok_or_else(|| error(FailureKind::InvalidPath, vec![]))?;
+    if !input.exists() {
+        return Err(error(FailureKind::FileNotFound, vec![]));
+    }
+
+    let imatrix = params.imatrix.as_ref().map(ImatrixPtr::new);
+    let mut c_params = unsafe { llama::llama_model_quantize_default_params() };
+    c_params.ftype = params.ftype.as_c();
+    c_params.nthread = params.threads as i32;
+    c_params.quantize_output_tensor = !params.leave_output_tensor;
+    c_params.pure = params.pure;
+    c_params.allow_requantize = params.allow_requantize;
+    if let Some(imatrix) = &imatrix {
+        c_params.imatrix = imatrix.0;
+    }
+
+    let (ret, log) = capture_errors(|| unsafe {
+        llama::llama_model_quantize(cinput.as_ptr(), coutput.as_ptr(), &c_params)
+    });
+    if ret != 0 {
+        return Err(error(FailureKind::classify(&log), log));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_quant_type() {
+        assert_eq!("q4_k_m".parse(), Ok(QuantType::Q4_K_M));
+        assert_eq!("IQ3_XXS".parse(), Ok(QuantType::IQ3_XXS));
+        assert!("q4_k".parse::<QuantType>().is_err());
+        for t in QuantType::ALL {
+            assert_eq!(t.as_str().parse(), Ok(t));
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use skelm_llama_cpp_sys::llama;
 
+use std::{ffi::CString, ptr::null};
+
+use thiserror::Error;
+
 use crate::token::Token;
-use crate::{Context, TokenData, TokenDataArray};
+use crate::{Context, TokenDataArray, Vocab};
 
 pub trait SamplerC {
     unsafe fn as_mut(&mut self) -> *mut llama::llama_sampler;
@@ -20,18 +24,13 @@ pub trait Sampler {
     fn reset(&mut self);
 
     fn sample(&mut self, context: &Context, idx: i32) -> Token {
-        let vocab = context.model.clone().vocab();
-        let mut data = Vec::with_capacity(vocab.n_tokens() as usize);
+        self.sample_logits(context.get_logits(idx))
+    }
 
-        let logits = context.get_logits(idx);
-        for token in vocab.tokens() {
-            data.push(TokenData::new(token, logits[token.as_index()], 0.0))
-        }
-        let mut array = TokenDataArray {
-            data,
-            selected: None,
-            sorted: false,
-        };
+    /// Select a token from logits already taken from a context, e.g. the logits of one
+    /// of the sequences of a batch
+    fn sample_logits(&mut self, logits: &[f32]) -> Token {
+        let mut array = TokenDataArray::from_logits(logits);
 
         self.apply(&mut array);
         let Some(sel) = array.selected else {
@@ -108,6 +107,34 @@ impl Sampler for SamplerChain {
     }
 }
 
+pub struct SamplerTopK {
+    ptr: *mut llama::llama_sampler,
+}
+
+impl SamplerTopK {
+    pub fn new(k: i32) -> Self {
+        unsafe {
+            Self {
+                ptr: llama::llama_sampler_init_top_k(k),
+            }
+        }
+    }
+}
+
+pub struct SamplerTopP {
+    ptr: *mut llama::llama_sampler,
+}
+
+impl SamplerTopP {
+    pub fn new(p: f32, min_keep: usize) -> Self {
+        unsafe {
+            Self {
+                ptr: llama::llama_sampler_init_top_p(p, min_keep),
+            }
+        }
+    }
+}
+
 pub struct SamplerMinP {
     ptr: *mut llama::llama_sampler,
 }
@@ -178,6 +205,61 @@ impl SamplerMirostatV2 {
     }
 }
 
+/// Constrain the generation to a GBNF grammar
+pub struct SamplerGrammar {
+    ptr: *mut llama::llama_sampler,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid grammar (see the llama.cpp log for the reason)")]
+pub struct GrammarError;
+
+impl SamplerGrammar {
+    pub fn new(vocab: &Vocab, grammar: &str, root: &str) -> Result<Self, GrammarError> {
+        let grammar = CString::new(grammar).map_err(|_| GrammarError)?;
+        let root = CString::new(root).map_err(|_| GrammarError)?;
+        let ptr = unsafe {
+            llama::llama_sampler_init_grammar(vocab.ptr.0, grammar.as_ptr(), root.as_ptr())
+        };
+        if ptr.is_null() {
+            return Err(GrammarError);
+        }
+        Ok(Self { ptr })
+    }
+
+    /// A grammar only applied once the generated text matches one of the trigger
+    /// patterns (regular expressions), the grammar matching from the trigger
+    pub fn new_lazy(
+        vocab: &Vocab,
+        grammar: &str,
+        root: &str,
+        trigger_patterns: &[&str],
+    ) -> Result<Self, GrammarError> {
+        let grammar = CString::new(grammar).map_err(|_| GrammarError)?;
+        let root = CString::new(root).map_err(|_| GrammarError)?;
+        let patterns = trigger_patterns
+            .iter()
+            .map(|p| CString::new(*p).map_err(|_| GrammarError))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut pattern_ptrs = patterns.iter().map(|p| p.as_ptr()).collect::<Vec<_>>();
+        let ptr = unsafe {
+            llama::llama_sampler_init_grammar_lazy_patterns(
+                vocab.ptr.0,
+                grammar.as_ptr(),
+                root.as_ptr(),
+                pattern_ptrs.as_mut_ptr(),
+                pattern_ptrs.len(),
+                null(),
+                0,
+            )
+        };
+        if ptr.is_null() {
+            return Err(GrammarError);
+        }
+        Ok(Self { ptr })
+    }
+}
+
 macro_rules! impl_sampler {
     ($name:ident) => {
         impl Drop for $name {
@@ -196,11 +278,14 @@ macro_rules! impl_sampler {
     };
 }
 
+impl_sampler!(SamplerTopK);
+impl_sampler!(SamplerTopP);
 impl_sampler!(SamplerMinP);
 impl_sampler!(SamplerTemperature);
 impl_sampler!(SamplerDistance);
 impl_sampler!(SamplerMirostatV1);
 impl_sampler!(SamplerMirostatV2);
+impl_sampler!(SamplerGrammar);
 
 impl SamplerRandom for SamplerMirostatV1 {}
 impl SamplerRandom for SamplerMirostatV2 {}
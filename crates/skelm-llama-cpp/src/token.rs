@@ -3,6 +3,10 @@
 pub struct Token(pub(crate) i32);
 
 impl Token {
+    pub fn id(self) -> i32 {
+        self.0
+    }
+
     pub fn as_index(self) -> usize {
         self.0 as usize
     }
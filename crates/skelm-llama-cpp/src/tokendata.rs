@@ -58,6 +58,39 @@ pub struct TokenDataArray {
 }
 
 impl TokenDataArray {
+    /// The candidates of all the tokens of the vocabulary, from the logits of a context
+    pub fn from_logits(logits: &[f32]) -> Self {
+        let data = logits
+            .iter()
+            .enumerate()
+            .map(|(i, logit)| TokenData::new(Token(i as i32), *logit, 0.0))
+            .collect();
+        Self {
+            data,
+            selected: None,
+            sorted: false,
+        }
+    }
+
+    /// Sort the candidates by decreasing logit and set their probabilities
+    pub fn softmax(&mut self) {
+        self.data
+            .sort_unstable_by(|a, b| b.logit().total_cmp(&a.logit()));
+        self.sorted = true;
+        let Some(max) = self.data.first().map(|d| d.logit()) else {
+            return;
+        };
+        let mut sum = 0.0;
+        for d in self.data.iter_mut() {
+            let p = (d.logit() - max).exp();
+            d.set_proba(p);
+            sum += p;
+        }
+        for d in self.data.iter_mut() {
+            d.set_proba(d.proba() / sum);
+        }
+    }
+
     pub fn as_mut_ptr<F, R>(&mut self, f: F) -> R
     where
         F: FnOnce(*mut llama::llama_token_data_array) -> R,
@@ -80,3 +113,20 @@ impl TokenDataArray {
         r
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn softmax() {
+        let mut array = TokenDataArray::from_logits(&[0.0, 2.0f32.ln(), 0.0]);
+        array.softmax();
+        let ids: Vec<_> = array.data.iter().map(|d| d.id().id()).collect();
+        assert_eq!(ids[0], 1);
+        let probas: Vec<_> = array.data.iter().map(|d| d.proba()).collect();
+        assert!((probas[0] - 0.5).abs() < 1e-6);
+        assert!((probas[1] - 0.25).abs() < 1e-6);
+        assert!((probas.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+}
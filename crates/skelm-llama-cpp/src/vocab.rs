@@ -2,7 +2,7 @@ use std::{ffi::c_char, ptr::null_mut, sync::Arc};
 
 use skelm_llama_cpp_sys::llama::{self, llama_token_attr};
 
-use crate::{token::Token, Model};
+use crate::{Model, token::Token};
 
 #[derive(Clone)]
 #[allow(dead_code)]
@@ -77,20 +77,46 @@ impl TokenAttr {
 
 impl Vocab {
     pub fn tokenize_size(&self, bytes: &[u8], first: bool) -> usize {
+        self.tokenize_count(bytes, first, true)
+    }
+
+    pub fn tokenize(&self, bytes: &[u8], first: bool) -> Vec<Token> {
+        self.tokenize_with(bytes, first, true)
+    }
+
+    /// Number of tokens of `bytes`, without allocating them
+    ///
+    /// `add_special` adds the BOS/EOS tokens the model expects around a sequence, and
+    /// `parse_special` turns the text of special tokens (e.g. `<|im_start|>`) into these
+    /// tokens instead of tokenizing it as plain text.
+    pub fn tokenize_count(&self, bytes: &[u8], add_special: bool, parse_special: bool) -> usize {
         let content_len = <i32>::try_from(bytes.len()).unwrap();
         let content = bytes.as_ptr() as *const c_char;
 
-        unsafe {
-            -llama::llama_tokenize(self.ptr.0, content, content_len, null_mut(), 0, first, true)
-                as usize
-        }
+        let n = unsafe {
+            llama::llama_tokenize(
+                self.ptr.0,
+                content,
+                content_len,
+                null_mut(),
+                0,
+                add_special,
+                parse_special,
+            )
+        };
+        n.unsigned_abs() as usize
     }
 
-    pub fn tokenize(&self, bytes: &[u8], first: bool) -> Vec<Token> {
+    pub fn tokenize_with(
+        &self,
+        bytes: &[u8],
+        add_special: bool,
+        parse_special: bool,
+    ) -> Vec<Token> {
         let content_len = <i32>::try_from(bytes.len()).unwrap();
         let content = bytes.as_ptr() as *const c_char;
 
-        let size = self.tokenize_size(bytes, first);
+        let size = self.tokenize_count(bytes, add_special, parse_special);
         let mut out = Vec::with_capacity(size);
 
         let out_ptr = out.as_mut_ptr() as *mut llama::llama_token;
@@ -102,11 +128,10 @@ impl Vocab {
                 content_len,
                 out_ptr,
                 size as i32,
-                first,
-                true,
+                add_special,
+                parse_special,
             )
         };
-        //println!("{}", n);
         assert_eq!(n as usize, size);
         unsafe {
             out.set_len(size);
@@ -114,6 +139,52 @@ impl Vocab {
         out
     }
 
+    /// Convert tokens back to text
+    ///
+    /// The bytes of all the tokens are joined before being decoded, so characters split
+    /// across tokens are kept; only invalid sequences are replaced.
+    pub fn detokenize(&self, tokens: &[Token]) -> String {
+        String::from_utf8_lossy(&self.detokenize_bytes(tokens, false, true)).into_owned()
+    }
+
+    /// Convert tokens back to bytes, optionally removing the BOS/EOS tokens added by the
+    /// tokenizer and rendering the text of special tokens
+    pub fn detokenize_bytes(
+        &self,
+        tokens: &[Token],
+        remove_special: bool,
+        unparse_special: bool,
+    ) -> Vec<u8> {
+        let n_tokens = <i32>::try_from(tokens.len()).unwrap();
+        let tokens_ptr = tokens.as_ptr() as *const llama::llama_token;
+        let detokenize = |buf: &mut Vec<u8>| unsafe {
+            llama::llama_detokenize(
+                self.ptr.0,
+                tokens_ptr,
+                n_tokens,
+                buf.as_mut_ptr() as *mut c_char,
+                <i32>::try_from(buf.len()).unwrap_or(i32::MAX),
+                remove_special,
+                unparse_special,
+            )
+        };
+
+        let mut buf = vec![0u8; tokens.len() * 4 + 16];
+        let mut n = detokenize(&mut buf);
+        if n < 0 {
+            buf.resize(n.unsigned_abs() as usize, 0);
+            n = detokenize(&mut buf);
+        }
+        assert!(n >= 0, "failed to detokenize");
+        buf.truncate(n as usize);
+        buf
+    }
+
+    /// The token of a given id, if it's part of the vocabulary
+    pub fn token(&self, id: u32) -> Option<Token> {
+        (id < self.n_tokens()).then_some(Token(id as i32))
+    }
+
     pub fn n_tokens(&self) -> u32 {
         unsafe { llama::llama_vocab_n_tokens(self.ptr.0) as u32 }
     }
@@ -123,19 +194,24 @@ impl Vocab {
     }
 
     pub fn as_bytes(&self, token: Token) -> Vec<u8> {
-        let mut buf = vec![0u8; 256];
-        let buf_ptr = buf.as_mut_ptr();
-        let n = unsafe {
+        let to_piece = |buf: &mut Vec<u8>| unsafe {
             llama::llama_token_to_piece(
                 self.ptr.0,
                 token.0,
-                buf_ptr as *mut c_char,
+                buf.as_mut_ptr() as *mut c_char,
                 buf.len() as i32,
                 0,
                 true,
             )
         };
 
+        // most pieces are short, the longer ones report the size they need
+        let mut buf = vec![0u8; 64];
+        let mut n = to_piece(&mut buf);
+        if n < 0 {
+            buf.resize(n.unsigned_abs() as usize, 0);
+            n = to_piece(&mut buf);
+        }
         if n < 0 {
             panic!("failed to convert token to piece")
         }
@@ -0,0 +1,312 @@
+//! Export and import of models as OCI image layout tarballs
+//!
+//! The archive contains the `oci-layout` marker, an `index.json` pointing at one
+//! manifest per model, and every blob (manifest, config and layers) stored under
+//! `blobs/sha256/<hex>`.
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::storage::*;
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const OCI_INDEX_FILE: &str = "index.json";
+const OCI_BLOBS_DIR: &str = "blobs/sha256/";
+const OCI_LAYOUT_VERSION: &str = "1.0.0";
+const OCI_MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciLayout {
+    image_layout_version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciIndex {
+    schema_version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciDescriptor {
+    media_type: String,
+    digest: Blob,
+    size: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("I/O error {0}")]
+    IO(#[from] std::io::Error),
+    #[error("JSON error {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("archive is not an OCI image layout: {0}")]
+    InvalidLayout(String),
+    #[error("blob {0} in archive has content with digest {1}")]
+    DigestMismatch(Blob, Blob),
+    #[error("blob {0} is missing from the archive")]
+    MissingBlob(Blob),
+    #[error("manifest {0} has no valid model name: {1}")]
+    InvalidName(Blob, String),
+}
+
+/// Write the manifest and all the blobs of a model as an OCI image layout tarball
+pub fn export_model<W: Write>(
+    store: &OllamaStore,
+    descr: &ModelDescr,
+    writer: W,
+) -> Result<(), ArchiveError> {
+    let manifest = store.get_manifest(descr)?;
+    let manifest_data = serde_json::to_vec(&manifest)?;
+    let manifest_blob = {
+        let mut ctx = BlobContext::new_sha256();
+        ctx.update(&manifest_data);
+        ctx.finalize()
+    };
+
+    let layout = serde_json::to_vec(&OciLayout {
+        image_layout_version: OCI_LAYOUT_VERSION.to_string(),
+    })?;
+    let index = serde_json::to_vec(&OciIndex {
+        schema_version: 2,
+        media_type: Some(OCI_MEDIA_TYPE_INDEX.to_string()),
+        manifests: vec![OciDescriptor {
+            media_type: manifest.media_type.clone(),
+            digest: manifest_blob.clone(),
+            size: manifest_data.len() as u64,
+            annotations: HashMap::from([(ANNOTATION_REF_NAME.to_string(), descr.to_string())]),
+        }],
+    })?;
+
+    let mut builder = tar::Builder::new(writer);
+    append_bytes(&mut builder, OCI_LAYOUT_FILE, &layout)?;
+    append_bytes(&mut builder, OCI_INDEX_FILE, &index)?;
+    append_bytes(
+        &mut builder,
+        &blob_entry_path(&manifest_blob),
+        &manifest_data,
+    )?;
+
+    let mut written = Vec::new();
+    for blob in manifest.all_digests() {
+        if written.contains(&blob) {
+            continue;
+        }
+        let file = std::fs::File::open(store.blob_path(&blob))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(file.metadata()?.len());
+        header.set_mode(0o644);
+        builder.append_data(&mut header, blob_entry_path(&blob), file)?;
+        written.push(blob);
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn append_bytes<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, path, data)
+}
+
+fn blob_entry_path(blob: &Blob) -> String {
+    match blob {
+        Blob::Sha256(h) => format!("{}{}", OCI_BLOBS_DIR, hex::encode(h)),
+    }
+}
+
+/// Import all the models of an OCI image layout tarball into the store
+///
+/// Every blob is hashed while being copied and rejected if it doesn't match its name.
+/// `rename` replaces the name recorded in the archive, and is only valid for
+/// archives containing a single model.
+pub fn import_models<R: Read>(
+    store: &OllamaStore,
+    reader: R,
+    rename: Option<&ModelDescr>,
+) -> Result<Vec<ModelDescr>, ArchiveError> {
+    let mut archive = tar::Archive::new(reader);
+
+    let mut layout = None;
+    let mut index = None;
+    let mut imported = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let path = path.trim_start_matches("./");
+
+        if path == OCI_LAYOUT_FILE {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            layout = Some(serde_json::from_slice::<OciLayout>(&data)?);
+        } else if path == OCI_INDEX_FILE {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            index = Some(serde_json::from_slice::<OciIndex>(&data)?);
+        } else if let Some(hex) = path.strip_prefix(OCI_BLOBS_DIR) {
+            let Ok(expected) = Blob::from_str(&format!("sha256:{}", hex)) else {
+                return Err(ArchiveError::InvalidLayout(format!(
+                    "invalid blob {}",
+                    path
+                )));
+            };
+            import_blob(store, &expected, &mut entry)?;
+            imported.push(expected);
+        }
+    }
+
+    let Some(layout) = layout else {
+        return Err(ArchiveError::InvalidLayout("no oci-layout".to_string()));
+    };
+    if layout.image_layout_version != OCI_LAYOUT_VERSION {
+        return Err(ArchiveError::InvalidLayout(format!(
+            "unsupported version {}",
+            layout.image_layout_version
+        )));
+    }
+    let Some(index) = index else {
+        return Err(ArchiveError::InvalidLayout("no index.json".to_string()));
+    };
+    if rename.is_some() && index.manifests.len() != 1 {
+        return Err(ArchiveError::InvalidLayout(
+            "cannot rename an archive with multiple models".to_string(),
+        ));
+    }
+
+    let mut models = Vec::new();
+    for descriptor in index.manifests {
+        if !imported.contains(&descriptor.digest) {
+            return Err(ArchiveError::MissingBlob(descriptor.digest));
+        }
+        let manifest_data = store.blob_read(&descriptor.digest)?;
+        // manifests are not kept as blobs in the store
+        std::fs::remove_file(store.blob_path(&descriptor.digest))?;
+        let manifest = Manifest::from_json_bytes(&manifest_data)?;
+
+        for blob in manifest.all_digests() {
+            if !store.blob_exists(&blob) {
+                return Err(ArchiveError::MissingBlob(blob));
+            }
+        }
+
+        let descr = match rename {
+            Some(descr) => descr.clone(),
+            None => {
+                let name = descriptor
+                    .annotations
+                    .get(ANNOTATION_REF_NAME)
+                    .cloned()
+                    .unwrap_or_default();
+                ModelDescr::from_str(&name)
+                    .map_err(|e| ArchiveError::InvalidName(descriptor.digest.clone(), e))?
+            }
+        };
+        store.add_manifest(&descr.registry, &descr.model, &descr.variant, &manifest)?;
+        models.push(descr);
+    }
+    Ok(models)
+}
+
+/// Copy a blob from the archive into the store, or only check it when already present
+fn import_blob<R: Read>(
+    store: &OllamaStore,
+    expected: &Blob,
+    reader: &mut R,
+) -> Result<(), ArchiveError> {
+    let got = if store.blob_exists(expected) {
+        let mut ctx = BlobContext::new_from_blob_type(expected);
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            ctx.update(&buf[0..n]);
+        }
+        ctx.finalize()
+    } else {
+        store.add_blob_from_reader_expected(reader, expected)?
+    };
+
+    if &got != expected {
+        return Err(ArchiveError::DigestMismatch(expected.clone(), got));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_store;
+
+    #[test]
+    fn export_import_roundtrip() {
+        let (src, _src_dir) = temp_store();
+        let (dst, _dst_dir) = temp_store();
+
+        let model = src.add_blob_from_bytes(b"GGUF model data").unwrap();
+        let config = src.add_blob_from_bytes(b"{}").unwrap();
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: MEDIA_TYPE_DOCKER_DISTRIBUTION_MANIFEST.to_string(),
+            config: ManifestConfig {
+                media_type: MEDIA_TYPE_DOCKER_CONTAINER_IMAGE.to_string(),
+                digest: config,
+                size: 2,
+            },
+            layers: vec![ManifestLayer {
+                media_type: MEDIA_TYPE_IMAGE_MODEL.to_string(),
+                digest: model.clone(),
+                size: 15,
+                from: None,
+            }],
+        };
+        let descr = ModelDescr::from_str("test:v1").unwrap();
+        src.add_manifest(&descr.registry, &descr.model, &descr.variant, &manifest)
+            .unwrap();
+
+        let mut archive = Vec::new();
+        export_model(&src, &descr, &mut archive).unwrap();
+
+        let models = import_models(&dst, archive.as_slice(), None).unwrap();
+        assert_eq!(models, vec![descr.clone()]);
+        assert_eq!(dst.blob_read(&model).unwrap(), b"GGUF model data");
+        assert_eq!(dst.list_blobs().unwrap().len(), 2);
+
+        let renamed = ModelDescr::from_str("other:v2").unwrap();
+        let models = import_models(&dst, archive.as_slice(), Some(&renamed)).unwrap();
+        assert_eq!(models, vec![renamed]);
+    }
+
+    #[test]
+    fn import_mismatching_blob() {
+        let (store, _dir) = temp_store();
+        let expected = Blob::from_str(
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+        )
+        .unwrap();
+        let result = import_blob(&store, &expected, &mut &b"other data"[..]);
+        assert!(matches!(result, Err(ArchiveError::DigestMismatch(..))));
+        // neither the blob nor its temporary file are left behind
+        let blobs = std::fs::read_dir(store.blobs_path()).unwrap();
+        assert_eq!(blobs.count(), 0);
+    }
+}
@@ -0,0 +1,216 @@
+//! Garbage collection of the blob store
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::storage::*;
+
+/// Files that can be removed from the blob store
+#[derive(Clone, Debug, Default)]
+pub struct GcPlan {
+    /// Blobs not referenced by any manifest, with their size
+    pub orphan_blobs: Vec<(Blob, u64)>,
+    /// Leftover temporary files from interrupted downloads or imports, with their size
+    pub stale_temp_files: Vec<(PathBuf, u64)>,
+    /// Blobs modified more recently are kept when applying the plan
+    pub min_age: Duration,
+}
+
+impl GcPlan {
+    pub fn is_empty(&self) -> bool {
+        self.orphan_blobs.is_empty() && self.stale_temp_files.is_empty()
+    }
+
+    /// Total number of bytes that removing all the files would free
+    pub fn reclaimable(&self) -> u64 {
+        self.orphan_blobs.iter().map(|(_, sz)| sz).sum::<u64>()
+            + self.stale_temp_files.iter().map(|(_, sz)| sz).sum::<u64>()
+    }
+}
+
+impl OllamaStore {
+    /// Set of all the blobs referenced by the manifests of the store
+    pub fn referenced_blobs(&self) -> std::io::Result<HashSet<Blob>> {
+        let mut referenced = HashSet::new();
+        for descr in self.list_model_descrs()? {
+            let manifest = self.get_manifest(&descr)?;
+            referenced.extend(manifest.all_digests());
+        }
+        Ok(referenced)
+    }
+
+    /// Find the blobs and temporary files that can be collected
+    ///
+    /// Files modified less than `min_age` ago are left alone, as they might belong to a
+    /// download in progress whose manifest is not written yet.
+    pub fn gc_plan(&self, min_age: Duration) -> std::io::Result<GcPlan> {
+        let referenced = self.referenced_blobs()?;
+
+        let mut plan = GcPlan {
+            min_age,
+            ..Default::default()
+        };
+        for entry in std::fs::read_dir(self.blobs_path())? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            if file_age(&metadata) < min_age {
+                continue;
+            }
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+
+            if name.ends_with(".tmp") {
+                // partial downloads are owned by whoever holds the blob lock
+                if let Some(blob) = partial_owner(&name) {
+                    if self.try_lock_blob(&blob)?.is_none() {
+                        continue;
+                    }
+                }
+                plan.stale_temp_files.push((entry.path(), metadata.len()));
+            } else if let Ok(blob) = Blob::from_path_name(&name) {
+                if !referenced.contains(&blob) {
+                    plan.orphan_blobs.push((blob, metadata.len()));
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Find which of the given blobs can be collected, e.g. the blobs of a removed model
+    pub fn gc_plan_blobs(
+        &self,
+        blobs: impl IntoIterator<Item = Blob>,
+        min_age: Duration,
+    ) -> std::io::Result<GcPlan> {
+        let referenced = self.referenced_blobs()?;
+        let mut plan = GcPlan {
+            min_age,
+            ..Default::default()
+        };
+        for blob in blobs {
+            if referenced.contains(&blob) {
+                continue;
+            }
+            let metadata = match std::fs::metadata(self.blob_path(&blob)) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if file_age(&metadata) >= min_age {
+                plan.orphan_blobs.push((blob, metadata.len()));
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Remove the files of a plan, returning the number of bytes freed
+    ///
+    /// The referenced blobs are computed again before deleting anything, so that a manifest
+    /// added since the plan was made keeps its blobs. The blobs locked by a download, or
+    /// reused by one since the plan was made, which refreshes their modification time,
+    /// are kept as well.
+    pub fn gc_apply(&self, plan: &GcPlan) -> std::io::Result<u64> {
+        let referenced = self.referenced_blobs()?;
+        let mut freed = 0;
+        for (blob, size) in plan.orphan_blobs.iter() {
+            if referenced.contains(blob) {
+                continue;
+            }
+            let Some(_lock) = self.try_lock_blob(blob)? else {
+                continue;
+            };
+            let path = self.blob_path(blob);
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if file_age(&metadata) < plan.min_age {
+                continue;
+            }
+            match std::fs::remove_file(path) {
+                Ok(()) => freed += size,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        for (path, size) in plan.stale_temp_files.iter() {
+            // a download may have started resuming the partial since the plan was made,
+            // its lock is held until the partial is removed
+            let owner = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(partial_owner);
+            let _lock = match owner {
+                Some(blob) => match self.try_lock_blob(&blob)? {
+                    Some(lock) => Some(lock),
+                    None => continue,
+                },
+                None => None,
+            };
+            match std::fs::remove_file(path) {
+                Ok(()) => freed += size,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(freed)
+    }
+}
+
+/// The blob whose partial download is the temporary file `name`
+fn partial_owner(name: &str) -> Option<Blob> {
+    let (blob, _) = name.strip_suffix(".tmp")?.rsplit_once('-')?;
+    Blob::from_path_name(blob).ok()
+}
+
+/// Time since the last modification of a file, zero if unknown
+fn file_age(metadata: &std::fs::Metadata) -> Duration {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_store;
+
+    #[test]
+    fn gc_keeps_locked_and_recent_blobs() {
+        let (store, _dir) = temp_store();
+        let old = Duration::from_secs(48 * 3600);
+        let make_old = |blob: &Blob| {
+            std::fs::File::options()
+                .append(true)
+                .open(store.blob_path(blob))
+                .and_then(|file| file.set_modified(SystemTime::now() - old))
+                .unwrap()
+        };
+        let locked = store.add_blob_from_bytes(b"locked").unwrap();
+        let orphan = store.add_blob_from_bytes(b"orphan").unwrap();
+        let recent = store.add_blob_from_bytes(b"recent").unwrap();
+        make_old(&locked);
+        make_old(&orphan);
+
+        let min_age = Duration::from_secs(3600);
+        let plan = store
+            .gc_plan_blobs([locked.clone(), orphan.clone(), recent.clone()], min_age)
+            .unwrap();
+        assert_eq!(plan.orphan_blobs.len(), 2);
+
+        let _lock = store.lock_blob(&locked).unwrap();
+        assert_eq!(store.gc_apply(&plan).unwrap(), 6);
+        assert!(store.blob_exists(&locked));
+        assert!(!store.blob_exists(&orphan));
+        assert!(store.blob_exists(&recent));
+    }
+}
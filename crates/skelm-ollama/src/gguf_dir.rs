@@ -0,0 +1,247 @@
+//! Store backend for a plain directory of GGUF files
+//!
+//! The models are indexed by a sidecar catalog stored in the directory, which gives a
+//! name to each file and optionally a template and parameters. Files that are not in the
+//! catalog yet are added with a name derived from their file name.
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{run::*, storage::*, store::*};
+
+pub const GGUF_CATALOG_FILE: &str = "llmup-catalog.json";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GgufCatalog {
+    pub models: Vec<GgufCatalogEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GgufCatalogEntry {
+    /// name of the model, e.g. `mistral:7b`
+    pub name: String,
+    /// file name of the model, relative to the directory
+    pub file: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub params: serde_json::Value,
+}
+
+pub struct GgufDirStore {
+    path: PathBuf,
+}
+
+pub(crate) fn has_gguf_files(path: &Path) -> bool {
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .any(|e| is_gguf_file_name(&e.file_name().to_string_lossy()))
+        })
+        .unwrap_or(false)
+}
+
+fn is_gguf_file_name(name: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(".gguf")
+}
+
+/// Name of a model from its file name, e.g. `Mistral-7B.Q4_K_M.gguf` gives `mistral-7b.q4_k_m:latest`
+fn implicit_name(file: &str) -> Option<ModelDescr> {
+    let stem = &file[..file.len() - ".gguf".len()];
+    let mut name = stem
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '_' | '.' | '-' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect::<String>();
+    name = name
+        .trim_start_matches(|c: char| !c.is_ascii_alphanumeric())
+        .to_string();
+    ModelDescr::from_str(&format!("{}:latest", name)).ok()
+}
+
+impl GgufDirStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if !path.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("GGUF directory {} does not exist", path.display()),
+            ));
+        }
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn catalog_path(&self) -> PathBuf {
+        self.path.join(GGUF_CATALOG_FILE)
+    }
+
+    /// Read the catalog as stored, without looking at the files of the directory
+    pub fn read_catalog(&self) -> std::io::Result<GgufCatalog> {
+        match std::fs::read(self.catalog_path()) {
+            Ok(data) => serde_json::from_slice(&data).map_err(std::io::Error::other),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(GgufCatalog::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write_catalog(&self, catalog: &GgufCatalog) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(catalog).map_err(std::io::Error::other)?;
+        let tmp = self
+            .path
+            .join(format!("{}.{}.tmp", GGUF_CATALOG_FILE, ulid::Ulid::new()));
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, self.catalog_path())
+    }
+
+    /// Synchronize the catalog with the files of the directory
+    ///
+    /// Entries whose file disappeared are dropped and new GGUF files get an entry. The
+    /// catalog is only written when it changed and the directory is writable.
+    pub fn index(&self) -> std::io::Result<GgufCatalog> {
+        let mut catalog = self.read_catalog()?;
+        let before = catalog.clone();
+
+        catalog
+            .models
+            .retain(|entry| self.path.join(&entry.file).is_file());
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if is_gguf_file_name(&name) && entry.file_type()?.is_file() {
+                files.push(name);
+            }
+        }
+        files.sort();
+
+        for file in files {
+            if catalog.models.iter().any(|entry| entry.file == file) {
+                continue;
+            }
+            let Some(descr) = implicit_name(&file) else {
+                continue;
+            };
+            let mut name = descr.to_string();
+            let mut n = 2;
+            while catalog.models.iter().any(|entry| entry.name == name) {
+                name = format!("{}-{}:{}", descr.model.as_str(), n, descr.variant.as_str());
+                n += 1;
+            }
+            catalog.models.push(GgufCatalogEntry {
+                name,
+                file,
+                template: None,
+                params: serde_json::Value::Null,
+            });
+        }
+
+        // the catalog can be rebuilt each time from a read-only directory
+        if catalog != before {
+            match self.write_catalog(&catalog) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::PermissionDenied
+                            | std::io::ErrorKind::ReadOnlyFilesystem
+                    ) => {}
+                result => result?,
+            }
+        }
+        Ok(catalog)
+    }
+
+    fn find_entry(&self, descr: &ModelDescr) -> std::io::Result<Option<GgufCatalogEntry>> {
+        Ok(self.index()?.models.into_iter().find(|entry| {
+            ModelDescr::from_str(&entry.name).is_ok_and(|entry_descr| &entry_descr == descr)
+        }))
+    }
+
+    fn entry_path(&self, descr: &ModelDescr) -> std::io::Result<PathBuf> {
+        match self.find_entry(descr)? {
+            Some(entry) => Ok(self.path.join(entry.file)),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("model {} not in catalog", descr),
+            )),
+        }
+    }
+}
+
+impl ModelStore for GgufDirStore {
+    fn list_model_descrs(&self) -> std::io::Result<Vec<ModelDescr>> {
+        Ok(self
+            .index()?
+            .models
+            .iter()
+            .filter_map(|entry| ModelDescr::from_str(&entry.name).ok())
+            .collect())
+    }
+
+    fn model_config(&self, descr: &ModelDescr) -> Result<ModelConfig, ModelConfigGetError> {
+        let entry = self
+            .find_entry(descr)
+            .map_err(|e| ModelConfigGetError::StoreError(e, descr.clone()))?
+            .ok_or_else(|| ModelConfigGetError::NotFound(descr.clone()))?;
+        let params = match entry.params {
+            serde_json::Value::Null => serde_json::Value::Object(Default::default()),
+            params => params,
+        };
+        Ok(ModelConfig {
+            model_path: self.path.join(entry.file),
+            projector_path: None,
+            adapter_paths: Vec::new(),
+            template: entry.template,
+            params,
+        })
+    }
+
+    fn model_size(&self, descr: &ModelDescr) -> std::io::Result<u64> {
+        Ok(std::fs::metadata(self.entry_path(descr)?)?.len())
+    }
+
+    fn model_modified(&self, descr: &ModelDescr) -> std::io::Result<SystemTime> {
+        std::fs::metadata(self.entry_path(descr)?)?.modified()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn gguf_dir_index() {
+        let dir = TempDir::new();
+        let path = dir.path();
+        std::fs::write(path.join("Mistral-7B.Q4_K_M.gguf"), b"GGUF").unwrap();
+        std::fs::write(path.join("notes.txt"), b"").unwrap();
+
+        let store = GgufDirStore::new(path).unwrap();
+        let descrs = ModelStore::list_model_descrs(&store).unwrap();
+        assert_eq!(
+            descrs,
+            vec![ModelDescr::from_str("mistral-7b.q4_k_m:latest").unwrap()]
+        );
+        let config = store.model_config(&descrs[0]).unwrap();
+        assert_eq!(config.model_path, path.join("Mistral-7B.Q4_K_M.gguf"));
+        assert_eq!(store.model_size(&descrs[0]).unwrap(), 4);
+
+        std::fs::remove_file(path.join("Mistral-7B.Q4_K_M.gguf")).unwrap();
+        assert!(store.index().unwrap().models.is_empty());
+    }
+}
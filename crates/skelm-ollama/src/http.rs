@@ -20,6 +20,18 @@ impl Default for OllamaConfig {
 }
 
 impl OllamaConfig {
+    /// Configuration to access the registry a model was pulled from
+    pub fn for_registry(registry: &super::Registry) -> Result<Self, url::ParseError> {
+        if registry == &super::Registry::default() {
+            return Ok(Self::default());
+        }
+        let base_url = Url::parse(&format!("https://{}/", registry.as_str()))?;
+        Ok(Self {
+            base_url,
+            version: String::from(VERSION),
+        })
+    }
+
     pub fn host(&self) -> String {
         format!(
             "{}",
@@ -49,4 +61,22 @@ impl OllamaConfig {
             ))
             .unwrap()
     }
+
+    /// URL listing the tags of a model, as `{"name": ..., "tags": [...]}`
+    pub fn tags_url(&self, model: &super::Model) -> Url {
+        self.base_url
+            .join(&format!(
+                "{}/library/{}/tags/list",
+                &self.version,
+                model.as_str()
+            ))
+            .unwrap()
+    }
+
+    /// URL listing the repositories of the registry, as `{"repositories": [...]}`
+    pub fn catalog_url(&self) -> Url {
+        self.base_url
+            .join(&format!("{}/_catalog", &self.version))
+            .unwrap()
+    }
 }
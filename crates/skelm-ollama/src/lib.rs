@@ -1,7 +1,23 @@
+mod archive;
+mod gc;
+mod gguf_dir;
 mod http;
+mod lock;
+mod modelfile;
 mod run;
 mod storage;
+mod store;
+#[cfg(test)]
+mod test_util;
+mod verify;
 
+pub use archive::*;
+pub use gc::*;
+pub use gguf_dir::*;
 pub use http::*;
+pub use lock::*;
+pub use modelfile::*;
 pub use run::*;
 pub use storage::*;
+pub use store::*;
+pub use verify::*;
@@ -0,0 +1,111 @@
+//! Advisory file locks for concurrent access to the store
+//!
+//! Lock files live under `locks/` in the models directory, one per blob and one per
+//! manifest, so that multiple processes (e.g. parallel pulls sharing a cache) can
+//! download the same blob or write the same manifest without corrupting each other.
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use fs4::fs_std::FileExt;
+
+use crate::storage::*;
+
+/// A held advisory lock, released when dropped
+pub struct StoreLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl StoreLock {
+    fn open(path: &Path) -> std::io::Result<File> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+    }
+
+    /// Take the exclusive lock, waiting for other holders to release it
+    pub fn acquire(path: PathBuf) -> std::io::Result<Self> {
+        let file = Self::open(&path)?;
+        FileExt::lock_exclusive(&file)?;
+        Ok(Self { file, path })
+    }
+
+    /// Take the exclusive lock, or return `None` if it is held by someone else
+    pub fn try_acquire(path: PathBuf) -> std::io::Result<Option<Self>> {
+        let file = Self::open(&path)?;
+        match FileExt::try_lock_exclusive(&file)? {
+            true => Ok(Some(Self { file, path })),
+            false => Ok(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+impl OllamaStore {
+    fn locks_path(&self) -> PathBuf {
+        self.path().join("locks")
+    }
+
+    fn blob_lock_path(&self, blob: &Blob) -> PathBuf {
+        self.locks_path().join("blobs").join(blob.as_path_name())
+    }
+
+    fn manifest_lock_path(&self, registry: &Registry, model: &Model, variant: &Variant) -> PathBuf {
+        self.locks_path()
+            .join("manifests")
+            .join(registry.as_str())
+            .join(model.as_str())
+            .join(variant.as_str())
+    }
+
+    /// Lock a blob for writing, waiting for any other writer to finish
+    pub fn lock_blob(&self, blob: &Blob) -> std::io::Result<StoreLock> {
+        StoreLock::acquire(self.blob_lock_path(blob))
+    }
+
+    pub fn try_lock_blob(&self, blob: &Blob) -> std::io::Result<Option<StoreLock>> {
+        StoreLock::try_acquire(self.blob_lock_path(blob))
+    }
+
+    /// Lock a manifest for writing, waiting for any other writer to finish
+    pub fn lock_manifest(
+        &self,
+        registry: &Registry,
+        model: &Model,
+        variant: &Variant,
+    ) -> std::io::Result<StoreLock> {
+        StoreLock::acquire(self.manifest_lock_path(registry, model, variant))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::temp_store;
+
+    #[test]
+    fn blob_lock_exclusive() {
+        let (store, _dir) = temp_store();
+        let blob = store.add_blob_from_bytes(b"data").unwrap();
+
+        let lock = store.lock_blob(&blob).unwrap();
+        assert!(store.try_lock_blob(&blob).unwrap().is_none());
+        drop(lock);
+        assert!(store.try_lock_blob(&blob).unwrap().is_some());
+    }
+}
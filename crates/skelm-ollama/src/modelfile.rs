@@ -0,0 +1,708 @@
+//! Modelfile parsing, rendering and model creation
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::run::Role;
+use crate::storage::*;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Modelfile {
+    pub from: String,
+    pub parameters: Vec<(String, String)>,
+    pub template: Option<String>,
+    pub system: Option<String>,
+    pub licenses: Vec<String>,
+    pub adapters: Vec<String>,
+    pub messages: Vec<(Role, String)>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ModelfileParseError {
+    #[error("line {0}: unknown command {1}")]
+    UnknownCommand(usize, String),
+    #[error("line {0}: missing argument for {1}")]
+    MissingArgument(usize, String),
+    #[error("line {0}: unterminated string")]
+    UnterminatedString(usize),
+    #[error("line {0}: invalid message role {1}")]
+    InvalidRole(usize, String),
+    #[error("line {0}: FROM specified multiple times")]
+    DuplicateFrom(usize),
+    #[error("no FROM command")]
+    MissingFrom,
+}
+
+impl FromStr for Modelfile {
+    type Err = ModelfileParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modelfile = Modelfile::default();
+        let mut from = None;
+        let mut lines = s.lines().enumerate().map(|(i, l)| (i + 1, l));
+
+        while let Some((lineno, line)) = lines.next() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (command, rest) = split_word(line);
+            let command = command.to_ascii_uppercase();
+            if rest.is_empty() {
+                return Err(ModelfileParseError::MissingArgument(lineno, command));
+            }
+
+            match command.as_str() {
+                "FROM" => {
+                    if from.is_some() {
+                        return Err(ModelfileParseError::DuplicateFrom(lineno));
+                    }
+                    from = Some(parse_value(lineno, rest, &mut lines)?);
+                }
+                "PARAMETER" => {
+                    let (key, value) = split_word(rest);
+                    if value.is_empty() {
+                        return Err(ModelfileParseError::MissingArgument(lineno, command));
+                    }
+                    let value = parse_value(lineno, value, &mut lines)?;
+                    modelfile.parameters.push((key.to_string(), value))
+                }
+                "MESSAGE" => {
+                    let (role, content) = split_word(rest);
+                    let Ok(role) = Role::from_str(role) else {
+                        return Err(ModelfileParseError::InvalidRole(lineno, role.to_string()));
+                    };
+                    let content = parse_value(lineno, content, &mut lines)?;
+                    modelfile.messages.push((role, content))
+                }
+                "TEMPLATE" => modelfile.template = Some(parse_value(lineno, rest, &mut lines)?),
+                "SYSTEM" => modelfile.system = Some(parse_value(lineno, rest, &mut lines)?),
+                "LICENSE" => modelfile
+                    .licenses
+                    .push(parse_value(lineno, rest, &mut lines)?),
+                "ADAPTER" => modelfile
+                    .adapters
+                    .push(parse_value(lineno, rest, &mut lines)?),
+                _ => return Err(ModelfileParseError::UnknownCommand(lineno, command)),
+            }
+        }
+
+        let Some(from) = from else {
+            return Err(ModelfileParseError::MissingFrom);
+        };
+        modelfile.from = from;
+        Ok(modelfile)
+    }
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    match s.split_once(char::is_whitespace) {
+        None => (s, ""),
+        Some((word, rest)) => (word, rest.trim_start()),
+    }
+}
+
+/// Parse a command argument, which is either a bare value, a double-quoted string,
+/// or a triple-quoted string that can span multiple lines
+fn parse_value<'a>(
+    lineno: usize,
+    value: &str,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Result<String, ModelfileParseError> {
+    const TRIPLE: &str = "\"\"\"";
+
+    let value = value.trim();
+    if let Some(start) = value.strip_prefix(TRIPLE) {
+        if let Some((content, _)) = start.split_once(TRIPLE) {
+            return Ok(content.to_string());
+        }
+        let mut content = start.to_string();
+        for (_, line) in lines.by_ref() {
+            content.push('\n');
+            if let Some((end, _)) = line.split_once(TRIPLE) {
+                content.push_str(end);
+                return Ok(content);
+            }
+            content.push_str(line);
+        }
+        Err(ModelfileParseError::UnterminatedString(lineno))
+    } else if let Some(start) = value.strip_prefix('"') {
+        let Some(inner) = start.strip_suffix('"') else {
+            return Err(ModelfileParseError::UnterminatedString(lineno));
+        };
+        let mut out = String::with_capacity(inner.len());
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some(c) => out.push(c),
+                    None => out.push('\\'),
+                },
+                c => out.push(c),
+            }
+        }
+        Ok(out)
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+fn quote_value(value: &str) -> String {
+    if value.contains('\n') {
+        quote_text(value)
+    } else if value.is_empty()
+        || value.trim() != value
+        || value.contains('"')
+        || value.contains('\\')
+        || value.starts_with('#')
+    {
+        escape_value(value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Quote a text as a triple-quoted string when it reads back unchanged, escaping it as a
+/// double-quoted string otherwise
+fn quote_text(value: &str) -> String {
+    const TRIPLE: &str = "\"\"\"";
+    // the end of the string is the first triple quote, and the line of the command is
+    // trimmed
+    let first_line = value.split('\n').next().unwrap_or_default();
+    if value.contains(TRIPLE)
+        || value.ends_with('"')
+        || value.contains('\r')
+        || first_line.trim_end() != first_line
+    {
+        escape_value(value)
+    } else {
+        format!("{}{}{}", TRIPLE, value, TRIPLE)
+    }
+}
+
+fn escape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Display for Modelfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "FROM {}", quote_value(&self.from))?;
+        for adapter in &self.adapters {
+            writeln!(f, "ADAPTER {}", quote_value(adapter))?;
+        }
+        if let Some(template) = &self.template {
+            writeln!(f, "TEMPLATE {}", quote_text(template))?;
+        }
+        if let Some(system) = &self.system {
+            writeln!(f, "SYSTEM {}", quote_text(system))?;
+        }
+        for (key, value) in &self.parameters {
+            writeln!(f, "PARAMETER {} {}", key, quote_value(value))?;
+        }
+        for (role, content) in &self.messages {
+            writeln!(f, "MESSAGE {} {}", role.as_str(), quote_value(content))?;
+        }
+        for license in &self.licenses {
+            writeln!(f, "LICENSE {}", quote_text(license))?;
+        }
+        Ok(())
+    }
+}
+
+/// The type of the value of the parameters known by Ollama
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParameterType {
+    Int,
+    Float,
+    Bool,
+    /// always stored as a list, even when specified once
+    StringList,
+}
+
+fn parameter_type(key: &str) -> Option<ParameterType> {
+    use ParameterType::*;
+    let ty = match key {
+        "num_keep" | "seed" | "num_predict" | "top_k" | "num_ctx" | "num_batch" | "num_gpu"
+        | "main_gpu" | "num_thread" | "repeat_last_n" | "mirostat" => Int,
+        "temperature" | "top_p" | "min_p" | "typical_p" | "repeat_penalty" | "presence_penalty"
+        | "frequency_penalty" | "mirostat_tau" | "mirostat_eta" | "tfs_z" => Float,
+        "penalize_newline" | "numa" | "low_vram" | "f16_kv" | "vocab_only" | "use_mmap"
+        | "use_mlock" => Bool,
+        "stop" => StringList,
+        _ => return None,
+    };
+    Some(ty)
+}
+
+/// The JSON value of a parameter, typed by its name, or guessed from the value for the
+/// unknown parameters
+///
+/// Values that are invalid for their type are kept as strings.
+fn parameter_value(key: &str, value: &str) -> serde_json::Value {
+    use serde_json::Value;
+
+    let int = || i64::from_str(value).ok().map(Value::from);
+    let float = || {
+        f64::from_str(value)
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+    };
+    let bool = || bool::from_str(value).ok().map(Value::Bool);
+    let typed = match parameter_type(key) {
+        Some(ParameterType::Int) => int(),
+        Some(ParameterType::Float) => float(),
+        Some(ParameterType::Bool) => bool(),
+        Some(ParameterType::StringList) => None,
+        None => bool().or_else(int).or_else(float),
+    };
+    typed.unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+impl Modelfile {
+    /// Convert the PARAMETER commands to the JSON object stored in the params layer
+    pub fn parameters_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        use serde_json::Value;
+
+        let mut map = serde_json::Map::new();
+        for (key, value) in &self.parameters {
+            let value = parameter_value(key, value);
+
+            match map.get_mut(key) {
+                Some(Value::Array(values)) => values.push(value),
+                Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+                None if parameter_type(key) == Some(ParameterType::StringList) => {
+                    map.insert(key.clone(), Value::Array(vec![value]));
+                }
+                None => {
+                    map.insert(key.clone(), value);
+                }
+            }
+        }
+        map
+    }
+
+    /// Reconstruct a Modelfile from the layers of an existing manifest
+    pub fn from_manifest(store: &OllamaStore, manifest: &Manifest) -> std::io::Result<Self> {
+        let mut modelfile = Modelfile::default();
+        for layer in &manifest.layers {
+            match layer.media_type.as_str() {
+                MEDIA_TYPE_IMAGE_MODEL => {
+                    modelfile.from = store.blob_path(&layer.digest).display().to_string()
+                }
+                MEDIA_TYPE_IMAGE_ADAPTER => modelfile
+                    .adapters
+                    .push(store.blob_path(&layer.digest).display().to_string()),
+                MEDIA_TYPE_IMAGE_TEMPLATE => {
+                    modelfile.template = Some(store.blob_read_string(&layer.digest)?)
+                }
+                MEDIA_TYPE_IMAGE_SYSTEM => {
+                    modelfile.system = Some(store.blob_read_string(&layer.digest)?)
+                }
+                MEDIA_TYPE_IMAGE_LICENSE => modelfile
+                    .licenses
+                    .push(store.blob_read_string(&layer.digest)?),
+                MEDIA_TYPE_IMAGE_PARAMS => {
+                    let params = store.blob_read_string(&layer.digest)?;
+                    let params: serde_json::Map<String, serde_json::Value> =
+                        serde_json::from_str(&params).map_err(json_io_error)?;
+                    for (key, value) in params {
+                        let values = match value {
+                            serde_json::Value::Array(values) => values,
+                            value => vec![value],
+                        };
+                        for value in values {
+                            let value = match value {
+                                serde_json::Value::String(s) => s,
+                                value => value.to_string(),
+                            };
+                            modelfile.parameters.push((key.clone(), value))
+                        }
+                    }
+                }
+                MEDIA_TYPE_IMAGE_MESSAGES => {
+                    let messages = store.blob_read_string(&layer.digest)?;
+                    let messages: Vec<ModelfileMessage> =
+                        serde_json::from_str(&messages).map_err(json_io_error)?;
+                    for message in messages {
+                        let Ok(role) = Role::from_str(&message.role) else {
+                            continue;
+                        };
+                        modelfile.messages.push((role, message.content))
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(modelfile)
+    }
+}
+
+fn json_io_error(e: serde_json::Error) -> std::io::Error {
+    std::io::Error::other(format!("json invalid: {}", e))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ModelfileMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Error, Debug)]
+pub enum ModelfileCreateError {
+    #[error("I/O error {0} for {1}")]
+    IO(std::io::Error, PathBuf),
+    #[error("FROM {0} is neither a file nor a valid model name")]
+    InvalidFrom(String),
+    #[error("base model {0} cannot be read: {1}")]
+    BaseModel(ModelDescr, std::io::Error),
+    #[error("base model {0} has no model layer")]
+    BaseModelImageNotFound(ModelDescr),
+    #[error("error writing blob {0}")]
+    BlobWrite(std::io::Error),
+}
+
+/// Create all the blobs described by a modelfile and return the resulting manifest
+///
+/// Relative paths in FROM and ADAPTER are resolved from `base_dir`. The manifest is
+/// not registered in the store, this is left to the caller with `add_manifest`.
+pub fn modelfile_create(
+    store: &OllamaStore,
+    modelfile: &Modelfile,
+    base_dir: &Path,
+) -> Result<Manifest, ModelfileCreateError> {
+    let from_path = base_dir.join(&modelfile.from);
+
+    let (mut layers, config, mut params) = if from_path.is_file() {
+        let layer = layer_from_file(store, &from_path, MEDIA_TYPE_IMAGE_MODEL)?;
+        let config = ImageConfig {
+            model_format: "gguf".to_string(),
+            ..ImageConfig::default()
+        };
+        (vec![layer], config, serde_json::Map::new())
+    } else {
+        let Ok(descr) = ModelDescr::from_str(&modelfile.from) else {
+            return Err(ModelfileCreateError::InvalidFrom(modelfile.from.clone()));
+        };
+        let base = store
+            .get_manifest(&descr)
+            .map_err(|e| ModelfileCreateError::BaseModel(descr.clone(), e))?;
+        if base.find_media_type(MEDIA_TYPE_IMAGE_MODEL).is_none() {
+            return Err(ModelfileCreateError::BaseModelImageNotFound(descr));
+        }
+        let config = store.get_image_config(&base).unwrap_or_default();
+        let params = match base.find_media_type(MEDIA_TYPE_IMAGE_PARAMS) {
+            None => serde_json::Map::new(),
+            Some(layer) => store
+                .blob_read_string(&layer.digest)
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_default(),
+        };
+        let from = descr.to_string();
+        let layers = base
+            .layers
+            .into_iter()
+            .filter(|l| l.media_type != MEDIA_TYPE_IMAGE_PARAMS)
+            .map(|mut l| {
+                l.from.get_or_insert_with(|| from.clone());
+                l
+            })
+            .collect::<Vec<_>>();
+        (layers, config, params)
+    };
+
+    let mut replace_layers = |media_type: &str, new_layers: Vec<ManifestLayer>| {
+        layers.retain(|l| l.media_type != media_type);
+        layers.extend(new_layers);
+    };
+
+    if !modelfile.adapters.is_empty() {
+        let mut adapters = Vec::new();
+        for adapter in &modelfile.adapters {
+            let path = base_dir.join(adapter);
+            adapters.push(layer_from_file(store, &path, MEDIA_TYPE_IMAGE_ADAPTER)?);
+        }
+        replace_layers(MEDIA_TYPE_IMAGE_ADAPTER, adapters);
+    }
+    if let Some(template) = &modelfile.template {
+        let layer = layer_from_bytes(store, template.as_bytes(), MEDIA_TYPE_IMAGE_TEMPLATE)?;
+        replace_layers(MEDIA_TYPE_IMAGE_TEMPLATE, vec![layer]);
+    }
+    if let Some(system) = &modelfile.system {
+        let layer = layer_from_bytes(store, system.as_bytes(), MEDIA_TYPE_IMAGE_SYSTEM)?;
+        replace_layers(MEDIA_TYPE_IMAGE_SYSTEM, vec![layer]);
+    }
+    if !modelfile.messages.is_empty() {
+        let messages = modelfile
+            .messages
+            .iter()
+            .map(|(role, content)| ModelfileMessage {
+                role: role.as_str().to_string(),
+                content: content.clone(),
+            })
+            .collect::<Vec<_>>();
+        let data = serde_json::to_vec(&messages).unwrap();
+        let layer = layer_from_bytes(store, &data, MEDIA_TYPE_IMAGE_MESSAGES)?;
+        replace_layers(MEDIA_TYPE_IMAGE_MESSAGES, vec![layer]);
+    }
+    if !modelfile.licenses.is_empty() {
+        let mut licenses = Vec::new();
+        for license in &modelfile.licenses {
+            licenses.push(layer_from_bytes(
+                store,
+                license.as_bytes(),
+                MEDIA_TYPE_IMAGE_LICENSE,
+            )?);
+        }
+        replace_layers(MEDIA_TYPE_IMAGE_LICENSE, licenses);
+    }
+
+    params.extend(modelfile.parameters_json());
+    if !params.is_empty() {
+        let data = serde_json::to_vec(&params).unwrap();
+        let layer = layer_from_bytes(store, &data, MEDIA_TYPE_IMAGE_PARAMS)?;
+        layers.push(layer);
+    }
+
+    manifest_from_layers(store, config, layers)
+}
+
+/// Create a manifest identical to the one of `base`, except for its model layer which
+/// is replaced by the file at `model_path`, e.g. a quantized version of the base model
+///
+/// `file_type` is the type of the new weights, as shown in the image config. The
+/// manifest is not registered in the store.
+pub fn replace_model_layer(
+    store: &OllamaStore,
+    base: &ModelDescr,
+    model_path: &Path,
+    file_type: &str,
+) -> Result<Manifest, ModelfileCreateError> {
+    let manifest = store
+        .get_manifest(base)
+        .map_err(|e| ModelfileCreateError::BaseModel(base.clone(), e))?;
+    if manifest.find_media_type(MEDIA_TYPE_IMAGE_MODEL).is_none() {
+        return Err(ModelfileCreateError::BaseModelImageNotFound(base.clone()));
+    }
+    let mut config = store.get_image_config(&manifest).unwrap_or_default();
+    config.file_type = file_type.to_string();
+
+    let model_layer = layer_from_file(store, model_path, MEDIA_TYPE_IMAGE_MODEL)?;
+    let from = base.to_string();
+    let layers = manifest
+        .layers
+        .into_iter()
+        .map(|mut l| {
+            if l.media_type == MEDIA_TYPE_IMAGE_MODEL {
+                return model_layer.clone();
+            }
+            l.from.get_or_insert_with(|| from.clone());
+            l
+        })
+        .collect::<Vec<_>>();
+    manifest_from_layers(store, config, layers)
+}
+
+/// Write the config blob of the layers and return their manifest
+fn manifest_from_layers(
+    store: &OllamaStore,
+    mut config: ImageConfig,
+    layers: Vec<ManifestLayer>,
+) -> Result<Manifest, ModelfileCreateError> {
+    config.architecture = go_arch().to_string();
+    config.os = go_os().to_string();
+    config.rootfs = ImageRootFs {
+        diff_ids: layers.iter().map(|l| l.digest.clone()).collect(),
+        ..ImageRootFs::default()
+    };
+    let config_data = serde_json::to_vec(&config).unwrap();
+    let config_blob = store
+        .add_blob_from_bytes(&config_data)
+        .map_err(ModelfileCreateError::BlobWrite)?;
+
+    Ok(Manifest {
+        schema_version: 2,
+        media_type: MEDIA_TYPE_DOCKER_DISTRIBUTION_MANIFEST.to_string(),
+        config: ManifestConfig {
+            media_type: MEDIA_TYPE_DOCKER_CONTAINER_IMAGE.to_string(),
+            digest: config_blob,
+            size: config_data.len() as u64,
+        },
+        layers,
+    })
+}
+
+fn layer_from_bytes(
+    store: &OllamaStore,
+    data: &[u8],
+    media_type: &str,
+) -> Result<ManifestLayer, ModelfileCreateError> {
+    let blob = store
+        .add_blob_from_bytes(data)
+        .map_err(ModelfileCreateError::BlobWrite)?;
+    Ok(ManifestLayer {
+        media_type: media_type.to_string(),
+        digest: blob,
+        size: data.len() as u64,
+        from: None,
+    })
+}
+
+fn layer_from_file(
+    store: &OllamaStore,
+    path: &Path,
+    media_type: &str,
+) -> Result<ManifestLayer, ModelfileCreateError> {
+    let io_err = |e| ModelfileCreateError::IO(e, path.to_path_buf());
+    let size = std::fs::metadata(path).map_err(io_err)?.len();
+
+    // a path pointing into the blob store (e.g. from `show --modelfile`) doesn't need copying
+    let existing = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| Blob::from_path_name(name).ok())
+        .filter(|blob| store.blob_path(blob) == path && store.blob_exists(blob));
+
+    let blob = match existing {
+        Some(blob) => blob,
+        None => {
+            let file = std::fs::File::open(path).map_err(io_err)?;
+            store.add_blob_from_file(file).map_err(io_err)?
+        }
+    };
+    Ok(ManifestLayer {
+        media_type: media_type.to_string(),
+        digest: blob,
+        size,
+        from: None,
+    })
+}
+
+fn go_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+fn go_os() -> &'static str {
+    match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODELFILE: &str = r#"
+# comment
+FROM llama3.2:latest
+PARAMETER temperature 0.7
+PARAMETER stop "<|eot_id|>"
+PARAMETER stop "User:"
+TEMPLATE """{{ .System }}
+{{ .Prompt }}"""
+SYSTEM You are a helpful assistant.
+MESSAGE user "is this a \"test\"?"
+MESSAGE assistant yes
+"#;
+
+    #[test]
+    fn parse() {
+        let modelfile = Modelfile::from_str(MODELFILE).unwrap();
+        assert_eq!(modelfile.from, "llama3.2:latest");
+        assert_eq!(
+            modelfile.template.as_deref(),
+            Some("{{ .System }}\n{{ .Prompt }}")
+        );
+        assert_eq!(
+            modelfile.system.as_deref(),
+            Some("You are a helpful assistant.")
+        );
+        assert_eq!(modelfile.parameters.len(), 3);
+        assert_eq!(
+            modelfile.messages[0],
+            (Role::User, "is this a \"test\"?".to_string())
+        );
+
+        let params = modelfile.parameters_json();
+        assert_eq!(params["temperature"], serde_json::json!(0.7));
+        assert_eq!(params["stop"], serde_json::json!(["<|eot_id|>", "User:"]));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let modelfile = Modelfile::from_str(MODELFILE).unwrap();
+        let rendered = modelfile.to_string();
+        assert_eq!(Modelfile::from_str(&rendered).unwrap(), modelfile);
+    }
+
+    #[test]
+    fn roundtrip_quotes() {
+        let modelfile = Modelfile {
+            from: "model.gguf".to_string(),
+            template: Some("{{ .Prompt }} \"\"\"quoted\"\"\"\n".to_string()),
+            system: Some("say \"hi\"".to_string()),
+            licenses: vec!["MIT  \nline\r\n".to_string()],
+            ..Default::default()
+        };
+        let rendered = modelfile.to_string();
+        assert_eq!(Modelfile::from_str(&rendered).unwrap(), modelfile);
+    }
+
+    #[test]
+    fn typed_parameters() {
+        let modelfile = Modelfile::from_str(
+            "FROM x\nPARAMETER stop true\nPARAMETER top_k 40\nPARAMETER seed 1.5",
+        )
+        .unwrap();
+        let params = modelfile.parameters_json();
+        assert_eq!(params["stop"], serde_json::json!(["true"]));
+        assert_eq!(params["top_k"], serde_json::json!(40));
+        assert_eq!(params["seed"], serde_json::json!("1.5"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Modelfile::from_str("PARAMETER x 1"),
+            Err(ModelfileParseError::MissingFrom)
+        );
+        assert_eq!(
+            Modelfile::from_str("FROM x\nTEMPLATE \"\"\"abc\n"),
+            Err(ModelfileParseError::UnterminatedString(2))
+        );
+        assert_eq!(
+            Modelfile::from_str("FROM x\nQUANTIZE q4"),
+            Err(ModelfileParseError::UnknownCommand(
+                2,
+                "QUANTIZE".to_string()
+            ))
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use std::{path::PathBuf, str::FromStr};
 
 use crate::storage::*;
+use serde::{Deserialize, Serialize};
 use thiserror::*;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -23,8 +24,65 @@ impl FromStr for Role {
     }
 }
 
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::System => "system",
+            Self::Assistant => "assistant",
+        }
+    }
+}
+
 pub struct Message(#[allow(unused)] String);
-pub struct Tool();
+/// A function the model can call, its parameters being described by a JSON schema
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "empty_parameters")]
+    pub parameters: serde_json::Value,
+}
+
+fn empty_parameters() -> serde_json::Value {
+    serde_json::json!({"type": "object", "properties": {}})
+}
+
+/// Tool definitions as written by users, either plain or in the OpenAI format
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ToolDefinition {
+    Function { function: Tool },
+    Plain(Tool),
+}
+
+impl Tool {
+    /// Parse a JSON array of tool definitions, `{"name", "description", "parameters"}`
+    /// objects optionally wrapped in `{"type": "function", "function": ...}`
+    pub fn list_from_json(json: &str) -> Result<Vec<Self>, serde_json::Error> {
+        let definitions: Vec<ToolDefinition> = serde_json::from_str(json)?;
+        Ok(definitions
+            .into_iter()
+            .map(|definition| match definition {
+                ToolDefinition::Function { function } => function,
+                ToolDefinition::Plain(tool) => tool,
+            })
+            .collect())
+    }
+
+    /// The tool in the format expected by chat templates
+    pub fn template_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters,
+            }
+        })
+    }
+}
 
 pub struct RunParams {
     pub messages: Vec<Message>,
@@ -39,6 +97,10 @@ pub struct RunParams {
 #[derive(Clone)]
 pub struct ModelConfig {
     pub model_path: PathBuf,
+    /// the multimodal projector (mmproj) of vision models
+    pub projector_path: Option<PathBuf>,
+    /// the LoRA adapters applied on top of the model
+    pub adapter_paths: Vec<PathBuf>,
     pub template: Option<String>,
     pub params: serde_json::Value,
 }
@@ -57,10 +119,16 @@ pub enum ModelConfigGetError {
     ReadingParameterError(std::io::Error, ModelDescr),
     #[error("error reading parameters JSON {0} for {1}")]
     ParameterFileNotJson(serde_json::error::Error, ModelDescr),
+    #[error("model not found {0}")]
+    NotFound(ModelDescr),
+    #[error("store error {0} for {1}")]
+    StoreError(std::io::Error, ModelDescr),
 }
 
-pub fn model_config_get(model_descr: &ModelDescr) -> Result<ModelConfig, ModelConfigGetError> {
-    let store = OllamaStore::default();
+pub fn model_config_get(
+    store: &OllamaStore,
+    model_descr: &ModelDescr,
+) -> Result<ModelConfig, ModelConfigGetError> {
     let manifest = store
         .get_manifest(&model_descr)
         .map_err(|e| ModelConfigGetError::ManifestError(e, model_descr.clone()))?;
@@ -111,8 +179,19 @@ pub fn model_config_get(model_descr: &ModelDescr) -> Result<ModelConfig, ModelCo
     */
 
     let path = store.blob_path(&model_layer.digest);
+    let projector_path = manifest
+        .find_media_type(MEDIA_TYPE_IMAGE_PROJECTOR)
+        .map(|layer| store.blob_path(&layer.digest));
+    let adapter_paths = manifest
+        .layers
+        .iter()
+        .filter(|layer| layer.media_type == MEDIA_TYPE_IMAGE_ADAPTER)
+        .map(|layer| store.blob_path(&layer.digest))
+        .collect();
     Ok(ModelConfig {
         model_path: path,
+        projector_path,
+        adapter_paths,
         template: template_data,
         params: params_json,
     })
@@ -11,17 +11,10 @@ use serde::{Deserialize, Serialize};
 use ulid::Ulid;
 
 pub struct OllamaStore {
+    /// the models directory, containing the blobs and manifests directories
     path: PathBuf,
 }
 
-impl Default for OllamaStore {
-    fn default() -> Self {
-        let home = std::env::home_dir().unwrap();
-        let ollama_default_path = home.join(".ollama");
-        Self::new(ollama_default_path)
-    }
-}
-
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ModelDescr {
     pub registry: Registry,
@@ -79,6 +72,12 @@ impl FromStr for Model {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > 256 {
+            return Err("invalid model name length".to_string());
+        }
+        for part in s.split('/') {
+            validate_name_part(part).map_err(|e| format!("invalid model name {}: {}", s, e))?;
+        }
         Ok(Self(s.to_string()))
     }
 }
@@ -95,11 +94,31 @@ impl FromStr for Variant {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > 128 {
+            return Err("invalid variant length".to_string());
+        }
+        validate_name_part(s).map_err(|e| format!("invalid variant {}: {}", s, e))?;
         Ok(Self(s.to_string()))
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+/// Check one component of a model name or variant, which is also used as a path element
+fn validate_name_part(s: &str) -> Result<(), &'static str> {
+    let mut chars = s.chars();
+    match chars.next() {
+        None => return Err("empty name"),
+        Some(c) if !(c.is_ascii_alphanumeric() || c == '_') => {
+            return Err("should start with a letter, a digit or '_'");
+        }
+        Some(_) => {}
+    }
+    if !chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-') {
+        return Err("only letters, digits, '_', '.' and '-' are allowed");
+    }
+    Ok(())
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Blob {
     Sha256([u8; 32]),
 }
@@ -217,29 +236,53 @@ impl Default for Registry {
     }
 }
 
+impl Registry {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl FromStr for Registry {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty()
+            || s.starts_with('.')
+            || !s
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ':' || c == '_')
+        {
+            return Err(format!("invalid registry {}", s));
+        }
         Ok(Self(s.to_string()))
     }
 }
 
 impl OllamaStore {
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        let path: PathBuf = path.as_ref().into();
-        if !path.exists() {
-            std::fs::create_dir_all(&path).unwrap();
-            std::fs::create_dir_all(&path.join("models")).unwrap();
-        }
-        Self { path }
+    /// Open the store rooted at `path` (e.g. `~/.ollama`), creating it if needed
+    pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::with_models_path(path.as_ref().join("models"))
+    }
+
+    /// Open the store from its models directory directly, as pointed by `OLLAMA_MODELS`
+    pub fn with_models_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let store = Self {
+            path: path.as_ref().into(),
+        };
+        std::fs::create_dir_all(store.blobs_path())?;
+        std::fs::create_dir_all(store.manifests_path())?;
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     fn model_path(&self) -> PathBuf {
-        self.path.join("models")
+        self.path.clone()
     }
 
-    fn blobs_path(&self) -> PathBuf {
+    pub(crate) fn blobs_path(&self) -> PathBuf {
         self.model_path().join("blobs")
     }
 
@@ -268,7 +311,7 @@ impl OllamaStore {
             let Ok(name) = entry_read_dir_string(d, false) else {
                 continue;
             };
-            let Ok(blob) = Blob::from_str(&name) else {
+            let Ok(blob) = Blob::from_path_name(&name) else {
                 continue;
             };
             blobs.push(blob)
@@ -280,10 +323,38 @@ impl OllamaStore {
         self.blobs_path().join(&blob.as_path_name())
     }
 
+    /// A new unique temporary path to download or write a blob into
     pub fn blob_path_tmp(&self, blob: &Blob) -> PathBuf {
         self.blobs_path()
-            .join(&blob.as_path_name())
-            .with_extension("tmp")
+            .join(format!("{}-{}.tmp", blob.as_path_name(), Ulid::new()))
+    }
+
+    /// A new unique temporary path for a file which will be added as a blob, e.g. a
+    /// converted model
+    ///
+    /// It is in the blobs directory, so `gc` collects it if it is left behind.
+    pub fn file_path_tmp(&self, prefix: &str) -> PathBuf {
+        self.blobs_path()
+            .join(format!("{}-{}.tmp", prefix, Ulid::new()))
+    }
+
+    /// The temporary files of previous interrupted writes of a blob
+    ///
+    /// They should only be resumed while holding the blob lock, as they might otherwise
+    /// belong to a write in progress.
+    pub fn blob_partial_files(&self, blob: &Blob) -> std::io::Result<Vec<PathBuf>> {
+        let prefix = blob.as_path_name();
+        let mut partials = Vec::new();
+        for entry in std::fs::read_dir(self.blobs_path())? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with(&prefix) && name.ends_with(".tmp") {
+                partials.push(entry.path());
+            }
+        }
+        Ok(partials)
     }
 
     pub fn blob_read(&self, blob: &Blob) -> std::io::Result<Vec<u8>> {
@@ -402,6 +473,7 @@ impl OllamaStore {
         variant: &Variant,
     ) -> std::io::Result<()> {
         let path = self.manifest_registry_model_variant_path(registry, model, variant);
+        let _lock = self.lock_manifest(registry, model, variant)?;
 
         if !path.exists() {
             return Ok(());
@@ -416,17 +488,104 @@ impl OllamaStore {
         model: &Model,
         variant: &Variant,
         manifest: &Manifest,
+    ) -> std::io::Result<()> {
+        let _lock = self.lock_manifest(registry, model, variant)?;
+        self.write_manifest(registry, model, variant, manifest)
+    }
+
+    /// Atomically replace a manifest, the caller holding its lock
+    ///
+    /// The data is written to a temporary file in the blobs directory, on the same file
+    /// system, then renamed over the manifest, so readers never see a partial manifest.
+    fn write_manifest(
+        &self,
+        registry: &Registry,
+        model: &Model,
+        variant: &Variant,
+        manifest: &Manifest,
     ) -> std::io::Result<()> {
         let path = self.manifest_registry_model_variant_path(registry, model, variant);
-        let manifest_data = serde_json::to_string(manifest).unwrap();
+        let manifest_data = serde_json::to_string(manifest).map_err(std::io::Error::other)?;
 
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
 
-        let mut file = std::fs::File::create(path)?;
-        file.write_all(&manifest_data.as_bytes())?;
-        Ok(())
+        let tmp_path = self
+            .blobs_path()
+            .join(format!("manifest-{}.tmp", Ulid::new()));
+        let result = std::fs::File::create_new(&tmp_path).and_then(|mut file| {
+            file.write_all(manifest_data.as_bytes())?;
+            file.sync_all()?;
+            std::fs::rename(&tmp_path, &path)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    /// Create a new manifest for `dst` pointing at the same blobs as `src`
+    ///
+    /// Fails with `AlreadyExists` if `dst` exists, unless `overwrite` is set
+    pub fn copy_manifest(
+        &self,
+        src: &ModelDescr,
+        dst: &ModelDescr,
+        overwrite: bool,
+    ) -> std::io::Result<()> {
+        let manifest = self.get_manifest(src)?;
+        let dst_path =
+            self.manifest_registry_model_variant_path(&dst.registry, &dst.model, &dst.variant);
+        let _lock = self.lock_manifest(&dst.registry, &dst.model, &dst.variant)?;
+        if !overwrite && dst_path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("model {} already exists", dst),
+            ));
+        }
+        self.write_manifest(&dst.registry, &dst.model, &dst.variant, &manifest)
+    }
+
+    /// Rename the manifest `src` to `dst` with a single file rename, so that the model
+    /// is never under both names, the blobs are not touched
+    pub fn rename_manifest(
+        &self,
+        src: &ModelDescr,
+        dst: &ModelDescr,
+        overwrite: bool,
+    ) -> std::io::Result<()> {
+        if src == dst {
+            return Ok(());
+        }
+        let src_path =
+            self.manifest_registry_model_variant_path(&src.registry, &src.model, &src.variant);
+        let dst_path =
+            self.manifest_registry_model_variant_path(&dst.registry, &dst.model, &dst.variant);
+        // locked in a fixed order, so that two opposite renames can't deadlock
+        let (first, second) = if src_path < dst_path {
+            (src, dst)
+        } else {
+            (dst, src)
+        };
+        let _first = self.lock_manifest(&first.registry, &first.model, &first.variant)?;
+        let _second = self.lock_manifest(&second.registry, &second.model, &second.variant)?;
+        if !src_path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("model {} not found", src),
+            ));
+        }
+        if !overwrite && dst_path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("model {} already exists", dst),
+            ));
+        }
+        if let Some(parent) = dst_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(src_path, dst_path)
     }
 
     pub fn blob_exists(&self, blob: &Blob) -> bool {
@@ -435,9 +594,19 @@ impl OllamaStore {
     }
 
     pub fn blob_self_verify(&self, blob: &Blob) -> std::io::Result<bool> {
+        self.blob_self_verify_progress(blob, |_| {})
+    }
+
+    /// Verify that the content of a blob matches its digest, calling `progress` with
+    /// the number of bytes hashed after each read
+    pub fn blob_self_verify_progress<F: FnMut(u64)>(
+        &self,
+        blob: &Blob,
+        mut progress: F,
+    ) -> std::io::Result<bool> {
         let path = self.blob_path(blob);
         let mut fs = std::fs::File::open(path)?;
-        let mut buf = [0; 16384];
+        let mut buf = vec![0; 1024 * 1024];
         let mut ctx = BlobContext::new_from_blob_type(blob);
         loop {
             let n = fs.read(&mut buf)?;
@@ -445,11 +614,21 @@ impl OllamaStore {
                 break;
             };
             ctx.update(&buf[0..n]);
+            progress(n as u64);
         }
         let blob_got = ctx.finalize();
         Ok(&blob_got == blob)
     }
 
+    /// Move a blob out of the blob store into the quarantine directory
+    pub fn quarantine_blob(&self, blob: &Blob) -> std::io::Result<PathBuf> {
+        let quarantine = self.model_path().join("quarantine");
+        std::fs::create_dir_all(&quarantine)?;
+        let dest = quarantine.join(format!("{}-{}", blob.as_path_name(), Ulid::new()));
+        std::fs::rename(self.blob_path(blob), &dest)?;
+        Ok(dest)
+    }
+
     pub fn write_blob_data(&self, blob: &Blob, data: &[u8]) -> std::io::Result<()> {
         let path = self.blob_path(blob);
 
@@ -457,51 +636,102 @@ impl OllamaStore {
             return Ok(());
         }
 
-        let mut file = std::fs::File::create_new(path)?;
-        file.write_all(data)?;
-        Ok(())
+        let tmp_path = self.blob_path_tmp(blob);
+        let result = std::fs::File::create_new(&tmp_path).and_then(|mut file| {
+            file.write_all(data)?;
+            std::fs::rename(&tmp_path, path)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result
     }
 
-    pub fn verify_blob(&self, blob: &Blob) -> std::io::Result<bool> {
-        let path = self.blob_path(blob);
-        let mut file = std::fs::File::open(path)?;
-        let mut buf = vec![0; 16834];
-        let mut context = BlobContext::new_from_blob_type(blob);
-        loop {
-            let n = file.read(&mut buf)?;
-            if n == 0 {
-                break;
-            }
-            context.update(&buf[0..n]);
-        }
-        let found_blob = context.finalize();
-        Ok(&found_blob == blob)
+    /// Add a blob from in-memory data, returning its digest
+    pub fn add_blob_from_bytes(&self, data: &[u8]) -> std::io::Result<Blob> {
+        let mut ctx = BlobContext::new_sha256();
+        ctx.update(data);
+        let blob = ctx.finalize();
+        self.write_blob_data(&blob, data)?;
+        Ok(blob)
     }
 
-    pub fn add_blob_from_file(&self, mut file: std::fs::File) -> std::io::Result<Blob> {
-        let u = Ulid::new();
-        let tmp_path = self.blobs_path().join(format!("{}.tmp", u));
+    /// Read and parse the config blob referenced by a manifest
+    pub fn get_image_config(&self, manifest: &Manifest) -> std::io::Result<ImageConfig> {
+        let data = self.blob_read(&manifest.config.digest)?;
+        serde_json::from_slice(&data)
+            .map_err(|e| std::io::Error::other(format!("json config invalid: {}", e)))
+    }
 
-        let mut buf = vec![0; 16384];
+    pub fn verify_blob(&self, blob: &Blob) -> std::io::Result<bool> {
+        self.blob_self_verify(blob)
+    }
 
-        let mut ctx = BlobContext::new_sha256();
-        let mut out = std::fs::File::create(&tmp_path)?;
+    pub fn add_blob_from_file(&self, file: std::fs::File) -> std::io::Result<Blob> {
+        self.add_blob_from_reader(file)
+    }
 
-        loop {
-            let n = file.read(&mut buf)?;
-            if n == 0 {
-                break;
-            };
-            out.write_all(&buf[0..n])?;
-            ctx.update(&buf[0..n]);
+    pub fn add_blob_from_reader<R: Read>(&self, file: R) -> std::io::Result<Blob> {
+        let (tmp_path, blob) = self.write_blob_tmp(file, BlobContext::new_sha256())?;
+        self.commit_blob_tmp(tmp_path, &blob)?;
+        Ok(blob)
+    }
+
+    /// Add a blob whose content should have the digest `expected`
+    ///
+    /// The digest of the content is returned, the blob is only added when it matches.
+    pub fn add_blob_from_reader_expected<R: Read>(
+        &self,
+        file: R,
+        expected: &Blob,
+    ) -> std::io::Result<Blob> {
+        let (tmp_path, blob) =
+            self.write_blob_tmp(file, BlobContext::new_from_blob_type(expected))?;
+        if &blob != expected {
+            std::fs::remove_file(tmp_path)?;
+            return Ok(blob);
         }
-        let blob = ctx.finalize();
+        self.commit_blob_tmp(tmp_path, &blob)?;
+        Ok(blob)
+    }
 
-        let blob_path = self.blob_path(&blob);
+    /// Copy the content of a reader to a temporary file of the blobs directory, hashing
+    /// it, the file being removed on error
+    fn write_blob_tmp<R: Read>(
+        &self,
+        mut file: R,
+        mut ctx: BlobContext,
+    ) -> std::io::Result<(PathBuf, Blob)> {
+        let u = Ulid::new();
+        let tmp_path = self.blobs_path().join(format!("{}.tmp", u));
 
-        std::fs::rename(tmp_path, blob_path)?;
+        let result = std::fs::File::create(&tmp_path).and_then(|mut out| {
+            let mut buf = vec![0; 16384];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                };
+                out.write_all(&buf[0..n])?;
+                ctx.update(&buf[0..n]);
+            }
+            Ok(ctx.finalize())
+        });
+        match result {
+            Ok(blob) => Ok((tmp_path, blob)),
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                Err(e)
+            }
+        }
+    }
 
-        Ok(blob)
+    fn commit_blob_tmp(&self, tmp_path: PathBuf, blob: &Blob) -> std::io::Result<()> {
+        let result = std::fs::rename(&tmp_path, self.blob_path(blob));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result
     }
 }
 
@@ -596,11 +826,82 @@ impl Manifest {
     pub fn find_media_type_mut(&mut self, ty: &str) -> Option<&mut ManifestLayer> {
         self.layers.iter_mut().find(|l| l.media_type == ty)
     }
+
+    pub fn filter_media_type<'a>(&'a self, ty: &'a str) -> impl Iterator<Item = &'a ManifestLayer> {
+        self.layers.iter().filter(move |l| l.media_type == ty)
+    }
+}
+
+/// Content of the manifest config blob
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub model_format: String,
+    #[serde(default)]
+    pub model_family: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_families: Option<Vec<String>>,
+    #[serde(default)]
+    pub model_type: String,
+    #[serde(default)]
+    pub file_type: String,
+    #[serde(default)]
+    pub architecture: String,
+    #[serde(default)]
+    pub os: String,
+    #[serde(default)]
+    pub rootfs: ImageRootFs,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ImageRootFs {
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub diff_ids: Vec<Blob>,
+}
+
+impl Default for ImageRootFs {
+    fn default() -> Self {
+        Self {
+            ty: "layers".to_string(),
+            diff_ids: Vec::new(),
+        }
+    }
 }
 
 pub const MEDIA_TYPE_IMAGE_MODEL: &str = "application/vnd.ollama.image.model";
+pub const MEDIA_TYPE_IMAGE_ADAPTER: &str = "application/vnd.ollama.image.adapter";
+pub const MEDIA_TYPE_IMAGE_PROJECTOR: &str = "application/vnd.ollama.image.projector";
 pub const MEDIA_TYPE_IMAGE_LICENSE: &str = "application/vnd.ollama.image.license";
 pub const MEDIA_TYPE_IMAGE_TEMPLATE: &str = "application/vnd.ollama.image.template";
+pub const MEDIA_TYPE_IMAGE_SYSTEM: &str = "application/vnd.ollama.image.system";
 pub const MEDIA_TYPE_IMAGE_PARAMS: &str = "application/vnd.ollama.image.params";
+pub const MEDIA_TYPE_IMAGE_MESSAGES: &str = "application/vnd.ollama.image.messages";
+pub const MEDIA_TYPE_DOCKER_CONTAINER_IMAGE: &str =
+    "application/vnd.docker.container.image.v1+json";
 pub const MEDIA_TYPE_DOCKER_DISTRIBUTION_MANIFEST: &str =
     "application/vnd.docker.distribution.manifest.v2+json";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_descr_parse() {
+        let descr = ModelDescr::from_str("prod-chat:current").unwrap();
+        assert_eq!(descr.registry, Registry::default());
+        assert_eq!(descr.model.as_str(), "prod-chat");
+        assert_eq!(descr.variant.as_str(), "current");
+        assert_eq!(descr.to_string(), "prod-chat:current");
+
+        let descr = ModelDescr::from_str("hf.co/user/model-GGUF").unwrap();
+        assert_eq!(descr.model.as_str(), "user/model-GGUF");
+        assert_eq!(descr.variant.as_str(), "latest");
+
+        assert!(ModelDescr::from_str("model:").is_err());
+        assert!(ModelDescr::from_str("model:.hidden").is_err());
+        assert!(ModelDescr::from_str("reg/../escape").is_err());
+        assert!(ModelDescr::from_str("reg/a//b").is_err());
+        assert!(ModelDescr::from_str("bad name").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Store backends and the resolution of the store location
+//!
+//! The location is taken, by order of priority, from the `--store` command line flag,
+//! the `LLMUP_HOME` environment variable, the `OLLAMA_MODELS` environment variable,
+//! the `store` key of the configuration file, and finally defaults to `~/.ollama`.
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{gguf_dir::*, run::*, storage::*};
+
+pub const ENV_LLMUP_HOME: &str = "LLMUP_HOME";
+pub const ENV_OLLAMA_MODELS: &str = "OLLAMA_MODELS";
+pub const ENV_XDG_CONFIG_HOME: &str = "XDG_CONFIG_HOME";
+
+/// Models that can be listed and loaded by name
+pub trait ModelStore {
+    /// All the models available in the store
+    fn list_model_descrs(&self) -> std::io::Result<Vec<ModelDescr>>;
+
+    /// The model file, template and parameters of a model
+    fn model_config(&self, descr: &ModelDescr) -> Result<ModelConfig, ModelConfigGetError>;
+
+    /// The size on disk of a model
+    fn model_size(&self, descr: &ModelDescr) -> std::io::Result<u64>;
+
+    /// The last time a model was added or modified
+    fn model_modified(&self, descr: &ModelDescr) -> std::io::Result<SystemTime>;
+}
+
+impl ModelStore for OllamaStore {
+    fn list_model_descrs(&self) -> std::io::Result<Vec<ModelDescr>> {
+        OllamaStore::list_model_descrs(self)
+    }
+
+    fn model_config(&self, descr: &ModelDescr) -> Result<ModelConfig, ModelConfigGetError> {
+        model_config_get(self, descr)
+    }
+
+    fn model_size(&self, descr: &ModelDescr) -> std::io::Result<u64> {
+        Ok(self.get_manifest(descr)?.size())
+    }
+
+    fn model_modified(&self, descr: &ModelDescr) -> std::io::Result<SystemTime> {
+        let path = self.manifest_registry_model_variant_path(
+            &descr.registry,
+            &descr.model,
+            &descr.variant,
+        );
+        std::fs::metadata(path)?.modified()
+    }
+}
+
+/// Where the models are stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StoreLocation {
+    /// An ollama models directory, containing the `blobs` and `manifests` directories
+    Ollama(PathBuf),
+    /// A plain directory of GGUF files
+    GgufDir(PathBuf),
+}
+
+#[derive(Error, Debug)]
+pub enum StoreLocationError {
+    #[error("cannot find the home directory, set {} or use --store", ENV_LLMUP_HOME)]
+    NoHomeDirectory,
+    #[error("error reading config file {1}: {0}")]
+    ConfigRead(std::io::Error, PathBuf),
+    #[error("invalid config file {1}: {0}")]
+    ConfigInvalid(String, PathBuf),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    store: Option<String>,
+}
+
+impl FromStr for StoreLocation {
+    type Err = std::convert::Infallible;
+
+    /// Parse a location, either explicitly prefixed by `ollama:` or `gguf:`, or a plain
+    /// path whose kind is detected from its content
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("gguf:") {
+            Ok(Self::GgufDir(PathBuf::from(path)))
+        } else if let Some(path) = s.strip_prefix("ollama:") {
+            Ok(Self::ollama_root(Path::new(path)))
+        } else {
+            Ok(Self::detect(Path::new(s)))
+        }
+    }
+}
+
+impl StoreLocation {
+    /// An ollama store from either its root (e.g. `~/.ollama`) or its models directory
+    fn ollama_root(path: &Path) -> Self {
+        if path.join("manifests").is_dir() || path.join("blobs").is_dir() {
+            Self::Ollama(path.to_path_buf())
+        } else {
+            Self::Ollama(path.join("models"))
+        }
+    }
+
+    fn detect(path: &Path) -> Self {
+        if path.join(GGUF_CATALOG_FILE).is_file() || has_gguf_files(path) {
+            Self::GgufDir(path.to_path_buf())
+        } else {
+            Self::ollama_root(path)
+        }
+    }
+
+    /// Find the location of the store, `flag` being the value given on the command line
+    pub fn resolve(flag: Option<&str>) -> Result<Self, StoreLocationError> {
+        if let Some(flag) = flag {
+            return Ok(Self::from_str(flag).unwrap());
+        }
+        if let Some(home) = std::env::var_os(ENV_LLMUP_HOME) {
+            return Ok(Self::from_str(&home.to_string_lossy()).unwrap());
+        }
+        if let Some(models) = std::env::var_os(ENV_OLLAMA_MODELS) {
+            return Ok(Self::Ollama(PathBuf::from(models)));
+        }
+        if let Some(config_path) = config_file_path() {
+            if let Some(store) = read_config_file(&config_path)?.store {
+                return Ok(Self::from_str(&store).unwrap());
+            }
+        }
+        let home = std::env::home_dir().ok_or(StoreLocationError::NoHomeDirectory)?;
+        Ok(Self::Ollama(home.join(".ollama").join("models")))
+    }
+
+    pub fn open(&self) -> std::io::Result<Store> {
+        match self {
+            Self::Ollama(path) => OllamaStore::with_models_path(path).map(Store::Ollama),
+            Self::GgufDir(path) => GgufDirStore::new(path).map(Store::GgufDir),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/llmup/config.toml`, or `~/.config/llmup/config.toml`
+pub fn config_file_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os(ENV_XDG_CONFIG_HOME) {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::home_dir()?.join(".config"),
+    };
+    Some(config_dir.join("llmup").join("config.toml"))
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile, StoreLocationError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ConfigFile::default()),
+        Err(e) => return Err(StoreLocationError::ConfigRead(e, path.to_path_buf())),
+    };
+    toml::from_str(&content)
+        .map_err(|e| StoreLocationError::ConfigInvalid(e.to_string(), path.to_path_buf()))
+}
+
+/// An opened store of any of the supported backends
+pub enum Store {
+    Ollama(OllamaStore),
+    GgufDir(GgufDirStore),
+}
+
+impl Store {
+    pub fn as_model_store(&self) -> &dyn ModelStore {
+        match self {
+            Self::Ollama(store) => store,
+            Self::GgufDir(store) => store,
+        }
+    }
+
+    /// The ollama store, for the operations that only make sense with manifests and blobs
+    pub fn ollama(&self) -> Option<&OllamaStore> {
+        match self {
+            Self::Ollama(store) => Some(store),
+            Self::GgufDir(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_location_parse() {
+        assert_eq!(
+            StoreLocation::from_str("gguf:/data/models").unwrap(),
+            StoreLocation::GgufDir(PathBuf::from("/data/models"))
+        );
+        assert_eq!(
+            StoreLocation::from_str("ollama:/nonexistent/.ollama").unwrap(),
+            StoreLocation::Ollama(PathBuf::from("/nonexistent/.ollama/models"))
+        );
+        assert_eq!(
+            StoreLocation::from_str("/nonexistent/.ollama").unwrap(),
+            StoreLocation::Ollama(PathBuf::from("/nonexistent/.ollama/models"))
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Fixtures shared by the tests of the crate
+use std::path::{Path, PathBuf};
+
+use crate::storage::OllamaStore;
+
+/// A unique temporary directory, removed when dropped even if the test panics
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("llmup-test-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A store in a temporary directory, removed with the directory
+pub fn temp_store() -> (OllamaStore, TempDir) {
+    let dir = TempDir::new();
+    (OllamaStore::new(dir.path()).unwrap(), dir)
+}
@@ -0,0 +1,69 @@
+//! Parallel verification of the blob store
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::storage::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobStatus {
+    Valid,
+    Missing,
+    Corrupted,
+}
+
+impl OllamaStore {
+    /// Verify the content of many blobs, spreading the hashing over `threads` workers
+    ///
+    /// `progress` is called from the workers with the number of bytes hashed since its
+    /// previous call. The results are in the same order as `blobs`.
+    pub fn verify_blobs_parallel<F>(
+        &self,
+        blobs: &[Blob],
+        threads: usize,
+        progress: F,
+    ) -> Vec<std::io::Result<BlobStatus>>
+    where
+        F: Fn(u64) + Sync,
+    {
+        let threads = threads.clamp(1, blobs.len().max(1));
+        let next = AtomicUsize::new(0);
+        let mut results = (0..blobs.len()).map(|_| None).collect::<Vec<_>>();
+
+        std::thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(blob) = blobs.get(i) else {
+                                break;
+                            };
+                            let status = if !self.blob_exists(blob) {
+                                Ok(BlobStatus::Missing)
+                            } else {
+                                self.blob_self_verify_progress(blob, &progress)
+                                    .map(|valid| match valid {
+                                        true => BlobStatus::Valid,
+                                        false => BlobStatus::Corrupted,
+                                    })
+                            };
+                            done.push((i, status));
+                        }
+                        done
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for worker in workers {
+                for (i, status) in worker.join().expect("verify worker panicked") {
+                    results[i] = Some(status);
+                }
+            }
+        });
+
+        results
+            .into_iter()
+            .map(|r| r.expect("all blobs verified"))
+            .collect()
+    }
+}
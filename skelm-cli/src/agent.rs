@@ -0,0 +1,381 @@
+//! Tools run locally on behalf of the model by `llmup agent`
+//!
+//! The tools are declared in a TOML file:
+//!
+//! ```toml
+//! [shell]
+//! allow = ["ls", "git", "cargo"]
+//!
+//! [fetch]
+//! base_url = "http://localhost:8080/api/"
+//!
+//! [[tools]]
+//! name = "weather"
+//! description = "Current weather of a city"
+//! command = "/usr/local/bin/weather"
+//! parameters = { type = "object", properties = { city = { type = "string" } } }
+//! ```
+//!
+//! User-defined tools get their arguments as JSON on stdin and answer on stdout.
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use skelm_exec::ToolCall;
+use skelm_ollama as ollama;
+
+/// Tool results are truncated to this size to keep the context small
+const MAX_RESULT_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    #[serde(default)]
+    pub shell: ShellConfig,
+    pub fetch: Option<FetchConfig>,
+    #[serde(default)]
+    pub tools: Vec<CommandTool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShellConfig {
+    /// programs the model can run
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FetchConfig {
+    /// only URLs under this one can be fetched
+    pub base_url: String,
+}
+
+/// An executable declared by the user
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandTool {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// JSON schema of the arguments
+    pub parameters: Option<serde_json::Value>,
+}
+
+impl AgentConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading agent config {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("parsing agent config {}", path.display()))
+    }
+}
+
+const SHELL_TOOL: &str = "shell";
+const READ_FILE_TOOL: &str = "read_file";
+const FETCH_TOOL: &str = "http_fetch";
+
+pub struct Agent {
+    config: AgentConfig,
+    /// files can only be read under this directory
+    root: PathBuf,
+    /// the fetch base URL, ending with `/`
+    fetch_base: Option<url::Url>,
+    client: reqwest::Client,
+    /// ask before running each tool
+    confirm: bool,
+}
+
+impl Agent {
+    pub fn new(config: AgentConfig, confirm: bool) -> anyhow::Result<Self> {
+        let fetch_base = match &config.fetch {
+            Some(fetch) => {
+                let mut base = url::Url::parse(&fetch.base_url)
+                    .with_context(|| format!("invalid fetch base URL {}", fetch.base_url))?;
+                if !base.path().ends_with('/') {
+                    base.set_path(&format!("{}/", base.path()));
+                }
+                Some(base)
+            }
+            None => None,
+        };
+        // a redirection could leave the base URL
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            config,
+            root: std::env::current_dir()?.canonicalize()?,
+            fetch_base,
+            client,
+            confirm,
+        })
+    }
+
+    /// The definitions of the tools given to the model
+    pub fn tools(&self) -> Vec<ollama::Tool> {
+        let mut tools = vec![ollama::Tool {
+            name: READ_FILE_TOOL.to_string(),
+            description: "Read a text file of the current directory".to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {"path": {"type": "string", "description": "relative path of the file"}},
+                "required": ["path"]
+            }),
+        }];
+        if !self.config.shell.allow.is_empty() {
+            tools.push(ollama::Tool {
+                name: SHELL_TOOL.to_string(),
+                description: format!(
+                    "Run a command and return its output. The command is split on spaces and \
+                     run without a shell. Allowed programs: {}",
+                    self.config.shell.allow.join(", ")
+                ),
+                parameters: serde_json::json!({
+                    "type": "object",
+                    "properties": {"command": {"type": "string"}},
+                    "required": ["command"]
+                }),
+            });
+        }
+        if let Some(fetch) = &self.config.fetch {
+            tools.push(ollama::Tool {
+                name: FETCH_TOOL.to_string(),
+                description: format!("HTTP GET of a path relative to {}", fetch.base_url),
+                parameters: serde_json::json!({
+                    "type": "object",
+                    "properties": {"path": {"type": "string"}},
+                    "required": ["path"]
+                }),
+            });
+        }
+        for tool in &self.config.tools {
+            tools.push(ollama::Tool {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool
+                    .parameters
+                    .clone()
+                    .unwrap_or_else(|| serde_json::json!({"type": "object", "properties": {}})),
+            });
+        }
+        tools
+    }
+
+    /// Run a tool call, returning the text given back to the model
+    pub async fn call(&self, call: &ToolCall) -> String {
+        if self.confirm && !confirm(call).await {
+            return "the user refused to run this tool".to_string();
+        }
+        let result = match call.name.as_str() {
+            READ_FILE_TOOL => self.read_file(call),
+            SHELL_TOOL if !self.config.shell.allow.is_empty() => self.shell(call).await,
+            FETCH_TOOL if self.fetch_base.is_some() => self.fetch(call).await,
+            name => match self.config.tools.iter().find(|t| t.name == name) {
+                Some(tool) => run_command_tool(tool, &call.arguments).await,
+                None => Err(anyhow::anyhow!("unknown tool {}", name)),
+            },
+        };
+        let mut text = match result {
+            Ok(text) => text,
+            Err(e) => format!("error: {:#}", e),
+        };
+        if text.len() > MAX_RESULT_SIZE {
+            let mut end = MAX_RESULT_SIZE;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+            text.push_str("\n[truncated]");
+        }
+        text
+    }
+
+    fn read_file(&self, call: &ToolCall) -> anyhow::Result<String> {
+        let path = string_argument(call, "path")?;
+        let path = self
+            .root
+            .join(path)
+            .canonicalize()
+            .with_context(|| format!("cannot open {}", path))?;
+        if !path.starts_with(&self.root) {
+            anyhow::bail!("{} is outside of the current directory", path.display())
+        }
+        let bytes = std::fs::read(&path)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    async fn shell(&self, call: &ToolCall) -> anyhow::Result<String> {
+        let command = string_argument(call, "command")?;
+        let mut words = command.split_whitespace();
+        let program = words.next().context("empty command")?;
+        if !self.config.shell.allow.iter().any(|p| p == program) {
+            anyhow::bail!("{} is not an allowed program", program)
+        }
+        let output = tokio::process::Command::new(program)
+            .args(words)
+            .current_dir(&self.root)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .with_context(|| format!("running {}", program))?;
+        Ok(command_output(&output))
+    }
+
+    async fn fetch(&self, call: &ToolCall) -> anyhow::Result<String> {
+        let base = self.fetch_base.as_ref().unwrap();
+        let path = string_argument(call, "path")?;
+        let url = base.join(path.trim_start_matches('/'))?;
+        if !is_under(&url, base) {
+            anyhow::bail!("{} is not under {}", url, base)
+        }
+        let response = self.client.get(url.clone()).send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            anyhow::bail!("{} returned {}: {}", url, status, body)
+        }
+        Ok(body)
+    }
+}
+
+/// Whether `url` has the origin of `base` and starts with all its path segments
+fn is_under(url: &url::Url, base: &url::Url) -> bool {
+    if url.origin() != base.origin() {
+        return false;
+    }
+    let (Some(mut segments), Some(base_segments)) = (url.path_segments(), base.path_segments())
+    else {
+        return false;
+    };
+    base_segments
+        .filter(|segment| !segment.is_empty())
+        .all(|segment| segments.next() == Some(segment))
+}
+
+fn string_argument<'a>(call: &'a ToolCall, name: &str) -> anyhow::Result<&'a str> {
+    call.arguments
+        .get(name)
+        .and_then(|v| v.as_str())
+        .with_context(|| format!("missing string argument {}", name))
+}
+
+async fn run_command_tool(
+    tool: &CommandTool,
+    arguments: &serde_json::Value,
+) -> anyhow::Result<String> {
+    let mut child = tokio::process::Command::new(&tool.command)
+        .args(&tool.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {}", tool.command))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = serde_json::to_vec(arguments)?;
+    tokio::io::AsyncWriteExt::write_all(&mut stdin, &input).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    Ok(command_output(&output))
+}
+
+fn command_output(output: &std::process::Output) -> String {
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.is_empty() {
+        text.push_str("\nstderr:\n");
+        text.push_str(&stderr);
+    }
+    if !output.status.success() {
+        text.push_str(&format!("\nexit status: {}", output.status));
+    }
+    text
+}
+
+/// Ask the user whether to run a tool, reading the answer off the async runtime
+async fn confirm(call: &ToolCall) -> bool {
+    let question = format!("run {} {}? [y/N] ", call.name, call.arguments);
+    tokio::task::spawn_blocking(move || {
+        eprint!("{}", question);
+        let _ = std::io::stderr().flush();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err() {
+            return false;
+        }
+        matches!(answer.trim(), "y" | "Y" | "yes")
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Append-only log of the conversation, one JSON message per line
+pub struct Transcript(Option<std::fs::File>);
+
+impl Transcript {
+    pub fn open(path: Option<&str>) -> anyhow::Result<Self> {
+        let file = path
+            .map(|path| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("opening transcript {}", path))
+            })
+            .transpose()?;
+        Ok(Self(file))
+    }
+
+    pub fn log(&mut self, message: &skelm_exec::ChatMessage) -> anyhow::Result<()> {
+        if let Some(file) = &mut self.0 {
+            serde_json::to_writer(&mut *file, message)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: AgentConfig = toml::from_str(
+            r#"
+            [shell]
+            allow = ["ls"]
+
+            [[tools]]
+            name = "weather"
+            command = "/usr/local/bin/weather"
+            parameters = { type = "object", properties = { city = { type = "string" } } }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.shell.allow, ["ls"]);
+        assert!(config.fetch.is_none());
+        let agent = Agent::new(config, false).unwrap();
+        let tools = agent.tools();
+        let names: Vec<_> = tools.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, [READ_FILE_TOOL, SHELL_TOOL, "weather"]);
+        assert_eq!(tools[2].parameters["properties"]["city"]["type"], "string");
+    }
+
+    #[test]
+    fn fetch_under_base() {
+        let base = url::Url::parse("http://h/api/").unwrap();
+        let under = |path: &str| is_under(&base.join(path).unwrap(), &base);
+        assert!(under("models"));
+        assert!(under("models/a?b=c"));
+        assert!(!under("../api2/secret"));
+        assert!(!under("//other/api/x"));
+        assert!(!under("http://h/api2/secret"));
+    }
+}
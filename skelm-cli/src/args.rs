@@ -1,14 +1,32 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use skelm_llama_cpp as llama;
 
 /// Example CLI with subcommands: list, pull, verify
 #[derive(Parser, Debug)]
 #[command(name = "llmup")]
 #[command(about = "CLI tool to install and managed use LLM models", long_about = None)]
 pub struct Cli {
+    /// Location of the model store: an ollama directory, or `gguf:<dir>` for a directory
+    /// of GGUF files. Defaults to LLMUP_HOME, OLLAMA_MODELS, the config file or ~/.ollama
+    #[arg(long, global = true)]
+    pub store: Option<String>,
+    /// Output format of the commands reporting results (list, info, verify, pull, tags, ...)
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    /// Human readable tables and text
+    Table,
+    /// A single JSON document
+    Json,
+    /// One JSON object per line
+    Jsonl,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// List available model
@@ -22,6 +40,16 @@ pub enum Commands {
         /// The name of the model to pull
         name: String,
     },
+    /// List the tags of a model available in the registry, with their size and quantization
+    Tags {
+        /// The name of the model, e.g. `llama3.2`
+        name: String,
+    },
+    /// Search the registry for models whose name contains the query
+    Search {
+        /// Part of the model name to look for
+        query: String,
+    },
     Set {
         /// The name of the model
         name: String,
@@ -30,21 +58,110 @@ pub enum Commands {
         /// The value to use (can be a filepath)
         value: String,
     },
+    /// Create a model from a Modelfile
+    Create {
+        /// The name of the model to create
+        name: String,
+        /// Path to the Modelfile
+        #[arg(short, long, default_value = "Modelfile")]
+        file: String,
+    },
+    /// Show the manifest of a model
+    Show {
+        /// The name of the model to show
+        name: String,
+        /// Print the Modelfile of the model instead
+        #[arg(long, default_value_t = false)]
+        modelfile: bool,
+    },
+    /// Copy a model to a new name, sharing the same blobs
+    Cp {
+        /// The name of the model to copy
+        src: String,
+        /// The new name
+        dst: String,
+        /// Replace the destination if it already exists
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+    },
+    /// Add a tag to a model, sharing the same blobs
+    Tag {
+        /// The name of the model to tag
+        name: String,
+        /// The new variant of the same model, or a full <model>:<variant> name
+        tag: String,
+        /// Move the tag if it already exists
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+    },
+    /// Rename a model
+    Mv {
+        /// The name of the model to rename
+        src: String,
+        /// The new name
+        dst: String,
+        /// Replace the destination if it already exists
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+    },
+    /// Export a model as an OCI image layout tarball
+    Export {
+        /// The name of the model to export
+        name: String,
+        /// The tarball to write
+        #[arg(short, long)]
+        output: String,
+    },
+    /// Import models from an OCI image layout tarball
+    Import {
+        /// The tarball to read
+        file: String,
+        /// Name to give to the imported model instead of the one in the archive
+        #[arg(long = "as")]
+        name: Option<String>,
+    },
     /// Remove a model by name
     Remove {
         /// The name of the model to remove
         name: String,
+        /// Don't remove the blobs that are no longer used
+        #[arg(long, default_value_t = false)]
+        no_gc: bool,
+        /// Minimum age in hours of the blobs to remove, younger ones are left to `gc`
+        #[arg(long, default_value_t = 24)]
+        min_age: u64,
+    },
+    /// Remove unreferenced blobs and stale temporary files
+    Gc {
+        /// Only report what would be removed
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Minimum age in hours of the files to remove
+        #[arg(long, default_value_t = 24)]
+        min_age: u64,
     },
     /// Verify install
     Verify {
         /// Flag to verify blobs (might take a long time)
         #[arg(short, long, default_value_t = false)]
         blobs: bool,
+        /// Quarantine corrupted blobs and download the missing ones again (implies --blobs)
+        #[arg(long, default_value_t = false)]
+        repair: bool,
+        /// Number of blobs verified in parallel (default to the number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<usize>,
     },
     /// Information about a model
     Info {
-        /// The name of the model to get info
+        /// The name of the model, a blob digest or the path of a GGUF file
         name: String,
+        /// Print all the metadata of the file
+        #[arg(long, default_value_t = false)]
+        metadata: bool,
+        /// Print the tensors of the file
+        #[arg(long, default_value_t = false)]
+        tensors: bool,
     },
     /// Run a model
     Run {
@@ -67,6 +184,33 @@ pub enum Commands {
         no_prompt: bool,
         #[arg(long)]
         output: Option<String>,
+        /// Don't show the reasoning of thinking models
+        #[arg(long, default_value_t = false)]
+        hide_thinking: bool,
+        /// Switch on the reasoning of the models supporting it
+        #[arg(long, default_value_t = false, conflicts_with = "no_think")]
+        think: bool,
+        /// Switch off the reasoning of the models supporting it
+        #[arg(long, default_value_t = false)]
+        no_think: bool,
+        /// JSON file with the definitions of the tools the model can call
+        #[arg(long)]
+        tools: Option<String>,
+        /// Constrain the tool calls to the declared tools and their parameters
+        #[arg(long, default_value_t = false, requires = "tools")]
+        tool_grammar: bool,
+        /// Image (PNG or JPEG) given with the prompt to vision models (can be repeated)
+        #[arg(long)]
+        image: Vec<String>,
+        /// Multimodal projector to use instead of the one of the model
+        #[arg(long)]
+        mmproj: Option<String>,
+        /// Output the log-probability of each generated token and of its N most likely
+        /// alternatives, as JSON lines instead of the text
+        #[arg(long, value_name = "N")]
+        logprobs: Option<usize>,
+        #[command(flatten)]
+        load: LoadArgs,
     },
     /// Bench model generation
     Bench {
@@ -74,10 +218,286 @@ pub enum Commands {
         name: String,
         #[arg(short, long)]
         max_tokens: Option<u64>,
+        #[command(flatten)]
+        load: LoadArgs,
     },
     /// Embedding generation
     Embed {
         /// The name of the model to run
         name: String,
+        #[command(flatten)]
+        load: LoadArgs,
     },
+    /// Convert text to tokens with the tokenizer of a model
+    Tokenize {
+        /// The name of the model, a blob digest or the path of a GGUF file
+        name: String,
+        /// The text to tokenize, read from stdin if not given
+        text: Option<String>,
+        /// Only print the number of tokens
+        #[arg(short, long, default_value_t = false)]
+        count: bool,
+        /// Print the text of each token along its id
+        #[arg(long, default_value_t = false)]
+        pieces: bool,
+        /// Don't add the BOS/EOS tokens the model expects around a sequence
+        #[arg(long, default_value_t = false)]
+        no_add_special: bool,
+        /// Tokenize the text of special tokens (e.g. `<|im_start|>`) as plain text
+        #[arg(long, default_value_t = false)]
+        no_parse_special: bool,
+    },
+    /// Convert token ids back to text with the tokenizer of a model
+    Detokenize {
+        /// The name of the model, a blob digest or the path of a GGUF file
+        name: String,
+        /// The token ids, read from stdin if not given (separated by spaces or commas)
+        tokens: Vec<u32>,
+        /// Remove the BOS/EOS tokens
+        #[arg(long, default_value_t = false)]
+        remove_special: bool,
+        /// Don't render the text of special tokens
+        #[arg(long, default_value_t = false)]
+        no_unparse_special: bool,
+    },
+    /// Chat with a model able to call local tools
+    Agent {
+        /// The name of the model to run
+        name: String,
+        /// The first message, asked interactively if not given
+        prompt: Option<String>,
+        /// TOML file declaring the shell allowlist, the fetch base URL and the user tools
+        #[arg(long)]
+        config: Option<String>,
+        /// Allow the model to run this program (can be repeated)
+        #[arg(long)]
+        allow: Vec<String>,
+        /// Allow the model to fetch the URLs under this one
+        #[arg(long)]
+        fetch_base_url: Option<String>,
+        /// Maximum number of generations for each user message
+        #[arg(long, default_value_t = 10)]
+        max_steps: u32,
+        /// Run the tools without asking for confirmation
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+        /// Append the messages of the conversation to this JSONL file
+        #[arg(long)]
+        transcript: Option<String>,
+        #[arg(long)]
+        system: Option<String>,
+        /// Constrain the tool calls to the declared tools and their parameters
+        #[arg(long, default_value_t = false)]
+        tool_grammar: bool,
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+    /// Evaluate the perplexity of a model on a text file
+    Perplexity {
+        /// The name of the model to evaluate
+        name: String,
+        /// The text to score
+        #[arg(short, long)]
+        file: String,
+        /// Tokens evaluated at once, also the context size unless --ctx is given
+        #[arg(long, default_value_t = 512)]
+        window: usize,
+        /// Tokens between the starts of two windows, half a window by default
+        #[arg(long)]
+        stride: Option<usize>,
+        /// Also output the log-probability of each token (with json/jsonl output)
+        #[arg(long, default_value_t = false)]
+        per_token: bool,
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+    /// Score candidate completions of a prompt, for labeling and multiple choice
+    Classify {
+        /// The name of the model to run
+        name: String,
+        /// The prompt, read from stdin if not given
+        prompt: Option<String>,
+        /// A candidate completion of the prompt (repeat for each candidate)
+        #[arg(short, long = "choice", value_name = "TEXT", required = true)]
+        choices: Vec<String>,
+        /// Compare the mean log-likelihood per token of the candidates instead of their
+        /// total, not to favor the shorter ones
+        #[arg(long, default_value_t = false)]
+        length_normalized: bool,
+        /// Score the candidates right after the prompt, without the chat template
+        #[arg(long, default_value_t = false)]
+        raw: bool,
+        #[arg(long)]
+        system: Option<String>,
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+    /// Generate the answers to the chat requests of a JSONL file, resuming the job if it
+    /// was interrupted
+    ///
+    /// The context (--ctx) is shared by the requests generated at once.
+    Batch {
+        /// The name of the model to run
+        name: String,
+        /// The requests, one JSON object per line with `messages` and the sampling options
+        #[arg(short, long)]
+        input: String,
+        /// The results, written as JSON lines in the order they complete
+        #[arg(short, long)]
+        output: String,
+        /// Number of requests generated at once
+        #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        parallel: u32,
+        /// Maximum number of tokens generated for the requests not giving `max_tokens`
+        #[arg(long, default_value_t = 512)]
+        max_tokens: usize,
+        /// Overwrite the output and start over instead of resuming the job
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+    /// Quantize a model to a smaller type, storing it as a new model
+    Quantize {
+        /// The name of the model to quantize
+        name: String,
+        /// The target type (e.g. Q4_K_M, Q5_K_M, Q8_0, IQ4_XS)
+        ftype: llama::QuantType,
+        /// The name of the new model, <model>:<variant>-<type> by default
+        #[arg(long = "as", value_name = "NAME")]
+        target: Option<String>,
+        /// Number of threads, all the cores by default
+        #[arg(long)]
+        threads: Option<u32>,
+        /// Importance matrix computed by llama-imatrix, improving the low bit types and
+        /// required by the lowest ones
+        #[arg(long)]
+        imatrix: Option<String>,
+        /// Keep the output tensor unquantized
+        #[arg(long, default_value_t = false)]
+        leave_output_tensor: bool,
+        /// Quantize all the tensors to the target type, without keeping the sensitive
+        /// ones in a higher precision
+        #[arg(long, default_value_t = false)]
+        pure: bool,
+        /// Overwrite the new model if it exists
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+    },
+}
+
+/// Options of the commands loading a model
+#[derive(Args, Debug)]
+pub struct LoadArgs {
+    /// Size of the context in tokens
+    #[arg(long)]
+    pub ctx: Option<u32>,
+    /// Type of the keys in the KV cache (f32, f16, bf16, q8_0, q4_0, q4_1, iq4_nl, q5_0, q5_1)
+    #[arg(long)]
+    pub cache_type_k: Option<llama::KvCacheType>,
+    /// Type of the values in the KV cache
+    #[arg(long)]
+    pub cache_type_v: Option<llama::KvCacheType>,
+    /// Load the model even if it's not estimated to fit in the available memory
+    #[arg(long, default_value_t = false)]
+    pub no_memory_check: bool,
+    /// LoRA adapter applied on top of the model, with an optional scale (can be repeated)
+    #[arg(long, value_name = "PATH[:SCALE]")]
+    pub lora: Vec<ScaledPath>,
+    /// Control vector steering the model, with an optional strength (can be repeated)
+    #[arg(long, value_name = "PATH[:STRENGTH]")]
+    pub control_vector: Vec<ScaledPath>,
+    /// Layers steered by the control vectors, all of them by default
+    #[arg(long, value_name = "START-END", value_parser = parse_layer_range, requires = "control_vector")]
+    pub control_vector_layers: Option<std::ops::RangeInclusive<i32>>,
+}
+
+/// A file given as `path[:scale]`, the scale defaulting to 1
+#[derive(Clone, Debug)]
+pub struct ScaledPath {
+    pub path: std::path::PathBuf,
+    pub scale: f32,
+}
+
+impl std::str::FromStr for ScaledPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a suffix which isn't a number is part of the path
+        match s.rsplit_once(':') {
+            Some((path, scale)) if !path.is_empty() => match scale.parse::<f32>() {
+                Ok(scale) => Ok(Self {
+                    path: path.into(),
+                    scale,
+                }),
+                Err(_) => Ok(Self {
+                    path: s.into(),
+                    scale: 1.0,
+                }),
+            },
+            _ => Ok(Self {
+                path: s.into(),
+                scale: 1.0,
+            }),
+        }
+    }
+}
+
+fn parse_layer_range(s: &str) -> Result<std::ops::RangeInclusive<i32>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expecting START-END, got {}", s))?;
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<i32>()
+            .map_err(|e| format!("invalid layer {}: {}", n, e))
+    };
+    Ok(parse(start)?..=parse(end)?)
+}
+
+impl LoadArgs {
+    pub fn load_params(&self) -> skelm_exec::LoadParams {
+        let defaults = skelm_exec::LoadParams::default();
+        let context = llama::ContextParams {
+            n_ctx: self.ctx.unwrap_or(defaults.context.n_ctx),
+            type_k: self.cache_type_k.unwrap_or(defaults.context.type_k),
+            type_v: self.cache_type_v.unwrap_or(defaults.context.type_v),
+            ..defaults.context
+        };
+        skelm_exec::LoadParams {
+            context,
+            memory_check: !self.no_memory_check,
+            adapters: self
+                .lora
+                .iter()
+                .map(|lora| (lora.path.clone(), lora.scale))
+                .collect(),
+            control_vectors: self
+                .control_vector
+                .iter()
+                .map(|cvec| (cvec.path.clone(), cvec.scale))
+                .collect(),
+            control_vector_layers: self.control_vector_layers.clone(),
+            ..defaults
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_scaled_path() {
+        let lora: ScaledPath = "adapters/style.gguf:0.5".parse().unwrap();
+        assert_eq!(lora.path, std::path::PathBuf::from("adapters/style.gguf"));
+        assert_eq!(lora.scale, 0.5);
+        let lora: ScaledPath = "C:\\adapters\\style.gguf".parse().unwrap();
+        assert_eq!(
+            lora.path,
+            std::path::PathBuf::from("C:\\adapters\\style.gguf")
+        );
+        assert_eq!(lora.scale, 1.0);
+        assert_eq!(parse_layer_range("10-20"), Ok(10..=20));
+    }
 }
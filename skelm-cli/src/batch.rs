@@ -0,0 +1,512 @@
+//! Batch inference over a JSONL file of chat requests, for `llmup batch`
+//!
+//! Each line of the input is a request, all the fields but `messages` being optional:
+//!
+//! ```json
+//! {"id": "row-1", "messages": [{"role": "user", "content": "Hi"}], "max_tokens": 64, "temperature": 0.2}
+//! ```
+//!
+//! The requests are generated together, each in its own sequence of one context, and
+//! their results are written to the output as they complete, with the number of their
+//! line. The completed lines are recorded in a checkpoint file next to the output, so
+//! that running the same job again resumes it.
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::Instant,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use skelm_llama_cpp::{self as llama, Sampler};
+
+use crate::run;
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    /// given back with the result
+    #[serde(default)]
+    pub id: Option<serde_json::Value>,
+    pub messages: Vec<skelm_exec::ChatMessage>,
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub seed: Option<u32>,
+    /// the generation stops before any of these texts
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// switch the reasoning on or off, for the models supporting it
+    pub think: Option<bool>,
+}
+
+impl BatchRequest {
+    /// The sampler of the request, with the defaults of `llmup run`
+    fn sampler(&self) -> llama::SamplerChain {
+        let mut sampler = llama::SamplerChain::new();
+        if let Some(k) = self.top_k.filter(|k| *k > 0) {
+            sampler.add(Box::new(llama::SamplerTopK::new(k)));
+        }
+        if let Some(p) = self.top_p.filter(|p| *p < 1.0) {
+            sampler.add(Box::new(llama::SamplerTopP::new(p, 1)));
+        }
+        let min_p = self.min_p.unwrap_or(0.05);
+        sampler.add(Box::new(llama::SamplerMinP::new(min_p, 1)));
+        let temperature = self.temperature.unwrap_or(0.8);
+        sampler.add(Box::new(llama::SamplerTemperature::new(temperature)));
+        let seed = self.seed.unwrap_or(0xFFFF_FFFF);
+        sampler.add(Box::new(llama::SamplerDistance::new(seed)));
+        sampler
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    /// number of the line of the request in the input, from 1
+    pub line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Outcome {
+    Completion {
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reasoning_content: Option<String>,
+        finish_reason: FinishReason,
+        usage: Usage,
+        timings: Timings,
+    },
+    Error {
+        error: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// end of the answer or stop text
+    Stop,
+    /// `max_tokens` or the context of the sequence reached
+    Length,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// Durations of the request, shared with the requests generated at the same time
+#[derive(Debug, Serialize)]
+pub struct Timings {
+    pub prompt_ms: f64,
+    pub generation_ms: f64,
+}
+
+impl BatchResult {
+    fn failed(line: usize, id: Option<serde_json::Value>, error: String) -> Self {
+        Self {
+            line,
+            id,
+            outcome: Outcome::Error { error },
+        }
+    }
+}
+
+pub struct BatchParams {
+    /// requests generated at once, each in its own sequence
+    pub parallel: usize,
+    /// tokens generated for the requests not giving `max_tokens`
+    pub max_tokens: usize,
+}
+
+/// A request being generated, in its own sequence
+struct Slot {
+    line: usize,
+    id: Option<serde_json::Value>,
+    sampler: llama::SamplerChain,
+    decoder: skelm_exec::TokenTextDecoder,
+    thinking: skelm_exec::ThinkingParser,
+    content: String,
+    reasoning: String,
+    stop: Vec<String>,
+    /// the content was cut at a stop text, the rest of the text is dropped
+    stopped: bool,
+    /// the prompt, then the last sampled token, to decode
+    pending: Vec<llama::Token>,
+    /// position of the first pending token in the sequence
+    position: usize,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    max_tokens: usize,
+    started: Instant,
+    first_token: Option<Instant>,
+}
+
+impl Slot {
+    fn start(
+        line: usize,
+        request: BatchRequest,
+        model: &skelm_exec::Model,
+        n_ctx_seq: usize,
+        default_max_tokens: usize,
+    ) -> Result<Self, String> {
+        if request.messages.is_empty() {
+            return Err("the request has no messages".to_string());
+        }
+        let max_tokens = request.max_tokens.unwrap_or(default_max_tokens);
+        if max_tokens == 0 {
+            return Err("max_tokens must be at least 1".to_string());
+        }
+        let prompt = model
+            .chat_render(&request.messages, &[], request.think)
+            .map_err(|e| format!("rendering the chat template: {}", e))?;
+        let tokens = model.vocab.tokenize(prompt.as_bytes(), true);
+        if tokens.len() >= n_ctx_seq {
+            return Err(format!(
+                "the prompt of {} tokens doesn't fit in the context of {} tokens of a sequence",
+                tokens.len(),
+                n_ctx_seq
+            ));
+        }
+        let markers = model.thinking_markers();
+        // tool calls written by the model stay whole in the content
+        let mut keep = vec![markers.start, markers.end];
+        if let Some(format) = model.tool_call_format() {
+            keep.extend_from_slice(format.control_texts());
+        }
+        let mut decoder =
+            skelm_exec::TokenTextDecoder::new(model.vocab.clone()).keep_control(&keep);
+        if let Some((starts, end)) = markers.headers() {
+            decoder = decoder.strip_headers(starts, end);
+        }
+        Ok(Self {
+            line,
+            sampler: request.sampler(),
+            id: request.id,
+            decoder,
+            thinking: skelm_exec::ThinkingParser::for_prompt(markers, &prompt),
+            content: String::new(),
+            reasoning: String::new(),
+            stop: request.stop,
+            stopped: false,
+            prompt_tokens: tokens.len(),
+            max_tokens: std::cmp::min(max_tokens, n_ctx_seq - tokens.len()),
+            pending: tokens,
+            position: 0,
+            completion_tokens: 0,
+            started: Instant::now(),
+            first_token: None,
+        })
+    }
+
+    /// Add a sampled token, returning why the generation is finished if it is
+    fn push(&mut self, token: llama::Token, vocab: &llama::Vocab) -> Option<FinishReason> {
+        self.first_token.get_or_insert_with(Instant::now);
+        if vocab.is_eog(token) {
+            return Some(FinishReason::Stop);
+        }
+        self.completion_tokens += 1;
+        let start = self.content.len();
+        for chunk in self.thinking.push(&self.decoder.push(token)) {
+            self.append(chunk);
+        }
+        if let Some(pos) = find_stop(&self.content, start, &self.stop) {
+            self.content.truncate(pos);
+            self.stopped = true;
+            return Some(FinishReason::Stop);
+        }
+        if self.completion_tokens >= self.max_tokens {
+            return Some(FinishReason::Length);
+        }
+        self.pending.push(token);
+        None
+    }
+
+    fn append(&mut self, chunk: skelm_exec::TextChunk) {
+        match chunk {
+            skelm_exec::TextChunk::Content(text) => self.content.push_str(&text),
+            skelm_exec::TextChunk::Reasoning(text) => self.reasoning.push_str(&text),
+        }
+    }
+
+    fn finish(mut self, finish_reason: FinishReason) -> BatchResult {
+        if !self.stopped {
+            let start = self.content.len();
+            let rest = self.decoder.finish();
+            for chunk in self
+                .thinking
+                .push(&rest)
+                .into_iter()
+                .chain(self.thinking.finish())
+            {
+                self.append(chunk);
+            }
+            if let Some(pos) = find_stop(&self.content, start, &self.stop) {
+                self.content.truncate(pos);
+            }
+        }
+        let end = Instant::now();
+        let first_token = self.first_token.unwrap_or(end);
+        BatchResult {
+            line: self.line,
+            id: self.id,
+            outcome: Outcome::Completion {
+                content: self.content,
+                reasoning_content: (!self.reasoning.is_empty()).then_some(self.reasoning),
+                finish_reason,
+                usage: Usage {
+                    prompt_tokens: self.prompt_tokens,
+                    completion_tokens: self.completion_tokens,
+                    total_tokens: self.prompt_tokens + self.completion_tokens,
+                },
+                timings: Timings {
+                    prompt_ms: (first_token - self.started).as_secs_f64() * 1000.0,
+                    generation_ms: (end - first_token).as_secs_f64() * 1000.0,
+                },
+            },
+        }
+    }
+}
+
+/// The position of the first stop text in `content`, searching only the ones the text
+/// appended from `from` can complete
+fn find_stop(content: &str, from: usize, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| {
+            let mut start = from.saturating_sub(stop.len() - 1);
+            while !content.is_char_boundary(start) {
+                start -= 1;
+            }
+            content[start..].find(stop.as_str()).map(|pos| start + pos)
+        })
+        .min()
+}
+
+/// Generate the requests, given with the number of their line, calling `on_result` as
+/// each one completes
+///
+/// The context is shared by the sequences, each one getting an equal part of it. The
+/// requests failing before their generation get an error result. Returns false if the
+/// job is interrupted by Ctrl-C, the requests being generated getting no result.
+pub fn run_batch(
+    context: &mut skelm_exec::Context,
+    requests: impl IntoIterator<Item = (usize, String)>,
+    params: &BatchParams,
+    mut on_result: impl FnMut(BatchResult) -> anyhow::Result<()>,
+) -> anyhow::Result<bool> {
+    let model = context.model().clone();
+    let context = &mut context.1;
+    let n_ctx_seq = context.n_ctx() as usize / params.parallel;
+
+    let quit_requested = run::quit_requested();
+    quit_requested.store(false, Ordering::Relaxed);
+
+    let mut requests = requests.into_iter();
+    let mut slots = (0..params.parallel)
+        .map(|_| None)
+        .collect::<Vec<Option<Slot>>>();
+    let mut batch = Vec::new();
+    // the slot of each token of the batch
+    let mut owners = Vec::new();
+    loop {
+        if quit_requested.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        for slot in slots.iter_mut().filter(|slot| slot.is_none()) {
+            for (line, json) in requests.by_ref() {
+                let request = match serde_json::from_str::<BatchRequest>(&json) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        on_result(BatchResult::failed(
+                            line,
+                            None,
+                            format!("invalid request: {}", e),
+                        ))?;
+                        continue;
+                    }
+                };
+                let id = request.id.clone();
+                match Slot::start(line, request, &model, n_ctx_seq, params.max_tokens) {
+                    Ok(started) => {
+                        *slot = Some(started);
+                        break;
+                    }
+                    Err(e) => on_result(BatchResult::failed(line, id, e))?,
+                }
+            }
+        }
+
+        batch.clear();
+        owners.clear();
+        for (i, slot) in slots.iter_mut().enumerate() {
+            let Some(slot) = slot else {
+                continue;
+            };
+            let n = slot.pending.len();
+            for (j, token) in slot.pending.drain(..).enumerate() {
+                batch.push(llama::SequenceToken {
+                    token,
+                    position: slot.position + j,
+                    seq: i as i32,
+                    // only the last token is sampled from
+                    logits: j + 1 == n,
+                });
+                owners.push(i);
+            }
+            slot.position += n;
+        }
+        if batch.is_empty() {
+            return Ok(true);
+        }
+
+        let mut sampled = Vec::new();
+        context.decode_sequences(&batch, |index, logits| {
+            let i = owners[index];
+            if let Some(slot) = &mut slots[i] {
+                sampled.push((i, slot.sampler.sample_logits(logits)));
+            }
+        })?;
+        for (i, token) in sampled {
+            let Some(slot) = &mut slots[i] else {
+                continue;
+            };
+            let Some(finish_reason) = slot.push(token, &model.vocab) else {
+                continue;
+            };
+            if let Some(slot) = slots[i].take() {
+                context.seq_remove(i as i32);
+                on_result(slot.finish(finish_reason))?;
+            }
+        }
+    }
+}
+
+/// The lines of the input completed by a job
+///
+/// Each line is recorded after its result, with the size of the output, so that a
+/// result written without being recorded is dropped when resuming.
+pub struct Checkpoint {
+    file: File,
+    pub done: HashSet<usize>,
+}
+
+impl Checkpoint {
+    pub fn path(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".checkpoint");
+        PathBuf::from(path)
+    }
+
+    /// Open the output of a job and its checkpoint, resuming the job if it was started,
+    /// or starting over when `force` is set
+    pub fn open(output: &Path, force: bool) -> anyhow::Result<(Self, File)> {
+        let path = Self::path(output);
+        let previous = if force {
+            None
+        } else {
+            match std::fs::read_to_string(&path) {
+                Ok(content) => Some(parse_checkpoint(&content)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("reading checkpoint {}", path.display()));
+                }
+            }
+        };
+
+        let Some((done, len)) = previous else {
+            if !force && output.exists() {
+                anyhow::bail!(
+                    "output file {} already exists without a checkpoint, use --force to overwrite it",
+                    output.display()
+                );
+            }
+            let output_file = File::create(output)
+                .with_context(|| format!("creating output file {}", output.display()))?;
+            let file = File::create(&path)
+                .with_context(|| format!("creating checkpoint {}", path.display()))?;
+            return Ok((
+                Self {
+                    file,
+                    done: HashSet::new(),
+                },
+                output_file,
+            ));
+        };
+        let mut output_file = OpenOptions::new()
+            .write(true)
+            .open(output)
+            .with_context(|| format!("opening output file {}", output.display()))?;
+        output_file.set_len(len)?;
+        output_file.seek(SeekFrom::End(0))?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening checkpoint {}", path.display()))?;
+        Ok((Self { file, done }, output_file))
+    }
+
+    /// Record a line whose result is written, the output being `output_len` bytes long
+    pub fn record(&mut self, line: usize, output_len: u64) -> std::io::Result<()> {
+        let record = format!("{} {}\n", line, output_len);
+        self.file.write_all(record.as_bytes())?;
+        self.done.insert(line);
+        Ok(())
+    }
+}
+
+/// The completed lines and the size of the output, ignoring a last record cut by an
+/// interruption
+fn parse_checkpoint(content: &str) -> (HashSet<usize>, u64) {
+    let mut done = HashSet::new();
+    let mut len = 0;
+    for record in content.split_inclusive('\n') {
+        let Some((line, output_len)) = record
+            .strip_suffix('\n')
+            .and_then(|record| record.split_once(' '))
+        else {
+            continue;
+        };
+        let (Ok(line), Ok(output_len)) = (line.parse(), output_len.parse()) else {
+            continue;
+        };
+        done.insert(line);
+        len = output_len;
+    }
+    (done, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_texts() {
+        let stop = ["wor".to_string(), "!".to_string()];
+        // "hello wo" was already searched
+        assert_eq!(find_stop("hello world!", 8, &stop), Some(6));
+        assert_eq!(find_stop("hello world!", 11, &stop), Some(11));
+        assert_eq!(find_stop("hello world", 0, &["".to_string()]), None);
+        assert_eq!(find_stop("héllo", 4, &["llo".to_string()]), Some(3));
+    }
+
+    #[test]
+    fn checkpoint_records() {
+        let (done, len) = parse_checkpoint("2 120\n1 250\n4 38");
+        assert_eq!(done, HashSet::from([1, 2]));
+        assert_eq!(len, 250);
+        assert_eq!(parse_checkpoint(""), (HashSet::new(), 0));
+    }
+}
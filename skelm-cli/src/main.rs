@@ -1,5 +1,7 @@
 use std::{
-    path::PathBuf,
+    collections::HashMap,
+    io::{Seek, Write},
+    path::{Path, PathBuf},
     process::exit,
     str::FromStr,
     time::{Duration, SystemTime},
@@ -7,18 +9,24 @@ use std::{
 
 use anyhow::Context;
 use clap::Parser;
+use futures_util::StreamExt;
 use skelm_exec::{ModelDescr, ModelParameters};
+use skelm_llama_cpp as llama;
 use skelm_ollama as ollama;
 use skelm_ollama::{OllamaConfig, OllamaStore};
 
 use reqwest::ClientBuilder;
 
+mod agent;
 mod args;
+mod batch;
 mod human;
+mod output;
 mod progressbar;
 mod run;
 
 use args::Cli;
+use output::Output;
 use progressbar::ProgressBar;
 
 use crate::human::bench_duration_units;
@@ -26,13 +34,43 @@ use crate::human::bench_duration_units;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let out = Output(cli.format);
 
+    // the registry queries don't use the store, which may not exist or be writable
     match cli.command {
-        args::Commands::List { filter } => cmd_list(filter).await,
-        args::Commands::Pull { name } => cmd_pull(name).await,
-        args::Commands::Remove { name } => cmd_remove(name).await,
-        args::Commands::Set { name, key, value } => cmd_set(name, key, value).await,
-        args::Commands::Verify { blobs } => cmd_verify(blobs).await,
+        args::Commands::Tags { name } => return cmd_tags(out, name).await,
+        args::Commands::Search { query } => return cmd_search(out, query).await,
+        _ => {}
+    }
+
+    let location = ollama::StoreLocation::resolve(cli.store.as_deref())?;
+    let store = location
+        .open()
+        .with_context(|| format!("opening store {:?}", location))?;
+
+    match cli.command {
+        args::Commands::List { filter } => cmd_list(&store, out, filter).await,
+        args::Commands::Pull { name } => cmd_pull(&store, out, name).await,
+        args::Commands::Tags { .. } | args::Commands::Search { .. } => unreachable!(),
+        args::Commands::Create { name, file } => cmd_create(&store, name, file).await,
+        args::Commands::Show { name, modelfile } => cmd_show(&store, name, modelfile).await,
+        args::Commands::Cp { src, dst, force } => cmd_cp(&store, src, dst, force).await,
+        args::Commands::Tag { name, tag, force } => cmd_tag(&store, name, tag, force).await,
+        args::Commands::Mv { src, dst, force } => cmd_mv(&store, src, dst, force).await,
+        args::Commands::Export { name, output } => cmd_export(&store, name, output).await,
+        args::Commands::Import { file, name } => cmd_import(&store, file, name).await,
+        args::Commands::Remove {
+            name,
+            no_gc,
+            min_age,
+        } => cmd_remove(&store, name, no_gc, min_age).await,
+        args::Commands::Gc { dry_run, min_age } => cmd_gc(&store, dry_run, min_age).await,
+        args::Commands::Set { name, key, value } => cmd_set(&store, name, key, value).await,
+        args::Commands::Verify {
+            blobs,
+            repair,
+            jobs,
+        } => cmd_verify(&store, out, blobs, repair, jobs).await,
         args::Commands::Run {
             name,
             debug,
@@ -41,16 +79,196 @@ async fn main() -> anyhow::Result<()> {
             system,
             input,
             output,
-        } => cmd_run(name, debug, model_path, no_prompt, system, input, output).await,
-        args::Commands::Info { name } => cmd_info(name).await,
-        args::Commands::Bench { name, max_tokens } => cmd_bench(name, max_tokens).await,
-        args::Commands::Embed { name } => cmd_embed(name).await,
+            hide_thinking,
+            think,
+            no_think,
+            tools,
+            tool_grammar,
+            image,
+            mmproj,
+            logprobs,
+            load,
+        } => {
+            let enable_thinking = match (think, no_think) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+            cmd_run(
+                &store,
+                out,
+                name,
+                debug,
+                model_path,
+                no_prompt,
+                system,
+                input,
+                output,
+                hide_thinking,
+                enable_thinking,
+                tools,
+                tool_grammar,
+                image,
+                mmproj,
+                logprobs,
+                load,
+            )
+            .await
+        }
+        args::Commands::Info {
+            name,
+            metadata,
+            tensors,
+        } => cmd_info(&store, out, name, metadata, tensors).await,
+        args::Commands::Bench {
+            name,
+            max_tokens,
+            load,
+        } => cmd_bench(&store, out, name, max_tokens, load).await,
+        args::Commands::Embed { name, load } => cmd_embed(&store, out, name, load).await,
+        args::Commands::Tokenize {
+            name,
+            text,
+            count,
+            pieces,
+            no_add_special,
+            no_parse_special,
+        } => {
+            cmd_tokenize(
+                &store,
+                out,
+                name,
+                text,
+                count,
+                pieces,
+                !no_add_special,
+                !no_parse_special,
+            )
+            .await
+        }
+        args::Commands::Detokenize {
+            name,
+            tokens,
+            remove_special,
+            no_unparse_special,
+        } => {
+            cmd_detokenize(
+                &store,
+                out,
+                name,
+                tokens,
+                remove_special,
+                !no_unparse_special,
+            )
+            .await
+        }
+        args::Commands::Perplexity {
+            name,
+            file,
+            window,
+            stride,
+            per_token,
+            load,
+        } => cmd_perplexity(&store, out, name, file, window, stride, per_token, load).await,
+        args::Commands::Classify {
+            name,
+            prompt,
+            choices,
+            length_normalized,
+            raw,
+            system,
+            load,
+        } => {
+            cmd_classify(
+                &store,
+                out,
+                name,
+                prompt,
+                choices,
+                length_normalized,
+                raw,
+                system,
+                load,
+            )
+            .await
+        }
+        args::Commands::Batch {
+            name,
+            input,
+            output,
+            parallel,
+            max_tokens,
+            force,
+            load,
+        } => {
+            cmd_batch(
+                &store, out, name, input, output, parallel, max_tokens, force, load,
+            )
+            .await
+        }
+        args::Commands::Quantize {
+            name,
+            ftype,
+            target,
+            threads,
+            imatrix,
+            leave_output_tensor,
+            pure,
+            force,
+        } => {
+            cmd_quantize(
+                &store,
+                name,
+                ftype,
+                target,
+                threads,
+                imatrix,
+                leave_output_tensor,
+                pure,
+                force,
+            )
+            .await
+        }
+        args::Commands::Agent {
+            name,
+            prompt,
+            config,
+            allow,
+            fetch_base_url,
+            max_steps,
+            yes,
+            transcript,
+            system,
+            tool_grammar,
+            load,
+        } => {
+            cmd_agent(
+                &store,
+                name,
+                prompt,
+                config,
+                allow,
+                fetch_base_url,
+                max_steps,
+                yes,
+                transcript,
+                system,
+                tool_grammar,
+                load,
+            )
+            .await
+        }
     }
 }
 
-async fn cmd_set(name: String, key: String, value: String) -> anyhow::Result<()> {
+async fn cmd_set(
+    store: &ollama::Store,
+    name: String,
+    key: String,
+    value: String,
+) -> anyhow::Result<()> {
     let model_descr = parse_ollama_descr(&name)?;
-    let store = OllamaStore::default();
+    let store = ollama_store(store)?;
     let mut manifest = store.get_manifest(&model_descr)?;
 
     match key.as_str() {
@@ -84,47 +302,444 @@ async fn cmd_set(name: String, key: String, value: String) -> anyhow::Result<()>
     Ok(())
 }
 
-async fn cmd_info(name: String) -> anyhow::Result<()> {
-    let model_descr = parse_model_descr(&name)?;
-    let model = skelm_exec::Model::load(&model_descr)?;
+async fn cmd_create(store: &ollama::Store, name: String, file: String) -> anyhow::Result<()> {
+    let model_descr = parse_ollama_descr(&name)?;
+    let store = ollama_store(store)?;
+
+    let content =
+        std::fs::read_to_string(&file).with_context(|| format!("reading modelfile {}", file))?;
+    let modelfile = ollama::Modelfile::from_str(&content)?;
+
+    let base_dir = PathBuf::from(&file)
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    let manifest = ollama::modelfile_create(store, &modelfile, &base_dir)?;
+
+    store.add_manifest(
+        &model_descr.registry,
+        &model_descr.model,
+        &model_descr.variant,
+        &manifest,
+    )?;
+    println!(
+        "created {} ({})",
+        model_descr,
+        human::size_units(manifest.size())
+    );
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn cmd_quantize(
+    store: &ollama::Store,
+    name: String,
+    ftype: llama::QuantType,
+    target: Option<String>,
+    threads: Option<u32>,
+    imatrix: Option<String>,
+    leave_output_tensor: bool,
+    pure: bool,
+    force: bool,
+) -> anyhow::Result<()> {
+    if ftype.needs_imatrix() && imatrix.is_none() {
+        anyhow::bail!(
+            "{} needs an importance matrix (--imatrix), it gives garbage without one",
+            ftype
+        )
+    }
+    let model_descr = parse_ollama_descr(&name)?;
+    let target = match target {
+        Some(target) => parse_ollama_descr(&target)?,
+        None => ollama::ModelDescr {
+            variant: ollama::Variant::from_str(&format!(
+                "{}-{}",
+                model_descr.variant.as_str(),
+                ftype.as_str().to_lowercase()
+            ))
+            .map_err(|e| anyhow::anyhow!(e))?,
+            ..model_descr.clone()
+        },
+    };
+    let store = ollama_store(store)?;
+    // checked early, quantizing takes a while
+    if !force && store.get_manifest(&target).is_ok() {
+        anyhow::bail!("model {} already exists", target)
+    }
+
+    let manifest = store.get_manifest(&model_descr)?;
+    let model_layer = manifest
+        .find_media_type(ollama::MEDIA_TYPE_IMAGE_MODEL)
+        .with_context(|| format!("model {} has no model layer", model_descr))?;
+    let input = store.blob_path(&model_layer.digest);
+
+    let mut params = llama::QuantizeParams::new(ftype);
+    params.threads = threads.unwrap_or(0);
+    params.imatrix = imatrix
+        .as_deref()
+        .map(|path| skelm_exec::load_imatrix(Path::new(path)))
+        .transpose()?;
+    params.leave_output_tensor = leave_output_tensor;
+    params.pure = pure;
+
+    run::llama_init_logging(false);
+    let output = store.file_path_tmp("quantize");
+    let quantized = {
+        let output = output.clone();
+        tokio::task::spawn_blocking(move || llama::quantize(input, output, &params)).await?
+    };
+    let manifest = quantized.map_err(anyhow::Error::from).and_then(|()| {
+        ollama::replace_model_layer(store, &model_descr, &output, ftype.as_str())
+            .map_err(anyhow::Error::from)
+    });
+    let _ = std::fs::remove_file(&output);
+    let manifest = manifest?;
+
+    store.add_manifest(&target.registry, &target.model, &target.variant, &manifest)?;
+    let size = manifest
+        .find_media_type(ollama::MEDIA_TYPE_IMAGE_MODEL)
+        .map(|l| l.size)
+        .unwrap_or_default();
+    println!(
+        "created {} from {} ({}, {})",
+        target,
+        model_descr,
+        ftype,
+        human::size_units(size)
+    );
+    Ok(())
+}
+
+async fn cmd_show(store: &ollama::Store, name: String, modelfile: bool) -> anyhow::Result<()> {
+    let model_descr = parse_ollama_descr(&name)?;
+    let store = ollama_store(store)?;
+    let manifest = store.get_manifest(&model_descr)?;
+
+    if modelfile {
+        let modelfile = ollama::Modelfile::from_manifest(store, &manifest)?;
+        println!("# Modelfile generated by \"llmup show\"");
+        println!("# To build a new Modelfile based on this, replace FROM with:");
+        println!("# FROM {}", model_descr);
+        println!();
+        print!("{}", modelfile);
+        return Ok(());
+    }
+
+    println!("{:50} {:80} {:15}", "MEDIA TYPE", "DIGEST", "SIZE");
+    println!(
+        "{:50} {:80} {:15}",
+        manifest.config.media_type,
+        manifest.config.digest.to_string(),
+        human::size_units(manifest.config.size)
+    );
+    for layer in &manifest.layers {
+        println!(
+            "{:50} {:80} {:15}",
+            layer.media_type,
+            layer.digest.to_string(),
+            human::size_units(layer.size)
+        );
+    }
+    Ok(())
+}
+
+async fn cmd_info(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    metadata: bool,
+    tensors: bool,
+) -> anyhow::Result<()> {
+    let path = resolve_model_file(store, &name)?;
+    let gguf = skelm_gguf::GgufFile::open(&path)
+        .with_context(|| format!("reading GGUF file {}", path.display()))?;
+
+    let mut tensor_types: Vec<(String, usize)> = Vec::new();
+    for tensor in gguf.tensors.iter() {
+        let ty = tensor.ty.to_string();
+        match tensor_types.iter_mut().find(|(t, _)| t == &ty) {
+            Some((_, n)) => *n += 1,
+            None => tensor_types.push((ty, 1)),
+        }
+    }
+    tensor_types.sort_by(|(_, n1), (_, n2)| n2.cmp(n1));
+
+    let info = output::InfoOutput {
+        name,
+        path: path.display().to_string(),
+        gguf_version: gguf.version,
+        architecture: gguf.architecture().map(|s| s.to_string()),
+        model_name: gguf.get_str("general.name").map(|s| s.to_string()),
+        parameter_count: gguf.parameter_count(),
+        context_length: gguf.get_arch_u64("context_length"),
+        block_count: gguf.get_arch_u64("block_count"),
+        embedding_length: gguf.get_arch_u64("embedding_length"),
+        tensor_count: gguf.tensors.len(),
+        tensors_size: gguf.tensors_size(),
+        tensor_types,
+        memory: {
+            let estimate =
+                skelm_exec::estimate_memory(&gguf, &skelm_exec::LoadParams::default().context);
+            output::InfoMemory {
+                n_ctx: estimate.n_ctx,
+                weights: estimate.weights,
+                kv_cache: estimate.kv_cache,
+                compute: estimate.compute,
+                total: estimate.total(),
+            }
+        },
+        chat_template: gguf
+            .get_str("tokenizer.chat_template")
+            .map(|s| s.to_string()),
+        metadata: metadata.then(|| {
+            gguf.metadata
+                .iter()
+                .map(|(k, v)| (k.clone(), v.to_string()))
+                .collect()
+        }),
+        tensors: tensors.then(|| {
+            gguf.tensors
+                .iter()
+                .map(|t| output::InfoTensor {
+                    name: t.name.clone(),
+                    dims: t.dims.clone(),
+                    ty: t.ty.to_string(),
+                    offset: t.offset,
+                    size: t.size(),
+                })
+                .collect()
+        }),
+    };
+
+    if !out.is_table() {
+        return out.value(&info);
+    }
+
+    let opt = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+    println!("path               : {}", info.path);
+    println!("gguf version       : {}", info.gguf_version);
+    println!(
+        "architecture       : {}",
+        info.architecture.unwrap_or_default()
+    );
+    println!(
+        "name               : {}",
+        info.model_name.unwrap_or_default()
+    );
+    println!("parameters         : {}", info.parameter_count);
+    println!("context length     : {}", opt(info.context_length));
+    println!("blocks             : {}", opt(info.block_count));
+    println!("embedding length   : {}", opt(info.embedding_length));
+    println!("tensors            : {}", info.tensor_count);
+    println!(
+        "tensors size       : {}",
+        info.tensors_size.map(human::size_units).unwrap_or_default()
+    );
+    let types = info
+        .tensor_types
+        .iter()
+        .map(|(ty, n)| format!("{} ({})", ty, n))
+        .collect::<Vec<_>>();
+    println!("tensor types       : {}", types.join(", "));
+    println!(
+        "memory (ctx {:6}) : {} (weights {}, kv cache {}, compute {})",
+        info.memory.n_ctx,
+        human::size_units(info.memory.total),
+        human::size_units(info.memory.weights),
+        human::size_units(info.memory.kv_cache),
+        human::size_units(info.memory.compute)
+    );
 
-    if let Some(chat_template) = model.model.chat_template() {
+    if let Some(metadata) = info.metadata {
+        println!();
+        for (k, v) in metadata {
+            println!("{:40} {}", k, v)
+        }
+    }
+    if let Some(tensors) = info.tensors {
+        println!();
+        println!("{:40} {:25} {:10} {:15}", "TENSOR", "SHAPE", "TYPE", "SIZE");
+        for t in tensors {
+            println!(
+                "{:40} {:25} {:10} {:15}",
+                t.name,
+                format!("{:?}", t.dims),
+                t.ty,
+                t.size.map(human::size_units).unwrap_or_default()
+            )
+        }
+    }
+    if let Some(chat_template) = info.chat_template {
+        println!();
         println!("chat-template:\n{}", chat_template)
     }
 
     Ok(())
 }
 
-async fn cmd_embed(name: String) -> anyhow::Result<()> {
+async fn cmd_embed(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    load: args::LoadArgs,
+) -> anyhow::Result<()> {
     let model_descr = parse_model_descr(&name)?;
-    let model = skelm_exec::Model::load(&model_descr)?;
-
     run::llama_init_logging(false);
+    let model =
+        skelm_exec::Model::load_with(store.as_model_store(), &model_descr, &load.load_params())?;
 
-    let mut context = model.new_context_embeddings().1;
+    let mut context = model.new_context_embeddings()?.1;
 
     let pooling_type = context.pooling_type();
 
-    println!("pooling type: {:?}", pooling_type);
+    if out.is_table() {
+        println!("pooling type: {:?}", pooling_type);
+    }
 
     let tokens = model.vocab.tokenize(b"test", true);
 
     let e = context.embeddings(&tokens)?;
 
+    if !out.is_table() {
+        return out.value(&output::EmbedOutput {
+            model: name,
+            pooling_type: format!("{:?}", pooling_type),
+            embedding: e,
+        });
+    }
+
     println!("embedding {:?}", e);
     Ok(())
 }
 
-async fn cmd_bench(name: String, max_tokens: Option<u64>) -> anyhow::Result<()> {
+/// Load only the vocabulary of a model, which is fast and needs little memory
+fn load_vocab(store: &ollama::Store, name: &str) -> anyhow::Result<skelm_llama_cpp::Vocab> {
+    let path = resolve_model_file(store, name)?;
+    run::llama_init_logging(false);
+    let params = skelm_llama_cpp::ModelParams { vocab_only: true };
+    let model = skelm_llama_cpp::Model::load(&path, &params)?;
+    Ok(model.vocab())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn cmd_tokenize(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    text: Option<String>,
+    count: bool,
+    pieces: bool,
+    add_special: bool,
+    parse_special: bool,
+) -> anyhow::Result<()> {
+    let text = match text {
+        Some(text) => text,
+        None => std::io::read_to_string(std::io::stdin()).context("reading stdin")?,
+    };
+    let vocab = load_vocab(store, &name)?;
+
+    if count {
+        let count = vocab.tokenize_count(text.as_bytes(), add_special, parse_special);
+        if out.is_table() {
+            println!("{}", count);
+            return Ok(());
+        }
+        return out.value(&output::TokenizeOutput {
+            count,
+            tokens: None,
+        });
+    }
+
+    let tokens = vocab.tokenize_with(text.as_bytes(), add_special, parse_special);
+    let result = output::TokenizeOutput {
+        count: tokens.len(),
+        tokens: Some(
+            tokens
+                .iter()
+                .map(|t| output::TokenEntry {
+                    id: t.id(),
+                    piece: vocab.as_string_lossy(*t),
+                })
+                .collect(),
+        ),
+    };
+    if !out.is_table() {
+        return out.value(&result);
+    }
+
+    let entries = result.tokens.unwrap_or_default();
+    if pieces {
+        for entry in entries {
+            println!("{:>8} {:?}", entry.id, entry.piece)
+        }
+    } else {
+        let ids = entries
+            .iter()
+            .map(|entry| entry.id.to_string())
+            .collect::<Vec<_>>();
+        println!("{}", ids.join(" "))
+    }
+    Ok(())
+}
+
+async fn cmd_detokenize(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    ids: Vec<u32>,
+    remove_special: bool,
+    unparse_special: bool,
+) -> anyhow::Result<()> {
+    let ids = if ids.is_empty() {
+        let input = std::io::read_to_string(std::io::stdin()).context("reading stdin")?;
+        input
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse::<u32>()
+                    .with_context(|| format!("invalid token id {:?}", s))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    } else {
+        ids
+    };
+    let vocab = load_vocab(store, &name)?;
+
+    let tokens = ids
+        .iter()
+        .map(|id| {
+            vocab
+                .token(*id)
+                .with_context(|| format!("token id {} is not in the vocabulary", id))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let bytes = vocab.detokenize_bytes(&tokens, remove_special, unparse_special);
+    let text = String::from_utf8_lossy(&bytes).into_owned();
+
+    if !out.is_table() {
+        return out.value(&output::DetokenizeOutput { text });
+    }
+    println!("{}", text);
+    Ok(())
+}
+
+async fn cmd_bench(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    max_tokens: Option<u64>,
+    load: args::LoadArgs,
+) -> anyhow::Result<()> {
     let model_descr = parse_model_descr(&name)?;
 
     let max_tokens = max_tokens.unwrap_or(u64::MAX);
 
     run::llama_init_logging(false);
 
-    let model = skelm_exec::Model::load(&model_descr)?;
+    let model =
+        skelm_exec::Model::load_with(store.as_model_store(), &model_descr, &load.load_params())?;
 
-    let mut context = model.new_context().1;
+    let mut context = model.new_context()?.1;
     let vocab = model.vocab;
 
     const BENCHMARK_CONTEXT: &str = "this is a context for doing tokens benchmarks";
@@ -169,6 +784,17 @@ async fn cmd_bench(name: String, max_tokens: Option<u64>) -> anyhow::Result<()>
         .unwrap_or(Duration::ZERO);
 
     let tps = token_generated as f64 / dur.as_secs_f64();
+
+    if !out.is_table() {
+        return out.value(&output::BenchOutput {
+            model: name,
+            tokens_generated: token_generated,
+            elapsed_secs: dur.as_secs_f64(),
+            tokens_per_second: tps,
+            secs_per_token: dur_per_token.as_secs_f64(),
+        });
+    }
+
     let time_token = bench_duration_units(dur_per_token);
 
     println!("model              : {}", name);
@@ -180,7 +806,268 @@ async fn cmd_bench(name: String, max_tokens: Option<u64>) -> anyhow::Result<()>
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+async fn cmd_perplexity(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    file: String,
+    window: usize,
+    stride: Option<usize>,
+    per_token: bool,
+    load: args::LoadArgs,
+) -> anyhow::Result<()> {
+    let model_descr = parse_model_descr(&name)?;
+    let text =
+        std::fs::read_to_string(&file).with_context(|| format!("reading text file {}", file))?;
+    let params = skelm_exec::ScoreParams {
+        window,
+        stride: stride.unwrap_or(std::cmp::max(window / 2, 1)),
+    };
+
+    run::llama_init_logging(false);
+
+    let mut load_params = load.load_params();
+    if load.ctx.is_none() {
+        load_params.context.n_ctx = window as u32;
+    }
+    let model = skelm_exec::Model::load_with(store.as_model_store(), &model_descr, &load_params)?;
+    let mut context = model.new_context()?;
+    let tokens = model.vocab.tokenize(text.as_bytes(), true);
+
+    // the first token is never scored
+    let bar = indicatif::ProgressBar::new(tokens.len().saturating_sub(1) as u64);
+    bar.set_style(indicatif::ProgressStyle::with_template(
+        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} tokens scored, {msg} ({eta})",
+    )?);
+    let start = SystemTime::now();
+    let score = context.score_tokens(&tokens, &params, |score| {
+        bar.set_position(score.tokens.len() as u64);
+        bar.set_message(format!("perplexity {:.4}", score.perplexity()));
+    })?;
+    bar.finish_and_clear();
+    let dur = SystemTime::now()
+        .duration_since(start)
+        .unwrap_or(Duration::ZERO);
+
+    if !out.is_table() {
+        let token_logprobs = per_token.then(|| {
+            score
+                .tokens
+                .iter()
+                .map(|t| output::TokenLogprob {
+                    token: t.token.id(),
+                    text: model.vocab.as_string_lossy(t.token),
+                    logprob: t.logprob,
+                })
+                .collect()
+        });
+        return out.value(&output::PerplexityOutput {
+            model: name,
+            file,
+            window: params.window,
+            stride: params.stride,
+            tokens: score.tokens.len(),
+            nll: score.nll,
+            mean_nll: score.mean_nll(),
+            perplexity: score.perplexity(),
+            token_logprobs,
+        });
+    }
+
+    println!("model              : {}", name);
+    println!("file               : {}", file);
+    println!("window / stride    : {} / {}", params.window, params.stride);
+    println!("tokens scored      : {}", score.tokens.len());
+    println!("negative log-lik.  : {:.4}", score.nll);
+    println!("mean nll           : {:.6}", score.mean_nll());
+    println!("perplexity         : {:.4}", score.perplexity());
+    println!("elapsed            : {}", bench_duration_units(dur));
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn cmd_classify(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    prompt: Option<String>,
+    choices: Vec<String>,
+    length_normalized: bool,
+    raw: bool,
+    system: Option<String>,
+    load: args::LoadArgs,
+) -> anyhow::Result<()> {
+    let model_descr = parse_model_descr(&name)?;
+    let prompt = match prompt {
+        Some(prompt) => prompt,
+        None => std::io::read_to_string(std::io::stdin()).context("reading stdin")?,
+    };
+
+    run::llama_init_logging(false);
+
+    // the prompt and each candidate have their own sequence
+    let mut load_params = load.load_params();
+    load_params.context.n_seq_max = choices.len() as u32 + 1;
+    load_params.context.kv_unified = true;
+    let model = skelm_exec::Model::load_with(store.as_model_store(), &model_descr, &load_params)?;
+
+    let prompt = if raw {
+        prompt
+    } else {
+        // the candidates start the answer, not a reasoning
+        model.model_template_render(&ModelParameters {
+            system: system.unwrap_or_default(),
+            prompt,
+            enable_thinking: Some(false),
+            tools: Vec::new(),
+            images: Vec::new(),
+        })
+    };
+    let mut context = model.new_context()?;
+    let mut scores = context.classify(&prompt, &choices, length_normalized)?;
+
+    if !out.is_table() {
+        return out.value(&output::ClassifyOutput {
+            model: name,
+            length_normalized,
+            candidates: scores
+                .into_iter()
+                .map(|score| output::CandidateScore {
+                    text: score.text,
+                    tokens: score.tokens,
+                    logprob: score.logprob,
+                    probability: score.probability,
+                })
+                .collect(),
+        });
+    }
+
+    scores.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+    println!(
+        "{:>11} {:>12} {:>6}  CANDIDATE",
+        "PROBABILITY", "LOGPROB", "TOKENS"
+    );
+    for score in scores {
+        println!(
+            "{:>10.2}% {:>12.4} {:>6}  {:?}",
+            score.probability * 100.0,
+            score.logprob,
+            score.tokens,
+            score.text
+        );
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn cmd_batch(
+    store: &ollama::Store,
+    out: Output,
+    name: String,
+    input: String,
+    output: String,
+    parallel: u32,
+    max_tokens: usize,
+    force: bool,
+    load: args::LoadArgs,
+) -> anyhow::Result<()> {
+    let model_descr = parse_model_descr(&name)?;
+    let content =
+        std::fs::read_to_string(&input).with_context(|| format!("reading input file {}", input))?;
+    let (mut checkpoint, mut output_file) = batch::Checkpoint::open(Path::new(&output), force)?;
+    let skipped = checkpoint.done.len();
+    // line numbers start at 1, the empty lines are not requests
+    let requests = content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(n, line)| !line.trim().is_empty() && !checkpoint.done.contains(n))
+        .map(|(n, line)| (n, line.to_string()))
+        .collect::<Vec<_>>();
+    if requests.is_empty() {
+        eprintln!("all the requests of {} are done", input);
+        return Ok(());
+    }
+
+    run::llama_init_logging(false);
+
+    let mut load_params = load.load_params();
+    load_params.context.n_seq_max = parallel;
+    load_params.context.kv_unified = true;
+    let model = skelm_exec::Model::load_with(store.as_model_store(), &model_descr, &load_params)?;
+    let mut context = model.new_context()?;
+
+    let bar = indicatif::ProgressBar::new(requests.len() as u64);
+    bar.set_style(indicatif::ProgressStyle::with_template(
+        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} requests ({eta})",
+    )?);
+    let mut summary = output::BatchOutput {
+        model: name,
+        input,
+        output,
+        completed: 0,
+        failed: 0,
+        skipped,
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        interrupted: false,
+    };
+    let params = batch::BatchParams {
+        parallel: parallel as usize,
+        max_tokens,
+    };
+    let start = SystemTime::now();
+    let finished = batch::run_batch(&mut context, requests, &params, |result| {
+        let mut json = serde_json::to_string(&result)?;
+        json.push('\n');
+        output_file.write_all(json.as_bytes())?;
+        checkpoint.record(result.line, output_file.stream_position()?)?;
+        match &result.outcome {
+            batch::Outcome::Completion { usage, .. } => {
+                summary.completed += 1;
+                summary.prompt_tokens += usage.prompt_tokens;
+                summary.completion_tokens += usage.completion_tokens;
+            }
+            batch::Outcome::Error { error } => {
+                bar.suspend(|| eprintln!("line {}: {}", result.line, error));
+                summary.failed += 1;
+            }
+        }
+        bar.inc(1);
+        Ok(())
+    })?;
+    bar.finish_and_clear();
+    let dur = SystemTime::now()
+        .duration_since(start)
+        .unwrap_or(Duration::ZERO);
+    summary.interrupted = !finished;
+    if summary.interrupted {
+        eprintln!("interrupted, run the same command again to resume");
+    }
+
+    if !out.is_table() {
+        return out.value(&summary);
+    }
+
+    println!("model              : {}", summary.model);
+    println!(
+        "input / output     : {} / {}",
+        summary.input, summary.output
+    );
+    println!("completed          : {}", summary.completed);
+    println!("failed             : {}", summary.failed);
+    println!("done previously    : {}", summary.skipped);
+    println!("prompt tokens      : {}", summary.prompt_tokens);
+    println!("completion tokens  : {}", summary.completion_tokens);
+    println!("elapsed            : {}", bench_duration_units(dur));
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn cmd_run(
+    store: &ollama::Store,
+    out: Output,
     name: String,
     debug: bool,
     model_path: bool,
@@ -188,13 +1075,21 @@ async fn cmd_run(
     system: Option<String>,
     input: Option<String>,
     output: Option<String>,
+    hide_thinking: bool,
+    enable_thinking: Option<bool>,
+    tools: Option<String>,
+    tool_grammar: bool,
+    images: Vec<String>,
+    mmproj: Option<String>,
+    logprobs: Option<usize>,
+    load: args::LoadArgs,
 ) -> anyhow::Result<()> {
     const DEFAULT_SYSTEM_PROMPT: &str = "you are a chatbot answering question";
 
     let model_descr = if model_path {
         ModelDescr::Path(PathBuf::from(name))
     } else {
-        ModelDescr::Ollama(ollama::ModelDescr::from_str(&name).unwrap())
+        parse_model_descr(&name)?
     };
 
     let input_data = if let Some(input_file) = input {
@@ -228,74 +1123,246 @@ async fn cmd_run(
     run::llama_init_logging(debug);
     tracing_subscriber::fmt::init();
 
-    let model = skelm_exec::Model::load(&model_descr)?;
+    let images = images
+        .into_iter()
+        .map(|image| skelm_exec::Image::Path(PathBuf::from(image)))
+        .collect::<Vec<_>>();
+    let load_params = skelm_exec::LoadParams {
+        vision: !images.is_empty(),
+        projector_path: mmproj.map(PathBuf::from),
+        ..load.load_params()
+    };
+    let model = skelm_exec::Model::load_with(store.as_model_store(), &model_descr, &load_params)?;
+
+    let tools = match tools {
+        Some(file) => {
+            let json = std::fs::read_to_string(&file)
+                .with_context(|| format!("reading tools file {}", file))?;
+            ollama::Tool::list_from_json(&json)
+                .with_context(|| format!("parsing tools file {}", file))?
+        }
+        None => Vec::new(),
+    };
+    let tool_format = if tools.is_empty() {
+        None
+    } else {
+        let format = model.tool_call_format();
+        if format.is_none() {
+            eprintln!("the chat template of the model doesn't support tool calls");
+        }
+        format
+    };
+    let grammar = if tool_grammar {
+        model.tool_call_sampler(&tools)?
+    } else {
+        None
+    };
 
-    let parameters = ModelParameters { system, prompt };
+    let parameters = ModelParameters {
+        system,
+        prompt,
+        enable_thinking,
+        tools,
+        images,
+    };
     let template = model.model_template_render(&parameters);
 
-    let mut context = model.new_context();
-    run::llama_run(&mut context, &template, &output)?;
+    let mut context = model.new_context()?;
+    let options = run::RunOptions {
+        hide_thinking,
+        stream: out.is_table(),
+        tool_format,
+        grammar,
+        logprobs,
+    };
+    let generation = run::llama_run(
+        &mut context,
+        &template,
+        &parameters.images,
+        &output,
+        options,
+    )?;
+    if !out.is_table() {
+        return out.value(&output::RunOutput {
+            model: model_descr.to_string(),
+            content: generation.content,
+            reasoning_content: (!generation.reasoning.is_empty()).then_some(generation.reasoning),
+            tool_calls: generation.tool_calls,
+            logprobs: logprobs.map(|_| output::RunLogprobs {
+                content: generation.logprobs,
+            }),
+        });
+    }
+    for call in generation.tool_calls {
+        println!();
+        println!("tool call: {} {}", call.name, call.arguments);
+    }
     Ok(())
 }
 
-async fn cmd_list(filter: Option<String>) -> anyhow::Result<()> {
-    let store = OllamaStore::default();
-    let regs = store.list_registries()?;
+#[allow(clippy::too_many_arguments)]
+async fn cmd_agent(
+    store: &ollama::Store,
+    name: String,
+    prompt: Option<String>,
+    config: Option<String>,
+    allow: Vec<String>,
+    fetch_base_url: Option<String>,
+    max_steps: u32,
+    yes: bool,
+    transcript: Option<String>,
+    system: Option<String>,
+    tool_grammar: bool,
+    load: args::LoadArgs,
+) -> anyhow::Result<()> {
+    const DEFAULT_SYSTEM_PROMPT: &str =
+        "you are an assistant answering questions with the help of the tools you can call";
 
-    let mut model_lines = Vec::new();
+    let mut config = match config {
+        Some(file) => agent::AgentConfig::load(std::path::Path::new(&file))?,
+        None => agent::AgentConfig::default(),
+    };
+    config.shell.allow.extend(allow);
+    if let Some(base_url) = fetch_base_url {
+        config.fetch = Some(agent::FetchConfig { base_url });
+    }
+    let agent = agent::Agent::new(config, !yes)?;
+    let tools = agent.tools();
+    let mut transcript = agent::Transcript::open(transcript.as_deref())?;
 
-    let now = SystemTime::now();
-    for reg in regs {
-        println!("{:?}", reg);
-        let models = store.list_models(&reg)?;
-        for model in models {
-            let variants = store.list_model_variants(&reg, &model)?;
-            for variant in variants {
-                let manifest_path =
-                    store.manifest_registry_model_variant_path(&reg, &model, &variant);
-                let fs = tokio::fs::File::open(manifest_path).await?;
-                let metadata = fs.metadata().await?;
-                let modified = metadata.modified()?;
-
-                let descr = ollama::ModelDescr {
-                    registry: reg.clone(),
-                    model: model.clone(),
-                    variant: variant.clone(),
-                };
-                let manifest = store.get_manifest(&descr)?;
-                let size = manifest.size();
-                let name = format!("{}:{}", model.as_str(), variant.as_str());
-                let acceptable = if let Some(filter) = &filter {
-                    name.starts_with(filter)
+    run::llama_init_logging(false);
+    let model_descr = parse_model_descr(&name)?;
+    let model =
+        skelm_exec::Model::load_with(store.as_model_store(), &model_descr, &load.load_params())?;
+    let Some(tool_format) = model.tool_call_format() else {
+        anyhow::bail!(
+            "the chat template of {} doesn't support tool calls",
+            model_descr
+        )
+    };
+
+    let system = skelm_exec::ChatMessage::system(
+        system.unwrap_or_else(|| DEFAULT_SYSTEM_PROMPT.to_string()),
+    );
+    transcript.log(&system)?;
+    let mut messages = vec![system];
+    let mut rl = rustyline::DefaultEditor::new()?;
+    let mut prompt = prompt;
+    loop {
+        let line = match prompt.take() {
+            Some(line) => line,
+            None => match rl.readline(">> ") {
+                Ok(line) if !line.trim().is_empty() => line,
+                Ok(_)
+                | Err(rustyline::error::ReadlineError::Eof)
+                | Err(rustyline::error::ReadlineError::Interrupted) => return Ok(()),
+                Err(e) => anyhow::bail!("error {:?}", e),
+            },
+        };
+        let message = skelm_exec::ChatMessage::user(line);
+        transcript.log(&message)?;
+        messages.push(message);
+
+        let mut answered = false;
+        for _ in 0..max_steps {
+            let rendered = model
+                .chat_render(&messages, &tools, None)
+                .map_err(|e| anyhow::anyhow!("rendering the chat template: {}", e))?;
+            let mut context = model.new_context()?;
+            let options = run::RunOptions {
+                stream: true,
+                tool_format: Some(tool_format),
+                grammar: if tool_grammar {
+                    model.tool_call_sampler(&tools)?
                 } else {
-                    true
-                };
-                if acceptable {
-                    let dur = now.duration_since(modified).unwrap_or(Duration::ZERO);
-                    model_lines.push((name, size, dur))
-                }
+                    None
+                },
+                ..Default::default()
+            };
+            let generation = run::llama_run(&mut context, &rendered, &[], &None, options)?;
+            println!();
+
+            let calls = generation.tool_calls.clone();
+            let message =
+                skelm_exec::ChatMessage::assistant(generation.content, generation.tool_calls);
+            transcript.log(&message)?;
+            messages.push(message);
+            if calls.is_empty() {
+                answered = true;
+                break;
             }
+            for call in calls {
+                eprintln!("tool call: {} {}", call.name, call.arguments);
+                let result = agent.call(&call).await;
+                let message = skelm_exec::ChatMessage::tool(call.name, result);
+                transcript.log(&message)?;
+                messages.push(message);
+            }
+        }
+        if !answered {
+            eprintln!("stopped after {} steps without an answer", max_steps);
+        }
+    }
+}
+
+async fn cmd_list(
+    store: &ollama::Store,
+    out: Output,
+    filter: Option<String>,
+) -> anyhow::Result<()> {
+    let store = store.as_model_store();
+
+    let mut model_lines = Vec::new();
+
+    for descr in store.list_model_descrs()? {
+        let name = format!("{}:{}", descr.model.as_str(), descr.variant.as_str());
+        let acceptable = if let Some(filter) = &filter {
+            name.starts_with(filter)
+        } else {
+            true
+        };
+        if acceptable {
+            let size = store.model_size(&descr)?;
+            let modified = store.model_modified(&descr)?;
+            model_lines.push(output::ListEntry {
+                registry: descr.registry.as_str().to_string(),
+                name,
+                size,
+                modified: modified
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO)
+                    .as_secs(),
+            })
         }
     }
 
-    model_lines.sort_by(|(_, _, m1), (_, _, m2)| m1.cmp(m2));
+    model_lines.sort_by_key(|e| std::cmp::Reverse(e.modified));
 
+    if !out.is_table() {
+        return out.values(&model_lines);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
     println!("{:40} {:15} {:15}", "NAME", "SIZE", "MODIFIED");
-    for (model_name, size, modified) in model_lines {
+    for entry in model_lines {
+        let modified = Duration::from_secs(now.saturating_sub(entry.modified));
         println!(
             "{:40} {:15} {:15}",
-            model_name,
-            human::size_units(size),
+            entry.name,
+            human::size_units(entry.size),
             format!("{} ago", human::duration_units(modified)),
         )
     }
     Ok(())
 }
 
-async fn cmd_pull(name: String) -> anyhow::Result<()> {
+async fn cmd_pull(store: &ollama::Store, out: Output, name: String) -> anyhow::Result<()> {
     let model_descr = parse_ollama_descr(&name)?;
 
-    let store = OllamaStore::default();
+    let store = ollama_store(store)?;
     let config = OllamaConfig::default();
     let client = ClientBuilder::new()
         .user_agent("llmup/0.1")
@@ -306,13 +1373,38 @@ async fn cmd_pull(name: String) -> anyhow::Result<()> {
     let download_results = skelm_download::ollama::download_model::<ProgressBar>(
         &client,
         &config,
-        &store,
+        store,
         &model_descr.registry,
         &model_descr.model,
         &model_descr.variant,
     )
     .await?;
 
+    if !out.is_table() {
+        let layers = download_results
+            .into_iter()
+            .map(|(media_type, result)| {
+                let (blob, status) = match result {
+                    skelm_download::ollama::DownloadResult::Skipped(blob) => {
+                        (blob, output::PullStatus::Skipped)
+                    }
+                    skelm_download::ollama::DownloadResult::Success(blob) => {
+                        (blob, output::PullStatus::Downloaded)
+                    }
+                };
+                output::PullLayer {
+                    media_type,
+                    digest: blob.to_string(),
+                    status,
+                }
+            })
+            .collect();
+        return out.value(&output::PullOutput {
+            name: model_descr.to_string(),
+            layers,
+        });
+    }
+
     for (download_name, download_result) in download_results {
         let r = match download_result {
             skelm_download::ollama::DownloadResult::Skipped(blob) => {
@@ -326,69 +1418,488 @@ async fn cmd_pull(name: String) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn cmd_remove(name: String) -> anyhow::Result<()> {
+async fn cmd_tags(out: Output, name: String) -> anyhow::Result<()> {
+    let model_descr = parse_ollama_descr(&name)?;
+    let config = OllamaConfig::for_registry(&model_descr.registry)?;
+    let client = ClientBuilder::new().user_agent("llmup/0.1").build()?;
+
+    let tags = skelm_download::ollama::list_tags(&client, &config, &model_descr.model).await?;
+
+    // a few tags are queried at a time, keeping their order
+    let entries = futures_util::stream::iter(tags)
+        .map(|tag| tag_entry(&client, &config, &model_descr.model, tag))
+        .buffered(8)
+        .collect::<Vec<_>>()
+        .await;
+
+    if !out.is_table() {
+        return out.values(&entries);
+    }
+
+    println!(
+        "{:40} {:15} {:10} {:10} {:15}",
+        "NAME", "SIZE", "QUANT", "PARAMS", "FAMILY"
+    );
+    for entry in entries {
+        if let Some(e) = entry.error.as_ref().filter(|_| entry.size.is_none()) {
+            println!("{:40} error: {}", entry.name, e);
+            continue;
+        }
+        println!(
+            "{:40} {:15} {:10} {:10} {:15}",
+            entry.name,
+            entry.size.map(human::size_units).unwrap_or_default(),
+            entry.quantization.unwrap_or_default(),
+            entry.parameter_size.unwrap_or_default(),
+            entry.family.unwrap_or_default(),
+        )
+    }
+    Ok(())
+}
+
+/// The size and the metadata of a tag, only the manifest and the small config blob are
+/// fetched, not the layers
+async fn tag_entry(
+    client: &reqwest::Client,
+    config: &OllamaConfig,
+    model: &ollama::Model,
+    tag: ollama::Variant,
+) -> output::TagEntry {
+    let name = format!("{}:{}", model.as_str(), tag.as_str());
+    let manifest = skelm_download::ollama::fetch_manifest(client, config, model, &tag).await;
+    match manifest {
+        Err(e) => output::TagEntry {
+            name,
+            size: None,
+            quantization: None,
+            parameter_size: None,
+            family: None,
+            error: Some(e.to_string()),
+        },
+        Ok(manifest) => {
+            let image_config =
+                skelm_download::ollama::fetch_image_config(client, config, &manifest).await;
+            let non_empty = |s: String| (!s.is_empty()).then_some(s);
+            let (quantization, parameter_size, family, error) = match image_config {
+                Ok(c) => (
+                    non_empty(c.file_type),
+                    non_empty(c.model_type),
+                    non_empty(c.model_family),
+                    None,
+                ),
+                Err(e) => (None, None, None, Some(e.to_string())),
+            };
+            output::TagEntry {
+                name,
+                size: Some(manifest.size()),
+                quantization,
+                parameter_size,
+                family,
+                error,
+            }
+        }
+    }
+}
+
+async fn cmd_search(out: Output, query: String) -> anyhow::Result<()> {
+    let config = OllamaConfig::default();
+    let client = ClientBuilder::new().user_agent("llmup/0.1").build()?;
+
+    let models = skelm_download::ollama::search_models(&client, &config, &query).await?;
+
+    if !out.is_table() {
+        return out.values(&models);
+    }
+    for model in models {
+        println!("{}", model)
+    }
+    Ok(())
+}
+
+async fn cmd_remove(
+    store: &ollama::Store,
+    name: String,
+    no_gc: bool,
+    min_age: u64,
+) -> anyhow::Result<()> {
     let skelm_exec::ModelDescr::Ollama(model_descr) = parse_model_descr(&name)? else {
         anyhow::bail!("ollama invalid name")
     };
-    let store = OllamaStore::default();
+    let store = ollama_store(store)?;
+    let manifest = store.get_manifest(&model_descr)?;
     store.remove_manifest(
         &model_descr.registry,
         &model_descr.model,
         &model_descr.variant,
     )?;
 
+    if !no_gc {
+        // only collect the blobs of the removed model, not the ones of unrelated downloads
+        let plan =
+            store.gc_plan_blobs(manifest.all_digests(), Duration::from_secs(min_age * 3600))?;
+        let freed = store.gc_apply(&plan)?;
+        if freed > 0 {
+            println!("reclaimed {}", human::size_units(freed));
+        }
+    }
+
     Ok(())
 }
 
-async fn cmd_verify(blobs: bool) -> anyhow::Result<()> {
-    let store = OllamaStore::default();
-    let regs = store.list_registries()?;
+async fn cmd_cp(
+    store: &ollama::Store,
+    src: String,
+    dst: String,
+    force: bool,
+) -> anyhow::Result<()> {
+    let src = parse_ollama_descr(&src)?;
+    let dst = parse_ollama_descr(&dst)?;
+    let store = ollama_store(store)?;
+    store.copy_manifest(&src, &dst, force)?;
+    println!("copied {} to {}", src, dst);
+    Ok(())
+}
 
-    for reg in regs {
-        let models = store.list_models(&reg)?;
-        for model in models {
-            let variants = store.list_model_variants(&reg, &model)?;
-            for variant in variants {
-                let model_descr = ollama::ModelDescr {
-                    registry: reg.clone(),
-                    model: model.clone(),
-                    variant: variant.clone(),
-                };
-                let manifest = store.get_manifest(&model_descr)?;
-                let digests = manifest.all_digests();
+async fn cmd_tag(
+    store: &ollama::Store,
+    name: String,
+    tag: String,
+    force: bool,
+) -> anyhow::Result<()> {
+    let src = parse_ollama_descr(&name)?;
+    let dst = if tag.contains(':') || tag.contains('/') {
+        parse_ollama_descr(&tag)?
+    } else {
+        ollama::ModelDescr {
+            variant: ollama::Variant::from_str(&tag).map_err(|e| anyhow::anyhow!(e))?,
+            ..src.clone()
+        }
+    };
+    let store = ollama_store(store)?;
+    store.copy_manifest(&src, &dst, force)?;
+    println!("tagged {} as {}", src, dst);
+    Ok(())
+}
+
+async fn cmd_mv(
+    store: &ollama::Store,
+    src: String,
+    dst: String,
+    force: bool,
+) -> anyhow::Result<()> {
+    let src = parse_ollama_descr(&src)?;
+    let dst = parse_ollama_descr(&dst)?;
+    let store = ollama_store(store)?;
+    store.rename_manifest(&src, &dst, force)?;
+    println!("renamed {} to {}", src, dst);
+    Ok(())
+}
 
-                let mut failed = Vec::new();
+async fn cmd_export(store: &ollama::Store, name: String, output: String) -> anyhow::Result<()> {
+    let model_descr = parse_ollama_descr(&name)?;
+    let store = ollama_store(store)?;
+
+    let file = std::fs::File::create_new(&output)
+        .with_context(|| format!("creating export file {}", output))?;
+    let writer = std::io::BufWriter::new(file);
+    if let Err(e) = ollama::export_model(store, &model_descr, writer) {
+        let _ = std::fs::remove_file(&output);
+        return Err(e.into());
+    }
+    println!("exported {} to {}", model_descr, output);
+    Ok(())
+}
 
-                for blob in digests.iter() {
-                    if !store.blob_exists(blob) {
-                        failed.push(format!("missing {}", blob));
-                        continue;
-                    }
-                    if blobs {
-                        let verified = store.blob_self_verify(blob)?;
-                        if !verified {
+async fn cmd_import(
+    store: &ollama::Store,
+    file: String,
+    name: Option<String>,
+) -> anyhow::Result<()> {
+    let rename = name.as_deref().map(parse_ollama_descr).transpose()?;
+    let store = ollama_store(store)?;
+
+    let reader =
+        std::fs::File::open(&file).with_context(|| format!("opening import file {}", file))?;
+    let models = ollama::import_models(store, std::io::BufReader::new(reader), rename.as_ref())?;
+    for model in models {
+        println!("imported {}", model);
+    }
+    Ok(())
+}
+
+async fn cmd_gc(store: &ollama::Store, dry_run: bool, min_age: u64) -> anyhow::Result<()> {
+    let store = ollama_store(store)?;
+    let plan = store.gc_plan(Duration::from_secs(min_age * 3600))?;
+
+    if plan.is_empty() {
+        println!("nothing to collect");
+        return Ok(());
+    }
+
+    for (blob, size) in plan.orphan_blobs.iter() {
+        println!("unreferenced blob {} ({})", blob, human::size_units(*size));
+    }
+    for (path, size) in plan.stale_temp_files.iter() {
+        println!(
+            "stale temporary file {} ({})",
+            path.display(),
+            human::size_units(*size)
+        );
+    }
+
+    if dry_run {
+        println!("reclaimable: {}", human::size_units(plan.reclaimable()));
+    } else {
+        let freed = store.gc_apply(&plan)?;
+        println!("reclaimed: {}", human::size_units(freed));
+    }
+    Ok(())
+}
+
+async fn cmd_verify(
+    store: &ollama::Store,
+    out: Output,
+    blobs: bool,
+    repair: bool,
+    jobs: Option<usize>,
+) -> anyhow::Result<()> {
+    let store = ollama_store(store)?;
+    let blobs = blobs || repair;
+
+    let mut models = Vec::new();
+    for model_descr in store.list_model_descrs()? {
+        let manifest = store.get_manifest(&model_descr);
+        models.push((model_descr, manifest));
+    }
+
+    // every blob is checked once, even when shared by multiple models
+    let mut all_blobs = Vec::new();
+    for (_, manifest) in models.iter() {
+        let Ok(manifest) = manifest else { continue };
+        for blob in manifest.all_digests() {
+            if !all_blobs.contains(&blob) {
+                all_blobs.push(blob)
+            }
+        }
+    }
+
+    let statuses: HashMap<ollama::Blob, std::io::Result<ollama::BlobStatus>> = if blobs {
+        let total = all_blobs
+            .iter()
+            .filter_map(|blob| std::fs::metadata(store.blob_path(blob)).ok())
+            .map(|m| m.len())
+            .sum();
+        let bar = indicatif::ProgressBar::new(total);
+        bar.set_style(indicatif::ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} verified ({eta})",
+        )?);
+        let threads =
+            jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        let results = store.verify_blobs_parallel(&all_blobs, threads, |n| bar.inc(n));
+        bar.finish_and_clear();
+        all_blobs.iter().cloned().zip(results).collect()
+    } else {
+        all_blobs
+            .iter()
+            .map(|blob| {
+                let status = match store.blob_exists(blob) {
+                    true => ollama::BlobStatus::Valid,
+                    false => ollama::BlobStatus::Missing,
+                };
+                (blob.clone(), Ok(status))
+            })
+            .collect()
+    };
+
+    let mut report = output::VerifyOutput {
+        models: Vec::new(),
+        repairs: Vec::new(),
+    };
+    let mut failed_models = Vec::new();
+    let mut to_repair: Vec<(ollama::Blob, ollama::Registry)> = Vec::new();
+    // blobs that couldn't be read, which may be valid and are left in place
+    let mut unreadable: Vec<(ollama::Blob, String)> = Vec::new();
+
+    for (model_descr, manifest) in models.iter() {
+        let mut failed = Vec::new();
+
+        match manifest {
+            Err(e) => failed.push(format!("invalid manifest {}", e)),
+            Ok(manifest) => {
+                for blob in manifest.all_digests() {
+                    match &statuses[&blob] {
+                        Ok(ollama::BlobStatus::Valid) => continue,
+                        Ok(ollama::BlobStatus::Missing) => failed.push(format!("missing {}", blob)),
+                        Ok(ollama::BlobStatus::Corrupted) => {
                             failed.push(format!("invalid blob {}", blob))
                         }
+                        Err(e) => {
+                            failed.push(format!("error reading blob {}: {}", blob, e));
+                            if !unreadable.iter().any(|(b, _)| b == &blob) {
+                                unreadable.push((blob, e.to_string()))
+                            }
+                            continue;
+                        }
+                    }
+                    if !to_repair.iter().any(|(b, _)| b == &blob) {
+                        to_repair.push((blob, model_descr.registry.clone()))
                     }
                 }
+                let config_status = &statuses[&manifest.config.digest];
+                let config = matches!(config_status, Ok(ollama::BlobStatus::Valid))
+                    .then(|| store.get_image_config(manifest));
+                if let Some(Err(e)) = config {
+                    failed.push(format!("invalid config {}: {}", manifest.config.digest, e))
+                }
+            }
+        }
 
-                if failed.is_empty() {
-                    println!("{}:{}: OK", model.as_str(), variant.as_str())
-                } else {
-                    println!("{}:{}: FAILED", model.as_str(), variant.as_str());
-                    for f in failed {
-                        println!(" * {}", f)
-                    }
+        if out.is_table() {
+            if failed.is_empty() {
+                println!("{}: OK", model_descr)
+            } else {
+                println!("{}: FAILED", model_descr);
+                for f in failed.iter() {
+                    println!(" * {}", f)
                 }
             }
         }
+        if !failed.is_empty() {
+            failed_models.push(model_descr.clone());
+        }
+        report.models.push(output::VerifyModel {
+            name: model_descr.to_string(),
+            ok: failed.is_empty(),
+            problems: failed,
+        });
+    }
+
+    if failed_models.is_empty() || !repair {
+        if !out.is_table() {
+            out.value(&report)?;
+        }
+        if !failed_models.is_empty() {
+            anyhow::bail!("{} model(s) failed verification", failed_models.len());
+        }
+        return Ok(());
+    }
+
+    for (blob, error) in unreadable {
+        report_repair(
+            out,
+            &mut report,
+            blob.to_string(),
+            None,
+            Err(format!("left in place, reading it failed: {}", error)),
+        );
+    }
+
+    let client = ClientBuilder::new().user_agent("llmup/0.1").build()?;
+
+    for (blob, registry) in to_repair {
+        let mut quarantined = None;
+        if store.blob_exists(&blob) {
+            let path = store.quarantine_blob(&blob)?;
+            if out.is_table() {
+                println!("quarantined {} to {}", blob, path.display());
+            }
+            quarantined = Some(path.display().to_string());
+        }
+        let config = OllamaConfig::for_registry(&registry)?;
+        let result = skelm_download::ollama::download_model_blob::<ProgressBar>(
+            &client, &config, store, &blob,
+        )
+        .await;
+        report_repair(
+            out,
+            &mut report,
+            blob.to_string(),
+            quarantined,
+            result.map(|_| ()),
+        );
+    }
+
+    // manifests that cannot be read are fetched again from their registry
+    for (model_descr, manifest) in models.iter() {
+        if manifest.is_ok() {
+            continue;
+        }
+        let config = OllamaConfig::for_registry(&model_descr.registry)?;
+        let result = skelm_download::ollama::download_model::<ProgressBar>(
+            &client,
+            &config,
+            store,
+            &model_descr.registry,
+            &model_descr.model,
+            &model_descr.variant,
+        )
+        .await;
+        report_repair(
+            out,
+            &mut report,
+            model_descr.to_string(),
+            None,
+            result.map(|_| ()),
+        );
+    }
+
+    if !out.is_table() {
+        out.value(&report)?;
+    }
+    let repair_failures = report.repairs.iter().filter(|r| !r.repaired).count();
+    if repair_failures > 0 {
+        anyhow::bail!("{} item(s) could not be repaired", repair_failures);
     }
     Ok(())
 }
 
+fn report_repair<E: std::fmt::Display>(
+    out: Output,
+    report: &mut output::VerifyOutput,
+    target: String,
+    quarantined: Option<String>,
+    result: Result<(), E>,
+) {
+    let error = result.err().map(|e| e.to_string());
+    if out.is_table() {
+        match &error {
+            None => println!("{}: repaired", target),
+            Some(e) => println!("{}: repair failed: {}", target, e),
+        }
+    }
+    report.repairs.push(output::VerifyRepair {
+        target,
+        quarantined,
+        repaired: error.is_none(),
+        error,
+    });
+}
+
+/// Find the GGUF file of a path, a blob digest or a model name
+fn resolve_model_file(store: &ollama::Store, name: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(name);
+    if path.is_file() {
+        return Ok(path);
+    }
+    if let Ok(blob) = ollama::Blob::from_str(name).or_else(|_| ollama::Blob::from_path_name(name)) {
+        return Ok(ollama_store(store)?.blob_path(&blob));
+    }
+    let model_descr = parse_ollama_descr(name)?;
+    Ok(store
+        .as_model_store()
+        .model_config(&model_descr)?
+        .model_path)
+}
+
+fn ollama_store(store: &ollama::Store) -> anyhow::Result<&OllamaStore> {
+    store
+        .ollama()
+        .context("this command needs an ollama store, not a directory of GGUF files")
+}
+
 fn parse_ollama_descr(name: &str) -> anyhow::Result<ollama::ModelDescr> {
-    ollama::ModelDescr::from_str(name).map_err(|_| {
-        anyhow::anyhow!("Invalid Ollama model description: expecting <registry>/<model>:<variant> or <model>:<variant>")
+    ollama::ModelDescr::from_str(name).map_err(|e| {
+        anyhow::anyhow!("Invalid Ollama model description ({}): expecting <registry>/<model>:<variant> or <model>:<variant>", e)
     })
 }
 
@@ -0,0 +1,254 @@
+//! Machine readable output of the commands
+use serde::Serialize;
+
+use crate::args::OutputFormat;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Output(pub OutputFormat);
+
+impl Output {
+    pub fn is_table(self) -> bool {
+        matches!(self.0, OutputFormat::Table)
+    }
+
+    /// Print a single value, pretty printed for json and on one line for jsonl
+    pub fn value<T: Serialize>(self, value: &T) -> anyhow::Result<()> {
+        match self.0 {
+            OutputFormat::Table | OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(value)?)
+            }
+            OutputFormat::Jsonl => println!("{}", serde_json::to_string(value)?),
+        }
+        Ok(())
+    }
+
+    /// Print a list of values, as an array for json and one value per line for jsonl
+    pub fn values<T: Serialize>(self, values: &[T]) -> anyhow::Result<()> {
+        match self.0 {
+            OutputFormat::Table | OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(values)?)
+            }
+            OutputFormat::Jsonl => {
+                for value in values {
+                    println!("{}", serde_json::to_string(value)?)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct ListEntry {
+    pub registry: String,
+    pub name: String,
+    /// size in bytes
+    pub size: u64,
+    /// last modification, in seconds since the unix epoch
+    pub modified: u64,
+}
+
+#[derive(Serialize)]
+pub struct InfoOutput {
+    pub name: String,
+    pub path: String,
+    pub gguf_version: u32,
+    pub architecture: Option<String>,
+    pub model_name: Option<String>,
+    pub parameter_count: u64,
+    pub context_length: Option<u64>,
+    pub block_count: Option<u64>,
+    pub embedding_length: Option<u64>,
+    pub tensor_count: usize,
+    /// size of the tensor data in bytes
+    pub tensors_size: Option<u64>,
+    /// number of tensors of each ggml type
+    pub tensor_types: Vec<(String, usize)>,
+    /// estimated memory needed to run the model with the default context
+    pub memory: InfoMemory,
+    pub chat_template: Option<String>,
+    /// all the metadata, with long arrays summarized
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Vec<(String, String)>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tensors: Option<Vec<InfoTensor>>,
+}
+
+/// Estimated memory use in bytes
+#[derive(Serialize)]
+pub struct InfoMemory {
+    pub n_ctx: u32,
+    pub weights: u64,
+    pub kv_cache: u64,
+    pub compute: u64,
+    pub total: u64,
+}
+
+#[derive(Serialize)]
+pub struct InfoTensor {
+    pub name: String,
+    pub dims: Vec<u64>,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub offset: u64,
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct VerifyOutput {
+    pub models: Vec<VerifyModel>,
+    pub repairs: Vec<VerifyRepair>,
+}
+
+#[derive(Serialize)]
+pub struct VerifyModel {
+    pub name: String,
+    pub ok: bool,
+    pub problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct VerifyRepair {
+    /// the blob digest or the model name being repaired
+    pub target: String,
+    pub quarantined: Option<String>,
+    pub repaired: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PullOutput {
+    pub name: String,
+    pub layers: Vec<PullLayer>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PullStatus {
+    Skipped,
+    Downloaded,
+}
+
+#[derive(Serialize)]
+pub struct PullLayer {
+    pub media_type: String,
+    pub digest: String,
+    pub status: PullStatus,
+}
+
+#[derive(Serialize)]
+pub struct TagEntry {
+    pub name: String,
+    /// total size of the blobs in bytes
+    pub size: Option<u64>,
+    pub quantization: Option<String>,
+    pub parameter_size: Option<String>,
+    pub family: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BenchOutput {
+    pub model: String,
+    pub tokens_generated: u64,
+    pub elapsed_secs: f64,
+    pub tokens_per_second: f64,
+    pub secs_per_token: f64,
+}
+
+#[derive(Serialize)]
+pub struct PerplexityOutput {
+    pub model: String,
+    pub file: String,
+    pub window: usize,
+    pub stride: usize,
+    /// number of scored tokens
+    pub tokens: usize,
+    /// total negative log-likelihood, in nats
+    pub nll: f64,
+    pub mean_nll: f64,
+    pub perplexity: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_logprobs: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(Serialize)]
+pub struct TokenLogprob {
+    pub token: i32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+#[derive(Serialize)]
+pub struct ClassifyOutput {
+    pub model: String,
+    pub length_normalized: bool,
+    /// in the order of the choices
+    pub candidates: Vec<CandidateScore>,
+}
+
+#[derive(Serialize)]
+pub struct CandidateScore {
+    pub text: String,
+    pub tokens: usize,
+    pub logprob: f64,
+    pub probability: f64,
+}
+
+#[derive(Serialize)]
+pub struct BatchOutput {
+    pub model: String,
+    pub input: String,
+    pub output: String,
+    /// requests completed by this run
+    pub completed: usize,
+    pub failed: usize,
+    /// requests completed by previous runs
+    pub skipped: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// stopped by Ctrl-C, running the job again resumes it
+    pub interrupted: bool,
+}
+
+#[derive(Serialize)]
+pub struct EmbedOutput {
+    pub model: String,
+    pub pooling_type: String,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+pub struct RunOutput {
+    pub model: String,
+    pub content: String,
+    pub reasoning_content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<skelm_exec::ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<RunLogprobs>,
+}
+
+/// The `logprobs` object of an OpenAI chat completion choice
+#[derive(Serialize)]
+pub struct RunLogprobs {
+    pub content: Vec<skelm_exec::TokenLogprobs>,
+}
+
+#[derive(Serialize)]
+pub struct TokenizeOutput {
+    pub count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Vec<TokenEntry>>,
+}
+
+#[derive(Serialize)]
+pub struct TokenEntry {
+    pub id: i32,
+    pub piece: String,
+}
+
+#[derive(Serialize)]
+pub struct DetokenizeOutput {
+    pub text: String,
+}
@@ -35,4 +35,8 @@ impl ProgressDisplay for ProgressBar {
     fn progress_finalize(self) {
         self.0.finish()
     }
+
+    fn progress_waiting(what: &str) {
+        eprintln!("waiting for another download of {}", what)
+    }
 }
@@ -1,48 +1,147 @@
-use std::io::Write;
-use std::{path::Path, sync::atomic::AtomicBool};
+use std::io::{IsTerminal, Write};
+use std::{
+    path::Path,
+    sync::{Once, atomic::AtomicBool},
+};
 
 use skelm_llama_cpp as llama;
 
+/// How the generated text is shown and parsed
+#[derive(Default)]
+pub struct RunOptions {
+    /// don't show the reasoning of the model
+    pub hide_thinking: bool,
+    /// print the text as it's generated
+    pub stream: bool,
+    /// parse the tool calls written in this format out of the content
+    pub tool_format: Option<skelm_exec::ToolCallFormat>,
+    /// constrain the tool calls
+    pub grammar: Option<llama::SamplerGrammar>,
+    /// record the log-probability of each token and of this number of alternatives,
+    /// streamed as JSON lines instead of the text
+    pub logprobs: Option<usize>,
+}
+
+/// The text generated by a run
+#[derive(Default)]
+pub struct Generation {
+    pub content: String,
+    pub reasoning: String,
+    pub tool_calls: Vec<skelm_exec::ToolCall>,
+    /// one entry per generated token, when requested
+    pub logprobs: Vec<skelm_exec::TokenLogprobs>,
+}
+
 pub struct Output {
     handle: Option<std::fs::File>,
-    utf8_errors: usize,
+    hide_thinking: bool,
+    stream: bool,
+    /// print the log-probabilities as JSON lines as they're generated
+    stream_logprobs: bool,
+    tool_format: Option<skelm_exec::ToolCallFormat>,
+    /// a tool call started, the rest of the content isn't shown
+    in_tool_call: bool,
+    /// dim the reasoning, when writing to a terminal
+    dim: bool,
+    generation: Generation,
 }
 
 impl Output {
-    pub fn new() -> Self {
+    pub fn new(options: &RunOptions) -> Self {
         Self {
-            utf8_errors: 0,
             handle: None,
+            hide_thinking: options.hide_thinking,
+            stream: options.stream && options.logprobs.is_none(),
+            stream_logprobs: options.stream && options.logprobs.is_some(),
+            tool_format: options.tool_format,
+            in_tool_call: false,
+            dim: std::io::stdout().is_terminal(),
+            generation: Generation::default(),
         }
     }
 
-    pub fn new_file<P: AsRef<Path>>(p: P) -> std::io::Result<Self> {
+    pub fn new_file<P: AsRef<Path>>(p: P, options: &RunOptions) -> std::io::Result<Self> {
         let file = std::fs::File::create(p)?;
         Ok(Self {
-            utf8_errors: 0,
             handle: Some(file),
+            dim: false,
+            ..Self::new(options)
         })
     }
 
-    pub fn append(&mut self, bytes: &[u8]) {
-        if let Some(file) = &mut self.handle {
-            file.write_all(bytes).unwrap();
-        } else {
-            match std::str::from_utf8(bytes) {
-                Ok(valid) => {
-                    print!("{}", valid);
-                    std::io::stdout().flush().unwrap();
+    pub fn append(&mut self, chunk: skelm_exec::TextChunk) {
+        const DIM: &str = "\x1b[2m";
+        const RESET: &str = "\x1b[0m";
+        let text = match chunk {
+            skelm_exec::TextChunk::Content(text) => {
+                let start = self.generation.content.len();
+                self.generation.content.push_str(&text);
+                if self.in_tool_call {
+                    return;
                 }
-                Err(_) => {
-                    self.utf8_errors += 1;
+                let Some(trigger) = self.tool_format.map(|f| f.trigger()) else {
+                    return self.write(&text);
+                };
+                // the trigger can be split across chunks, look for it in all the content
+                let search_from = start.saturating_sub(trigger.len());
+                let search_from = (search_from..=start)
+                    .find(|i| self.generation.content.is_char_boundary(*i))
+                    .unwrap_or(start);
+                match self.generation.content[search_from..].find(trigger) {
+                    Some(pos) if search_from + pos >= start => {
+                        self.in_tool_call = true;
+                        text[..search_from + pos - start].to_string()
+                    }
+                    Some(_) => {
+                        self.in_tool_call = true;
+                        return;
+                    }
+                    None => text,
                 }
             }
+            skelm_exec::TextChunk::Reasoning(text) => {
+                self.generation.reasoning.push_str(&text);
+                if self.hide_thinking {
+                    return;
+                }
+                if self.dim {
+                    format!("{}{}{}", DIM, text, RESET)
+                } else {
+                    text
+                }
+            }
+        };
+        self.write(&text)
+    }
+
+    pub fn append_logprobs(&mut self, logprobs: skelm_exec::TokenLogprobs) {
+        if self.stream_logprobs {
+            println!("{}", serde_json::to_string(&logprobs).unwrap());
+        }
+        self.generation.logprobs.push(logprobs);
+    }
+
+    fn write(&mut self, text: &str) {
+        if let Some(file) = &mut self.handle {
+            file.write_all(text.as_bytes()).unwrap();
+        } else if self.stream {
+            print!("{}", text);
+            std::io::stdout().flush().unwrap();
+        }
+    }
+
+    pub fn finish(mut self) -> Generation {
+        if let Some(format) = self.tool_format {
+            let (content, tool_calls) = format.parse(&self.generation.content);
+            self.generation.content = content;
+            self.generation.tool_calls = tool_calls;
         }
+        self.generation
     }
 }
 
 pub fn llama_init_logging(debug: bool) {
-    llama::llama_logging(Box::new(move |level, key, t| {
+    llama::llama_logging(Box::new(move |level, key, t: &str| {
         if level != llama::LogLevel::Error
             && (!debug && ![llama::LogKey::ModelLoader].contains(&key))
         {
@@ -67,48 +166,79 @@ pub fn llama_sampler() -> impl llama::Sampler {
     sampler
 }
 
+/// Flag set by Ctrl-C to stop the generation, the handler being installed once for all
+/// the runs of the process
+pub fn quit_requested() -> &'static AtomicBool {
+    static QUIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            QUIT_REQUESTED.store(true, std::sync::atomic::Ordering::Relaxed);
+        })
+        .expect("Error setting Ctrl-C handler")
+    });
+    &QUIT_REQUESTED
+}
+
 pub fn llama_run(
     context: &mut skelm_exec::Context,
     line: &str,
+    images: &[skelm_exec::Image],
     output: &Option<String>,
-) -> anyhow::Result<()> {
+    mut options: RunOptions,
+) -> anyhow::Result<Generation> {
     let model = context.model().clone();
-    let vocab = model.vocab;
+    let vocab = model.vocab.clone();
 
-    context.append_bytes(line.as_bytes());
+    context.append_prompt(line, images)?;
 
     let context = &mut context.1;
 
-    let mut sampler = llama_sampler();
+    let mut sampler = llama::SamplerChain::new();
+    if let Some(grammar) = options.grammar.take() {
+        sampler.add(Box::new(grammar));
+    }
+    sampler.add(Box::new(llama_sampler()));
 
-    let quit_requested = std::sync::Arc::new(AtomicBool::new(false));
-    let quit_requested_inner = quit_requested.clone();
-    ctrlc::set_handler(move || {
-        quit_requested_inner.store(true, std::sync::atomic::Ordering::Relaxed);
-    })
-    .expect("Error setting Ctrl-C handler");
+    let quit_requested = quit_requested();
+    quit_requested.store(false, std::sync::atomic::Ordering::Relaxed);
 
     let mut output = output
         .as_ref()
-        .map(|o| Output::new_file(o))
-        .unwrap_or(Ok(Output::new()))?;
+        .map(|o| Output::new_file(o, &options))
+        .unwrap_or_else(|| Ok(Output::new(&options)))?;
+    let markers = model.thinking_markers();
+    let mut keep = vec![markers.start, markers.end];
+    if let Some(format) = options.tool_format {
+        keep.extend_from_slice(format.control_texts());
+    }
+    let mut decoder = skelm_exec::TokenTextDecoder::new(vocab.clone()).keep_control(&keep);
+    if let Some((starts, end)) = markers.headers() {
+        decoder = decoder.strip_headers(starts, end);
+    }
+    let mut thinking = skelm_exec::ThinkingParser::for_prompt(markers, line);
     let mut tokens = Vec::new();
     while !quit_requested.load(std::sync::atomic::Ordering::Relaxed) {
         let n = context.next_token(&mut sampler, &vocab);
         match n {
             None => break,
             Some(t) => {
+                if let Some(top_n) = options.logprobs {
+                    let logits = context.get_logits(-1);
+                    output.append_logprobs(skelm_exec::token_logprobs(&vocab, logits, t, top_n));
+                }
                 tokens.push(t);
                 context.append_tokens(&[t])?;
-                let attr = vocab.token_attr(t);
-                if attr.is_control() {
-                    continue;
+                for chunk in thinking.push(&decoder.push(t)) {
+                    output.append(chunk)
                 }
-                let bytes = vocab.as_bytes(t);
-                output.append(&bytes);
             }
         }
     }
+    let rest = decoder.finish();
+    for chunk in thinking.push(&rest).into_iter().chain(thinking.finish()) {
+        output.append(chunk)
+    }
 
-    Ok(())
+    Ok(output.finish())
 }